@@ -1,21 +1,28 @@
-use embedded_hal::{digital::v2::OutputPin, serial::Read, serial::Write};
+use embedded_hal::{blocking::delay::DelayUs, digital::v2::OutputPin, serial::Read, serial::Write};
 
-use crate::protocol::{Command, ConnectionType, FirmwareInfo, IpAddresses, Response, WifiConnectionFailure, WiFiMode, ResolverAddresses};
+use crate::protocol::{AccessPoint, Addressing, Command, ConnectionType, CountryConfig, Custom, DateTime, Dialect, EnterpriseCredentials, FirmwareInfo, FlowControl, HttpRequest, Interface, IpAddresses, JoinedAccessPoint, MacAddress, MdnsConfig, RemoteEndpoint, Response, TlsConfig, UdpOptions, WifiConnectionFailure, WiFiMode, ResolverAddresses, SleepMode, SmartConfigEvent, SmartConfigType, SoftApConfig, SoftApInfo, StationInfo, UpdateProgress};
 
-use heapless::{consts::{U16, U2}, spsc::{Consumer, Queue}, String};
-
-use log::info;
+use heapless::{spsc::{Consumer, Queue}, Vec};
 
 use crate::adapter::AdapterError::UnableToInitialize;
-use crate::ingress::Ingress;
+use crate::ingress::{self, Ingress, Overflow, Shared, SharedRef};
+use crate::parser;
+use crate::payload::Payload;
 use crate::network::Esp8266IpNetworkDriver;
+use crate::INGRESS_LEN;
+use crate::wifi::Reconnect;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttState;
 use core::fmt::Debug;
 use nom::lib::std::fmt::Formatter;
 use crate::protocol::Response::IpAddress;
+#[cfg(feature = "trace")]
+use crate::trace::{Direction, Tracer};
 use drogue_network::dns::DnsError;
-use drogue_network::addr::{Ipv4Addr, HostAddr, HostSocketAddr};
+use drogue_network::addr::{IpAddr, Ipv4Addr, HostAddr, SocketAddr};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AdapterError {
     UnableToInitialize,
     NoAvailableSockets,
@@ -25,6 +32,227 @@ pub enum AdapterError {
     WriteError,
     ReadError,
     InvalidSocket,
+    InvalidParameter,
+    Unsupported,
+    /// The board did not respond within the configured timeout; holds the
+    /// milliseconds elapsed.
+    ResponseTimeout(u32),
+    /// The board was still busy after retrying the command.
+    Busy,
+    /// The board rejected an operation on a link.
+    Socket { link_id: usize, error: SocketError },
+    /// The board didn't complete a command, see `CommandError`.
+    Command(CommandError),
+}
+
+/// A command the board didn't complete, and what it answered instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandError {
+    /// The command, such as `AT+CIPSTART`, see `Command::name`.
+    pub command: &'static str,
+    /// The link the command applied to, if any.
+    pub link_id: Option<usize>,
+    /// What the board answered instead of the expected response.
+    pub observed: Observed,
+}
+
+/// What the board answered to a command it didn't complete.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Observed {
+    /// Another response, named like its `Response` variant, such as `Error`.
+    Response(&'static str),
+    /// No response within the timeout; holds the milliseconds elapsed.
+    Timeout(u32),
+    /// The board was still busy after retrying the command.
+    Busy,
+}
+
+/// Why the board rejected an operation on a link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocketError {
+    /// The link isn't connected (`link is not valid`), so the socket must
+    /// be closed.
+    LinkInvalid,
+    /// The link is connected already (`ALREADY CONNECTED`).
+    AlreadyConnected,
+    /// The board answered `ERROR` without a reason, such as while it is
+    /// still busy with the link, so the operation may be retried later.
+    Rejected,
+    /// The board took the data, but failed to send it (`SEND FAIL`), see
+    /// `Adapter::set_send_retries`.
+    SendFailed,
+}
+
+impl SocketError {
+    /// The error the board answered with, if any.
+    fn from_response(response: &Response) -> Option<Self> {
+        match response {
+            Response::LinkInvalid => Some(SocketError::LinkInvalid),
+            Response::AlreadyConnected => Some(SocketError::AlreadyConnected),
+            Response::Error => Some(SocketError::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Monotonic clock used to time out commands, see `Adapter::set_clock`.
+///
+/// Required to be `Sync`, so that the adapter holding it remains `Send`.
+pub trait Clock: Sync {
+    /// Milliseconds since an arbitrary point in time, wrapping on overflow.
+    fn now_ms(&self) -> u32;
+}
+
+impl<F> Clock for F
+    where
+        F: Fn() -> u32 + Sync,
+{
+    fn now_ms(&self) -> u32 {
+        self()
+    }
+}
+
+/// Time to wait for the board to respond, in milliseconds, by class of command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Configuration and queries, answered by the board itself.
+    pub default: u32,
+    /// Joining and scanning access points, opening connections, resolving
+    /// names and sending data.
+    pub network: u32,
+    /// Resetting the board, until it reports being ready.
+    pub restart: u32,
+    /// Updating the firmware over the air.
+    pub update: u32,
+    /// Checking the board is alive, see `Adapter::check_alive`.
+    pub alive: u32,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            default: 2_000,
+            network: 20_000,
+            restart: 5_000,
+            update: 300_000,
+            alive: 500,
+        }
+    }
+}
+
+impl Timeouts {
+    fn for_command(&self, command: &Command) -> u32 {
+        match command {
+            Command::JoinAp { .. }
+            | Command::JoinEnterpriseAp(..)
+            | Command::ScanAccessPoints
+            | Command::StartConnection { .. }
+            | Command::CloseConnection(..)
+            | Command::CloseSingleConnection
+            | Command::Send { .. }
+            | Command::ResolveDomain { .. }
+            | Command::Ping { .. }
+            | Command::HttpRequest(..) => self.network,
+            #[cfg(feature = "mqtt")]
+            Command::Mqtt(..) => self.network,
+            Command::Reset | Command::Restore => self.restart,
+            Command::UpdateFirmware => self.update,
+            Command::Attention => self.alive,
+            _ => self.default,
+        }
+    }
+}
+
+/// Progress of WPS pairing, as tracked from the board's notifications.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WpsState {
+    Idle,
+    Pairing,
+    Paired,
+    Failed(WifiConnectionFailure),
+}
+
+/// State of the board's Wi-Fi station, as tracked from its notifications.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiState {
+    Disconnected,
+    /// Associated with an access point, but no IP address obtained yet.
+    Connected,
+    /// Associated with an access point and an IP address obtained.
+    GotIp,
+}
+
+/// Health of the board, as determined by `Adapter::check_alive`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Health {
+    Alive,
+    /// The board is still processing a previous command.
+    Busy,
+    /// The board restarted on its own, and must be recovered using
+    /// `Adapter::recover`.
+    Restarted,
+    /// The board answered with something other than `OK`, and must be
+    /// resynchronized using `Adapter::resync`.
+    OutOfSync,
+    /// The board did not answer in time. It may be hung, and need to be
+    /// power-cycled using its enable pin.
+    Unresponsive,
+}
+
+/// Counters of the traffic with the board, see `Adapter::stats`, all
+/// wrapping on overflow.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Commands written to the board, including retries.
+    pub commands: usize,
+    /// Commands retried, as the board was busy or failed to send data.
+    pub retries: usize,
+    /// Commands the board did not respond to in time.
+    pub timeouts: usize,
+    /// Bytes written to the board, commands and data alike.
+    pub bytes_sent: usize,
+    /// Bytes read from the board by the ingress.
+    pub bytes_received: usize,
+    /// Lines dropped by the ingress as unparseable, see `Adapter::parse_failures`.
+    pub parse_failures: usize,
+    /// Losses of received data, as reported by `Event::Overflow`.
+    pub overflows: usize,
+}
+
+/// Counters of a link, kept across the connections using it, see
+/// `Adapter::link_stats`. All wrap on overflow.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStats {
+    /// Bytes accepted by the board for sending.
+    pub bytes_sent: usize,
+    /// Bytes read by the application.
+    pub bytes_received: usize,
+    /// Writes which the board did not accept.
+    pub send_failures: usize,
+    /// Connections closed, by either end.
+    pub closes: usize,
+}
+
+/// Unsolicited events reported by the board, see `Adapter::event`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    WifiConnected,
+    WifiDisconnected,
+    /// An IP address was obtained for the station interface.
+    GotIp,
+    /// The connection of a link was closed, by either end.
+    LinkClosed(usize),
+    /// The board restarted, see `Adapter::has_restarted`.
+    ModuleRestarted,
+    /// Received data was lost by the ingress.
+    Overflow(Overflow),
 }
 
 #[derive(Debug)]
@@ -32,10 +260,131 @@ enum SocketState {
     HalfClosed,
     Closed,
     Open,
+    /// Waiting for the response to `AT+CIPSTART`, see `connect_tcp_nb`.
+    Connecting,
     Connected,
+    /// Closed by `AT+CIPCLOSE`, waiting for the board to report it closed.
+    Closing,
+}
+
+type Initialized<'a, Tx, Rx, const N: usize = INGRESS_LEN, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS> =
+    (Adapter<'a, Tx, RQ, NQ, L>, Ingress<'a, Rx, N, RQ, NQ>);
+
+/// Largest number of connections supported by any module, and the default
+/// number of links tracked by an `Adapter`.
+pub const MAX_LINKS: usize = 16;
+
+/// Default length of the response queue. A queue of length `N` holds up to
+/// `N - 1` responses.
+pub const RESPONSE_QUEUE_LEN: usize = 3;
+
+/// Default length of the notification queue.
+pub const NOTIFICATION_QUEUE_LEN: usize = 17;
+
+/// Longest command accepted by `Adapter::send_raw`.
+const MAX_COMMAND_LEN: usize = 256;
+
+/// Largest amount of data accepted by a single `AT+CIPSEND`.
+const MAX_SEND_LEN: usize = 2048;
+
+/// Capabilities of the module and its AT firmware.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModuleProfile {
+    /// Number of connections the firmware supports at once, up to 16.
+    pub max_links: usize,
+    /// Largest amount of data retrieved with a single `AT+CIPRECVDATA`.
+    /// Reads are further limited by `BUFFER_LEN`, see the `8k` feature.
+    pub max_receive_len: usize,
+    /// Command set of the firmware, if it only ships with one.
+    pub dialect: Option<Dialect>,
+}
+
+impl ModuleProfile {
+    /// ESP8266 modules, running either the NONOS or the ESP-AT firmware.
+    pub const ESP8266: ModuleProfile = ModuleProfile {
+        max_links: 5,
+        max_receive_len: 2048,
+        dialect: None,
+    };
+
+    /// ESP32 modules, including the ESP32-C3, with the ESP-AT firmware as
+    /// released.
+    pub const ESP32: ModuleProfile = ModuleProfile {
+        max_links: 5,
+        max_receive_len: 8192,
+        dialect: Some(Dialect::EspAt),
+    };
+
+    /// ESP32 modules with ESP-AT firmware built with a raised connection
+    /// limit (`AT socket maximum connection number`) of 16 links.
+    pub const ESP32_16_LINKS: ModuleProfile = ModuleProfile {
+        max_links: 16,
+        ..ModuleProfile::ESP32
+    };
+}
+
+impl Default for ModuleProfile {
+    fn default() -> Self {
+        ModuleProfile::ESP8266
+    }
+}
+
+/// Features of the module and its firmware, as detected by `initialize`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// Version reported by `AT+GMR`, if it could be parsed.
+    pub firmware: Option<FirmwareInfo>,
+    pub dialect: Dialect,
+    /// Number of connections the firmware supports at once, as far as they
+    /// are tracked by the adapter.
+    pub max_links: usize,
+    /// Inbound data is buffered by the board until requested (`AT+CIPRECVMODE`),
+    /// rather than pushed along with its `+IPD` notification.
+    pub passive_receive: bool,
+    /// TLS is configured per link (`AT+CIPSSLCCONF` and related commands).
+    pub tls_config: bool,
+}
+
+impl Capabilities {
+    fn detect(profile: &ModuleProfile, dialect: Dialect, firmware: Option<FirmwareInfo>, links: usize) -> Self {
+        // AT+CIPRECVMODE was introduced with AT 1.4
+        let passive_receive = match firmware {
+            Some(info) => (info.major, info.minor) >= (1, 4),
+            None => true,
+        };
+        Self {
+            firmware,
+            dialect,
+            max_links: core::cmp::min(profile.max_links, links),
+            passive_receive,
+            tls_config: dialect == Dialect::EspAt,
+        }
+    }
 }
 
-type Initialized<'a, Tx, Rx> = (Adapter<'a, Tx>, Ingress<'a, Rx>);
+/// Options applied while initializing the board.
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// Wi-Fi mode to switch to. Some modules boot as a soft access point,
+    /// which makes joining an access point fail.
+    pub mode: Option<WiFiMode>,
+    /// Hardware flow control to enable, avoiding dropped bytes at high baud rates.
+    /// The UART behind `tx` and `rx` must already use the same flow control,
+    /// with the board's RTS and CTS lines wired to it.
+    pub flow_control: Option<FlowControl>,
+    /// Baud rate the board runs at, required to change its flow control.
+    /// Defaults to 115200.
+    pub baud_rate: Option<u32>,
+    /// Report the remote address along with inbound data, see `Adapter::set_remote_info`.
+    pub remote_info: bool,
+    /// Command set of the firmware. Defaults to the one matching the version
+    /// reported by the board, the dialect of the profile, or `Dialect::default()`.
+    pub dialect: Option<Dialect>,
+    /// Module the board is built around. Defaults to the ESP8266.
+    pub profile: ModuleProfile,
+    /// Time to wait for the board to become ready. Defaults to 5 seconds.
+    pub ready_timeout_ms: Option<u32>,
+}
 
 /// Initialize an ESP8266 board for usage as a Wifi-offload device.
 ///
@@ -45,27 +394,115 @@ type Initialized<'a, Tx, Rx> = (Adapter<'a, Tx>, Ingress<'a, Rx>);
 /// * reset_pin: Pin connect to the ESP's `rst` pin.
 /// * response_queue: Queue for inbound AT command responses.
 /// * notification_queue: Queue for inbound unsolicited AT notification messages.
-pub fn initialize<'a, Tx, Rx, EnablePin, ResetPin>(
-    mut tx: Tx,
-    mut rx: Rx,
+/// * delay: Delay used to time out waiting for the board to become ready.
+///
+/// The queues may be of any depth, such as a deeper notification queue where
+/// bursts of `+IPD` notifications are received; the `Adapter` and `Ingress`
+/// take on their depths as the `RQ` and `NQ` parameters.
+pub fn initialize<'a, Tx, Rx, const RQ: usize, const NQ: usize, EnablePin, ResetPin, Delay>(
+    tx: Tx,
+    rx: Rx,
     enable_pin: &mut EnablePin,
     reset_pin: &mut ResetPin,
-    response_queue: &'a mut Queue<Response, U2>,
-    notification_queue: &'a mut Queue<Response, U16>,
-) -> Result<Initialized<'a, Tx, Rx>, AdapterError>
+    response_queue: &'a mut Queue<Response, RQ>,
+    notification_queue: &'a mut Queue<Response, NQ>,
+    delay: &mut Delay,
+) -> Result<Initialized<'a, Tx, Rx, INGRESS_LEN, RQ, NQ, MAX_LINKS>, AdapterError>
     where
         Tx: Write<u8>,
         Rx: Read<u8>,
         EnablePin: OutputPin,
         ResetPin: OutputPin,
+        Delay: DelayUs<u16>,
 {
-    let mut buffer: [u8; 1024] = [0; 1024];
-    let mut pos = 0;
-
-    const READY: [u8; 7] = *b"ready\r\n";
+    initialize_with_options(
+        tx,
+        rx,
+        enable_pin,
+        reset_pin,
+        response_queue,
+        notification_queue,
+        delay,
+        InitOptions::default(),
+    )
+}
 
-    let mut counter = 0;
+/// Initialize an ESP8266 board, like `initialize`, applying additional options.
+///
+/// * options: Settings applied once the board is ready.
+///
+/// When enabling flow control, `tx` must wait for CTS before each byte and `rx`
+/// must deassert RTS while its buffer is full; otherwise ingress bytes are still
+/// dropped at high baud rates.
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_with_options<'a, Tx, Rx, const RQ: usize, const NQ: usize, EnablePin, ResetPin, Delay>(
+    tx: Tx,
+    rx: Rx,
+    enable_pin: &mut EnablePin,
+    reset_pin: &mut ResetPin,
+    response_queue: &'a mut Queue<Response, RQ>,
+    notification_queue: &'a mut Queue<Response, NQ>,
+    delay: &mut Delay,
+    options: InitOptions,
+) -> Result<Initialized<'a, Tx, Rx, INGRESS_LEN, RQ, NQ, MAX_LINKS>, AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+        EnablePin: OutputPin,
+        ResetPin: OutputPin,
+        Delay: DelayUs<u16>,
+{
+    // short enough not to miss bytes at 115200 baud
+    const POLL_INTERVAL_US: u16 = 50;
+
+    let timeout_us = options.ready_timeout_ms.unwrap_or(5_000).saturating_mul(1_000);
+    let mut initializer = start_initialize(
+        tx,
+        rx,
+        enable_pin,
+        reset_pin,
+        response_queue,
+        notification_queue,
+        options,
+    )?;
+
+    let mut waited_us = 0u32;
+    loop {
+        match initializer.poll_initialize() {
+            Ok(initialized) => return Ok(initialized),
+            Err(nb::Error::Other(e)) => return Err(e),
+            Err(nb::Error::WouldBlock) if waited_us >= timeout_us => {
+                warn!("adapter did not become ready");
+                return Err(AdapterError::Timeout);
+            }
+            Err(nb::Error::WouldBlock) => {
+                delay.delay_us(POLL_INTERVAL_US);
+                waited_us += POLL_INTERVAL_US as u32;
+            }
+        }
+    }
+}
 
+/// Start initializing an ESP8266 board, without waiting for it to become ready.
+///
+/// Takes the same arguments as `initialize_with_options`, except for the delay.
+/// The returned `Initializer` must be polled until the board is initialized,
+/// timing out as appropriate for the application.
+pub fn start_initialize<'a, Tx, Rx, const RQ: usize, const NQ: usize, EnablePin, ResetPin>(
+    tx: Tx,
+    rx: Rx,
+    enable_pin: &mut EnablePin,
+    reset_pin: &mut ResetPin,
+    response_queue: &'a mut Queue<Response, RQ>,
+    notification_queue: &'a mut Queue<Response, NQ>,
+    options: InitOptions,
+) -> Result<Initializer<'a, Tx, Rx, INGRESS_LEN, RQ, NQ, MAX_LINKS>, AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+        EnablePin: OutputPin,
+        ResetPin: OutputPin,
+{
     enable_pin
         .set_high()
         .map_err(|_| AdapterError::UnableToInitialize)?;
@@ -73,73 +510,217 @@ pub fn initialize<'a, Tx, Rx, EnablePin, ResetPin>(
         .set_high()
         .map_err(|_| AdapterError::UnableToInitialize)?;
 
-    log::debug!("waiting for adapter to become ready");
+    debug!("waiting for adapter to become ready");
 
-    loop {
-        let result = rx.read();
-        match result {
-            Ok(c) => {
-                buffer[pos] = c;
-                pos += 1;
-                if pos >= READY.len() && buffer[pos - READY.len()..pos] == READY {
-                    log::debug!("adapter is ready");
-                    disable_echo(&mut tx, &mut rx)?;
-                    enable_mux(&mut tx, &mut rx)?;
-                    set_recv_mode(&mut tx, &mut rx)?;
-                    return Ok(build_adapter_and_ingress(
-                        tx,
-                        rx,
-                        response_queue,
-                        notification_queue,
-                    ));
+    Ok(Initializer {
+        parts: Some((tx, rx, response_queue, notification_queue)),
+        options,
+        window: [0; 7],
+    })
+}
+
+type InitializerParts<'a, Tx, Rx, const RQ: usize, const NQ: usize> = (
+    Tx,
+    Rx,
+    &'a mut Queue<Response, RQ>,
+    &'a mut Queue<Response, NQ>,
+);
+
+/// A board being initialized, see `start_initialize`, whose ingress buffers
+/// up to `N` bytes and whose adapter tracks `L` links.
+pub struct Initializer<'a, Tx, Rx, const N: usize = INGRESS_LEN, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    parts: Option<InitializerParts<'a, Tx, Rx, RQ, NQ>>,
+    options: InitOptions,
+    // the most recently received bytes, to spot the `ready` banner
+    window: [u8; 7],
+}
+
+impl<'a, Tx, Rx, const N: usize, const RQ: usize, const NQ: usize, const L: usize> Initializer<'a, Tx, Rx, N, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    const LINKS_SUPPORTED: () = assert!(
+        L > 0 && L <= MAX_LINKS,
+        "the number of links must be between 1 and MAX_LINKS"
+    );
+
+    /// Buffer up to `M` rather than `N` bytes in the ingress, such as to save
+    /// memory or to keep up with bursts of received data.
+    pub fn with_ingress_len<const M: usize>(self) -> Initializer<'a, Tx, Rx, M, RQ, NQ, L> {
+        Initializer {
+            parts: self.parts,
+            options: self.options,
+            window: self.window,
+        }
+    }
+
+    /// Track `M` rather than `L` links, up to `MAX_LINKS`, such as to save
+    /// memory where the firmware supports fewer links. Links beyond those are
+    /// neither opened nor accepted.
+    pub fn with_links<const M: usize>(self) -> Initializer<'a, Tx, Rx, N, RQ, NQ, M> {
+        Initializer {
+            parts: self.parts,
+            options: self.options,
+            window: self.window,
+        }
+    }
+
+    /// Process the bytes received so far, finishing initialization once the
+    /// board is ready.
+    ///
+    /// The settings are applied as soon as the board is ready, which blocks
+    /// until the board acknowledged them. Once initialized, further polls fail.
+    pub fn poll_initialize(&mut self) -> nb::Result<Initialized<'a, Tx, Rx, N, RQ, NQ, L>, AdapterError> {
+        const READY: [u8; 7] = *b"ready\r\n";
+
+        let (_, rx, _, _) = self
+            .parts
+            .as_mut()
+            .ok_or(nb::Error::Other(AdapterError::UnableToInitialize))?;
+
+        loop {
+            match rx.read() {
+                Ok(c) => {
+                    self.window.rotate_left(1);
+                    self.window[6] = c;
+                    if self.window == READY {
+                        break;
+                    }
                 }
-            }
-            Err(nb::Error::WouldBlock) => {
-                continue;
-            }
-            Err(_) if counter > 10_000 => {
-                break;
-            }
-            Err(_) => {
-                counter += 1;
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                // framing or overrun errors while the board boots
+                Err(nb::Error::Other(_)) => {}
             }
         }
+
+        debug!("adapter is ready");
+        let parts = self
+            .parts
+            .take()
+            .ok_or(nb::Error::Other(AdapterError::UnableToInitialize))?;
+        let options = core::mem::take(&mut self.options);
+        Self::finish(parts, options).map_err(nb::Error::Other)
     }
 
-    Err(AdapterError::UnableToInitialize)
+    fn finish(
+        parts: InitializerParts<'a, Tx, Rx, RQ, NQ>,
+        options: InitOptions,
+    ) -> Result<Initialized<'a, Tx, Rx, N, RQ, NQ, L>, AdapterError> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::LINKS_SUPPORTED;
+        let (mut tx, mut rx, response_queue, notification_queue) = parts;
+        resync(&mut tx, &mut rx)?;
+        let firmware = query_firmware_info(&mut tx, &mut rx)?;
+        debug!("firmware {:?}", firmware);
+        let dialect = options
+            .dialect
+            .or_else(|| firmware.as_ref().map(Dialect::for_firmware))
+            .or(options.profile.dialect)
+            .unwrap_or_default();
+        let capabilities = Capabilities::detect(&options.profile, dialect, firmware, L);
+        if let Dialect::EspAt = dialect {
+            disable_persistence(&mut tx, &mut rx)?;
+        }
+        enable_mux(&mut tx, &mut rx)?;
+        if capabilities.passive_receive {
+            set_recv_mode(&mut tx, &mut rx)?;
+        }
+        if let Some(mode) = options.mode {
+            set_mode(&mut tx, &mut rx, dialect, mode)?;
+        }
+        if let Some(flow_control) = options.flow_control {
+            let baud_rate = options.baud_rate.unwrap_or(115_200);
+            set_flow_control(&mut tx, &mut rx, dialect, baud_rate, flow_control)?;
+        }
+        if options.remote_info {
+            enable_remote_info(&mut tx, &mut rx, dialect)?;
+        }
+        build_adapter_and_ingress(
+            tx,
+            rx,
+            response_queue,
+            notification_queue,
+            options.profile,
+            capabilities,
+        )
+    }
 }
 
-fn build_adapter_and_ingress<'a, Tx, Rx>(
+fn build_adapter_and_ingress<'a, Tx, Rx, const N: usize, const RQ: usize, const NQ: usize, const L: usize>(
     tx: Tx,
     rx: Rx,
-    response_queue: &'a mut Queue<Response, U2>,
-    notification_queue: &'a mut Queue<Response, U16>,
-) -> Initialized<'a, Tx, Rx>
+    response_queue: &'a mut Queue<Response, RQ>,
+    notification_queue: &'a mut Queue<Response, NQ>,
+    profile: ModuleProfile,
+    capabilities: Capabilities,
+) -> Result<Initialized<'a, Tx, Rx, N, RQ, NQ, L>, AdapterError>
     where
         Tx: Write<u8>,
         Rx: Read<u8>,
 {
+    let (adapter_shared, ingress_shared) = Shared::claim().ok_or_else(|| {
+        warn!("more than {} boards initialized", ingress::MAX_BOARDS);
+        AdapterError::UnableToInitialize
+    })?;
     let (response_producer, response_consumer) = response_queue.split();
     let (notification_producer, notification_consumer) = notification_queue.split();
-    (
+    Ok((
         Adapter {
             tx,
             response_consumer,
             notification_consumer,
-            sockets: initialize_sockets(),
+            shared: adapter_shared,
+            sockets: core::array::from_fn(|_| Socket::default()),
+            link_stats: [LinkStats::default(); L],
+            datagrams: Vec::new(),
+            wifi_state: WifiState::Disconnected,
+            wps_state: WpsState::Idle,
+            smart_config_events: Queue::new(),
+            custom_notifications: Queue::new(),
+            addressing: Addressing::Dhcp,
+            profile,
+            capabilities,
+            clock: None,
+            #[cfg(feature = "trace")]
+            tracer: None,
+            timeouts: Timeouts::default(),
+            timeout: 0,
+            busy_retries: 3,
+            busy_backoff_ms: 100,
+            send_retries: 0,
+            last_command: ("", None),
+            command_start: None,
+            restarted: false,
+            stats: Stats::default(),
+            available_stale: false,
+            watchdog_interval: None,
+            last_check: None,
+            reconnect: Reconnect::new(),
+            events: Queue::new(),
+            joining: false,
+            join_start: None,
+            connect_start: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: MqttState::new(),
         },
-        Ingress::new(rx, response_producer, notification_producer),
-    )
+        Ingress::new(rx, response_producer, notification_producer, ingress_shared),
+    ))
 }
 
-fn initialize_sockets() -> [Socket; 5] {
-    [
-        Socket::new(),
-        Socket::new(),
-        Socket::new(),
-        Socket::new(),
-        Socket::new(),
-    ]
+/// The reason a join failed, where the board didn't report one.
+fn join_failure(error: AdapterError) -> WifiConnectionFailure {
+    warn!("join failed: {:?}", error);
+    match error {
+        AdapterError::InvalidParameter => WifiConnectionFailure::InvalidParameter,
+        AdapterError::Timeout
+        | AdapterError::Command(CommandError { observed: Observed::Timeout(_), .. }) => WifiConnectionFailure::Timeout,
+        _ => WifiConnectionFailure::ConnectionFailed,
+    }
 }
 
 fn write_command<Tx>(tx: &mut Tx, cmd: &[u8]) -> Result<(), Tx::Error>
@@ -161,6 +742,34 @@ fn disable_echo<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<(), AdapterError>
     Ok(wait_for_ok(rx).map_err(|_| UnableToInitialize)?)
 }
 
+// Discards whatever the board sent so far, such as boot messages, and makes
+// sure it answers commands without echoing them.
+fn resync<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<(), AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    loop {
+        match rx.read() {
+            Err(nb::Error::WouldBlock) => break,
+            _ => continue,
+        }
+    }
+    disable_echo(tx, rx)?;
+    write_command(tx, b"AT\r\n").map_err(|_| UnableToInitialize)?;
+    wait_for_ok(rx).map_err(|_| UnableToInitialize)
+}
+
+// Keeps ESP-AT from persisting configuration, like the `_CUR` commands of NONOS
+fn disable_persistence<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<(), AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    write_command(tx, b"AT+SYSSTORE=0\r\n").map_err(|_| UnableToInitialize)?;
+    Ok(wait_for_ok(rx).map_err(|_| UnableToInitialize)?)
+}
+
 fn enable_mux<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<(), AdapterError>
     where
         Tx: Write<u8>,
@@ -179,18 +788,89 @@ fn set_recv_mode<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<(), AdapterError>
     Ok(wait_for_ok(rx).map_err(|_| UnableToInitialize)?)
 }
 
-fn wait_for_ok<Rx>(rx: &mut Rx) -> Result<(), Rx::Error>
+fn set_mode<Tx, Rx>(tx: &mut Tx, rx: &mut Rx, dialect: Dialect, mode: WiFiMode) -> Result<(), AdapterError>
     where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    let command = Command::SetMode(mode);
+    let bytes = command.encode(dialect).map_err(|_| UnableToInitialize)?;
+    write_command(tx, bytes.as_bytes()).map_err(|_| UnableToInitialize)?;
+    write_command(tx, b"\r\n").map_err(|_| UnableToInitialize)?;
+    wait_for_ok(rx).map_err(|_| UnableToInitialize)
+}
+
+fn enable_remote_info<Tx, Rx>(tx: &mut Tx, rx: &mut Rx, dialect: Dialect) -> Result<(), AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    let command = Command::SetRemoteInfo(true);
+    let bytes = command.encode(dialect).map_err(|_| UnableToInitialize)?;
+    write_command(tx, bytes.as_bytes()).map_err(|_| UnableToInitialize)?;
+    write_command(tx, b"\r\n").map_err(|_| UnableToInitialize)?;
+    wait_for_ok(rx).map_err(|_| UnableToInitialize)
+}
+
+fn set_flow_control<Tx, Rx>(
+    tx: &mut Tx,
+    rx: &mut Rx,
+    dialect: Dialect,
+    baud_rate: u32,
+    flow_control: FlowControl,
+) -> Result<(), AdapterError>
+    where
+        Tx: Write<u8>,
+        Rx: Read<u8>,
+{
+    let command = Command::SetUart { baud_rate, flow_control };
+    let bytes = command.encode(dialect).map_err(|_| UnableToInitialize)?;
+    write_command(tx, bytes.as_bytes()).map_err(|_| UnableToInitialize)?;
+    write_command(tx, b"\r\n").map_err(|_| UnableToInitialize)?;
+    wait_for_ok(rx).map_err(|_| UnableToInitialize)
+}
+
+fn query_firmware_info<Tx, Rx>(tx: &mut Tx, rx: &mut Rx) -> Result<Option<FirmwareInfo>, AdapterError>
+    where
+        Tx: Write<u8>,
         Rx: Read<u8>,
 {
-    let mut buf: [u8; 64] = [0; 64];
+    write_command(tx, b"AT+GMR\r\n").map_err(|_| UnableToInitialize)?;
+
+    let mut buf: [u8; 512] = [0; 512];
     let mut pos = 0;
+    let mut tail: [u8; 4] = [0; 4];
+
+    loop {
+        let b = nb::block!(rx.read()).map_err(|_| UnableToInitialize)?;
+        if pos < buf.len() {
+            buf[pos] = b;
+            pos += 1;
+        }
+        tail.rotate_left(1);
+        tail[3] = b;
+        if tail == *b"OK\r\n" {
+            break;
+        }
+    }
+
+    match parser::firmware_info(&buf[0..pos]) {
+        Ok((_, Response::FirmwareInfo(info))) => Ok(Some(info)),
+        _ => Ok(None),
+    }
+}
+
+fn wait_for_ok<Rx>(rx: &mut Rx) -> Result<(), Rx::Error>
+    where
+        Rx: Read<u8>,
+{
+    let mut tail: [u8; 4] = [0; 4];
 
     loop {
         let b = nb::block!(rx.read())?;
-        buf[pos] = b;
-        pos += 1;
-        if buf[0..pos].ends_with(b"OK\r\n") {
+        tail.rotate_left(1);
+        tail[3] = b;
+        if tail == *b"OK\r\n" {
             return Ok(());
         }
     }
@@ -199,6 +879,12 @@ fn wait_for_ok<Rx>(rx: &mut Rx) -> Result<(), Rx::Error>
 struct Socket {
     state: SocketState,
     available: usize,
+    incoming: bool,
+    keepalive: Option<u16>,
+    peer: Option<SocketAddr>,
+    tls: Option<TlsConfig>,
+    /// No more data may be written, see `shutdown`.
+    write_shutdown: bool,
 }
 
 impl Socket {
@@ -206,6 +892,11 @@ impl Socket {
         Self {
             state: SocketState::Closed,
             available: 0,
+            incoming: false,
+            keepalive: None,
+            peer: None,
+            tls: None,
+            write_shutdown: false,
         }
     }
 
@@ -230,17 +921,73 @@ impl Socket {
     }
 }
 
-pub struct Adapter<'a, Tx>
+impl Default for Socket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A datagram delivered by the board for a UDP link, waiting to be read.
+struct Datagram {
+    link_id: usize,
+    data: Payload,
+    // bytes of `data` already read
+    read: usize,
+    remote: Option<SocketAddr>,
+}
+
+impl Datagram {
+    fn unread(&self) -> &[u8] {
+        &self.data[self.read..]
+    }
+}
+
+pub struct Adapter<'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
     where
         Tx: Write<u8>,
 {
     tx: Tx,
-    response_consumer: Consumer<'a, Response, U2>,
-    notification_consumer: Consumer<'a, Response, U16>,
-    sockets: [Socket; 5],
+    response_consumer: Consumer<'a, Response, RQ>,
+    notification_consumer: Consumer<'a, Response, NQ>,
+    shared: SharedRef,
+    sockets: [Socket; L],
+    link_stats: [LinkStats; L],
+    datagrams: Vec<Datagram, 2>,
+    wifi_state: WifiState,
+    wps_state: WpsState,
+    smart_config_events: Queue<SmartConfigEvent, 3>,
+    custom_notifications: Queue<Custom, 3>,
+    addressing: Addressing,
+    profile: ModuleProfile,
+    capabilities: Capabilities,
+    clock: Option<&'a dyn Clock>,
+    #[cfg(feature = "trace")]
+    tracer: Option<&'a dyn Tracer>,
+    timeouts: Timeouts,
+    timeout: u32,
+    busy_retries: u8,
+    busy_backoff_ms: u32,
+    send_retries: u8,
+    /// Name and link of the last command written, for `CommandError`.
+    last_command: (&'static str, Option<usize>),
+    /// When the last command was written, to time out `poll_response`.
+    command_start: Option<u32>,
+    restarted: bool,
+    stats: Stats,
+    /// The bytes available to links may be off, see `refresh_available`.
+    available_stale: bool,
+    watchdog_interval: Option<u32>,
+    last_check: Option<u32>,
+    pub(crate) reconnect: Reconnect,
+    events: Queue<Event, 9>,
+    joining: bool,
+    join_start: Option<u32>,
+    connect_start: Option<u32>,
+    #[cfg(feature = "mqtt")]
+    pub(crate) mqtt: MqttState,
 }
 
-impl<'a, Tx> Debug for Adapter<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Debug for Adapter<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
@@ -250,69 +997,426 @@ impl<'a, Tx> Debug for Adapter<'a, Tx>
     }
 }
 
-impl<'a, Tx> Adapter<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Adapter<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
-    fn send<'c>(&mut self, command: Command<'c>) -> Result<Response, AdapterError> {
-        let bytes = command.as_bytes();
+    pub(crate) fn send<'c>(&mut self, command: Command<'c>) -> Result<Response, AdapterError> {
+        let mut retries = 0;
+        loop {
+            self.write_command(&command)?;
+            match self.wait_for_response()? {
+                Response::Busy if retries < self.busy_retries => {
+                    retries += 1;
+                    self.stats.retries = self.stats.retries.wrapping_add(1);
+                    self.back_off();
+                }
+                Response::Busy => return Err(AdapterError::Busy),
+                response => return Ok(response),
+            }
+        }
+    }
 
-        info!(
-            "writing command {}",
-            core::str::from_utf8(bytes.as_bytes()).unwrap()
-        );
-        for b in bytes.as_bytes().iter() {
+    /// The error of the last command, which the board answered with
+    /// `response` rather than the expected one.
+    pub(crate) fn unexpected(&self, response: &Response) -> AdapterError {
+        self.command_error(Observed::Response(response.name()))
+    }
+
+    /// The error of the last command given the outcome of sending it,
+    /// keeping errors other than a missing or busy answer as they are.
+    pub(crate) fn failed(&self, outcome: Result<Response, AdapterError>) -> AdapterError {
+        match outcome {
+            Ok(response) => self.unexpected(&response),
+            Err(AdapterError::ResponseTimeout(elapsed)) => self.command_error(Observed::Timeout(elapsed)),
+            Err(AdapterError::Busy) => self.command_error(Observed::Busy),
+            Err(error) => error,
+        }
+    }
+
+    fn command_error(&self, observed: Observed) -> AdapterError {
+        let (command, link_id) = self.last_command;
+        AdapterError::Command(CommandError { command, link_id, observed })
+    }
+
+    // Without a clock, commands are retried right away.
+    fn back_off(&self) {
+        if let Some(clock) = self.clock {
+            let start = clock.now_ms();
+            while clock.now_ms().wrapping_sub(start) < self.busy_backoff_ms {
+                // busy loop until the backoff elapsed.
+            }
+        }
+    }
+
+    /// Set how often a command is retried while the board reports being busy,
+    /// and how long to wait in between. Waiting requires a clock, see `set_clock`.
+    ///
+    /// Defaults to 3 retries, 100 ms apart.
+    pub fn set_busy_retries(&mut self, retries: u8, backoff_ms: u32) {
+        self.busy_retries = retries;
+        self.busy_backoff_ms = backoff_ms;
+    }
+
+    /// Set how often data is sent again once the board reported `SEND FAIL`,
+    /// waiting as long as for a busy board in between, see `set_busy_retries`.
+    ///
+    /// Defaults to none, reporting `SocketError::SendFailed` right away.
+    pub fn set_send_retries(&mut self, retries: u8) {
+        self.send_retries = retries;
+    }
+
+    fn write_command<'c>(&mut self, command: &Command<'c>) -> Result<(), AdapterError> {
+        let bytes = command.encode(self.capabilities.dialect)?;
+        self.timeout = self.timeouts.for_command(command);
+        self.last_command = (command.name(), command.link_id());
+        self.command_start = self.now_ms();
+        self.stats.commands = self.stats.commands.wrapping_add(1);
+
+        info!("writing command {}", bytes.as_str());
+        self.write_data(bytes.as_bytes())?;
+        self.write_data(b"\r\n")
+    }
+
+    /// Write raw data following a `>` prompt from the board.
+    pub(crate) fn write_data(&mut self, data: &[u8]) -> Result<(), AdapterError> {
+        #[cfg(feature = "trace")]
+        if let Some(tracer) = self.tracer {
+            tracer.trace(Direction::Sent, data);
+        }
+        self.stats.bytes_sent = self.stats.bytes_sent.wrapping_add(data.len());
+        for b in data.iter() {
             nb::block!(self.tx.write(*b)).map_err(|_| AdapterError::WriteError)?;
         }
-        nb::block!(self.tx.write(b'\r')).map_err(|_| AdapterError::WriteError)?;
-        nb::block!(self.tx.write(b'\n')).map_err(|_| AdapterError::WriteError)?;
-        self.wait_for_response()
+        Ok(())
+    }
+
+    pub(crate) fn take_response(&mut self) -> Option<Response> {
+        self.response_consumer.dequeue()
+    }
+
+    /// Take the response to the last command if it arrived, without waiting,
+    /// so that callers driving their own scheduler can do other work in
+    /// between, such as after `write_raw`.
+    ///
+    /// Once a clock is set, fails with `AdapterError::ResponseTimeout` after
+    /// the command's timeout elapsed since it was written, see `set_clock`.
+    /// Further polls then return `WouldBlock` until a response arrives.
+    pub fn poll_response(&mut self) -> nb::Result<Response, AdapterError> {
+        if let Some(response) = self.response_consumer.dequeue() {
+            return Ok(response);
+        }
+        if let Err(error) = self.check_timeout(self.command_start) {
+            self.command_start = None;
+            return Err(nb::Error::Other(error));
+        }
+        Err(nb::Error::WouldBlock)
     }
 
-    fn wait_for_response(&mut self) -> Result<Response, AdapterError> {
+    /// Wait for the response to the last command, for as long as its class
+    /// of command allows once a clock is set.
+    pub(crate) fn wait_for_response(&mut self) -> Result<Response, AdapterError> {
+        let start = self.clock.map(|clock| clock.now_ms());
         loop {
             // busy loop until a response is received.
             if let Some(response) = self.response_consumer.dequeue() {
                 return Ok(response);
             }
+            self.check_timeout(start)?;
         }
     }
 
-    /// Retrieve the firmware version for the adapter.
-    pub fn get_firmware_info(&mut self) -> Result<FirmwareInfo, ()> {
-        let command = Command::QueryFirmwareInfo;
+    /// Wait for the board to report being ready after it restarted.
+    fn wait_for_restart(&mut self) -> Result<(), AdapterError> {
+        let start = self.clock.map(|clock| clock.now_ms());
+        loop {
+            self.process_notifications();
+            if self.restarted {
+                self.restarted = false;
+                return Ok(());
+            }
+            self.check_timeout(start)?;
+        }
+    }
 
-        if let Ok(Response::FirmwareInfo(info)) = self.send(command) {
-            return Ok(info);
+    fn check_timeout(&mut self, start: Option<u32>) -> Result<(), AdapterError> {
+        if let (Some(clock), Some(start)) = (self.clock, start) {
+            let elapsed = clock.now_ms().wrapping_sub(start);
+            if elapsed >= self.timeout {
+                warn!("no response after {} ms", elapsed);
+                self.stats.timeouts = self.stats.timeouts.wrapping_add(1);
+                return Err(AdapterError::ResponseTimeout(elapsed));
+            }
         }
+        Ok(())
+    }
 
-        Err(())
+    /// The current time, if a clock is set.
+    pub(crate) fn now_ms(&self) -> Option<u32> {
+        self.clock.map(|clock| clock.now_ms())
     }
 
-    /// Get the board's IP address. Only valid if connected to an access-point.
-    pub fn get_ip_address(&mut self) -> Result<IpAddresses, ()> {
-        let command = Command::QueryIpAddress;
+    /// The state shared with the ingress.
+    pub(crate) fn shared(&self) -> &'static Shared {
+        self.shared.get()
+    }
 
-        if let Ok(Response::IpAddresses(addresses)) = self.send(command) {
-            return Ok(addresses);
-        }
+    /// Time out commands the board does not respond to, using `clock`.
+    ///
+    /// Without a clock, the adapter waits for responses indefinitely.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock, timeouts: Timeouts) {
+        self.clock = Some(clock);
+        self.timeouts = timeouts;
+    }
 
-        Err(())
+    /// Pass all bytes written to the board to `tracer`, see the `trace` module.
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: &'a dyn Tracer) {
+        self.tracer = Some(tracer);
     }
 
-    /// Set the mode of the Wi-Fi stack
+    /// Resynchronize with the board, after it sent unexpected data or
+    /// responses no longer match their commands.
     ///
-    /// Must be done before joining an access point.
-    pub fn set_mode(&mut self, mode: WiFiMode) -> Result<(), ()> {
-        let command = Command::SetMode(mode);
+    /// Discards all pending responses and received bytes, disables echo, and
+    /// checks the board responds to `AT`. The `Ingress` must be digesting
+    /// for this to complete; with a clock set, this fails with `Timeout` if
+    /// the ingress doesn't discard its buffer within the default timeout.
+    pub fn resync(&mut self) -> Result<(), AdapterError> {
+        let shared = self.shared();
+        shared.request_flush();
+        self.timeout = self.timeouts.default;
+        let start = self.now_ms();
+        while shared.flush_pending() {
+            self.check_timeout(start).map_err(|_| AdapterError::Timeout)?;
+        }
+        while self.response_consumer.dequeue().is_some() {}
 
-        match self.send(command) {
-            Ok(Response::Ok) => Ok(()),
-            _ => Err(()),
+        self.send(Command::DisableEcho)?;
+        match self.send(Command::Attention)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
         }
     }
 
-    /// Join a wifi access-point.
+    /// Features of the module and its firmware, as detected by `initialize`.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The command set used to talk to the board.
+    pub fn dialect(&self) -> Dialect {
+        self.capabilities.dialect
+    }
+
+    /// Select the command set used to talk to the board, overriding the one
+    /// detected by `initialize`.
+    pub fn set_dialect(&mut self, dialect: Dialect) -> Result<(), AdapterError> {
+        self.capabilities = Capabilities::detect(&self.profile, dialect, self.capabilities.firmware, L);
+        if let Dialect::EspAt = dialect {
+            match self.send(Command::DisablePersistence)? {
+                Response::Ok => {}
+                other => return Err(self.unexpected(&other)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Select the command set matching the firmware version reported by the board.
+    pub fn detect_dialect(&mut self) -> Result<Dialect, AdapterError> {
+        let info = self.get_firmware_info()?;
+        let dialect = Dialect::for_firmware(&info);
+        self.capabilities.firmware = Some(info);
+        self.set_dialect(dialect)?;
+        Ok(dialect)
+    }
+
+    /// Retrieve the firmware version for the adapter.
+    pub fn get_firmware_info(&mut self) -> Result<FirmwareInfo, AdapterError> {
+        let command = Command::QueryFirmwareInfo;
+
+        match self.send(command) {
+            Ok(Response::FirmwareInfo(info)) => Ok(info),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Get the board's IP address. Only valid if connected to an access-point.
+    pub fn get_ip_address(&mut self) -> Result<IpAddresses, AdapterError> {
+        let command = Command::QueryIpAddress;
+
+        match self.send(command) {
+            Ok(Response::IpAddresses(mut addresses)) => {
+                addresses.addressing = self.addressing;
+                Ok(addresses)
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Assign a static IP address to the board, disabling DHCP for the station.
+    ///
+    /// The setting is not persisted.
+    ///
+    /// * `ip`: The board's address.
+    /// * `gateway`: The default gateway.
+    /// * `netmask`: The netmask of the network.
+    pub fn set_station_ip(
+        &mut self,
+        ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        netmask: Ipv4Addr,
+    ) -> Result<(), AdapterError> {
+        let command = Command::SetIpAddress {
+            ip,
+            gateway,
+            netmask,
+        };
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                self.addressing = Addressing::Static;
+                Ok(())
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Enable or disable DHCP for the station interface.
+    ///
+    /// Assigning a static IP address using `set_station_ip` implicitly
+    /// disables DHCP. The setting is not persisted.
+    pub fn set_dhcp(&mut self, enabled: bool) -> Result<(), AdapterError> {
+        let command = Command::SetDhcp {
+            interface: Interface::Station,
+            enabled,
+        };
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                self.addressing = if enabled {
+                    Addressing::Dhcp
+                } else {
+                    Addressing::Static
+                };
+                Ok(())
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Get the MAC address of the station interface.
+    pub fn get_station_mac(&mut self) -> Result<MacAddress, AdapterError> {
+        self.get_mac(Interface::Station)
+    }
+
+    /// Set the MAC address of the station interface.
+    ///
+    /// The address must be unicast, and differ from the soft access point's
+    /// address. The setting is not persisted.
+    pub fn set_station_mac(&mut self, mac: MacAddress) -> Result<(), AdapterError> {
+        self.set_mac(Interface::Station, mac)
+    }
+
+    /// Get the MAC address of the soft access point interface.
+    pub fn get_ap_mac(&mut self) -> Result<MacAddress, AdapterError> {
+        self.get_mac(Interface::SoftAccessPoint)
+    }
+
+    /// Set the MAC address of the soft access point interface.
+    ///
+    /// The address must be unicast, and differ from the station's address.
+    /// The setting is not persisted.
+    pub fn set_ap_mac(&mut self, mac: MacAddress) -> Result<(), AdapterError> {
+        self.set_mac(Interface::SoftAccessPoint, mac)
+    }
+
+    fn get_mac(&mut self, interface: Interface) -> Result<MacAddress, AdapterError> {
+        let command = Command::QueryMacAddress(interface);
+
+        match self.send(command) {
+            Ok(Response::MacAddress(mac)) => Ok(mac),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    fn set_mac(&mut self, interface: Interface, mac: MacAddress) -> Result<(), AdapterError> {
+        let command = Command::SetMacAddress(interface, mac);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// List the stations connected to the soft access point.
+    ///
+    /// Stations are added to `results`, any exceeding its capacity are dropped.
+    pub fn connected_stations<const N: usize>(&mut self, results: &mut Vec<StationInfo, N>) -> Result<(), AdapterError> {
+        let command = Command::QueryStations;
+
+        let mut response = self.send(command);
+        loop {
+            match response {
+                Ok(Response::Station(station)) => {
+                    results.push(station).ok();
+                }
+                Ok(Response::Ok) => return Ok(()),
+                other => return Err(self.failed(other)),
+            }
+            response = self.wait_for_response();
+        }
+    }
+
+    /// Configure the pool of addresses leased by the soft access point's DHCP server.
+    ///
+    /// The range must be within the soft access point's subnet. The setting is
+    /// not persisted.
+    ///
+    /// * `lease_minutes`: The lease time, between 1 and 2880 minutes.
+    /// * `start`: The first address of the pool.
+    /// * `end`: The last address of the pool.
+    pub fn configure_dhcp_server(
+        &mut self,
+        lease_minutes: u16,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+    ) -> Result<(), AdapterError> {
+        let command = Command::ConfigureDhcpServer {
+            lease_minutes,
+            start,
+            end,
+        };
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Restore the default address pool of the soft access point's DHCP server.
+    pub fn reset_dhcp_server(&mut self) -> Result<(), AdapterError> {
+        let command = Command::ResetDhcpServer;
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Set the mode of the Wi-Fi stack
+    ///
+    /// Must be done before joining an access point.
+    pub fn set_mode(&mut self, mode: WiFiMode) -> Result<(), AdapterError> {
+        let command = Command::SetMode(mode);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Join a wifi access-point.
     ///
     /// The board will expect to obtain an IP address from DHCP.
     ///
@@ -323,152 +1427,1458 @@ impl<'a, Tx> Adapter<'a, Tx>
         ssid: &'c str,
         password: &'c str,
     ) -> Result<(), WifiConnectionFailure> {
-        let command = Command::JoinAp { ssid, password };
+        self.join_bssid(ssid, password, None)
+    }
+
+    /// Join a wifi access-point, like `join`, optionally pinned to a specific radio.
+    ///
+    /// * `bssid`: The BSSID of the access-point, when several share the same SSID.
+    pub fn join_bssid<'c>(
+        &mut self,
+        ssid: &'c str,
+        password: &'c str,
+        bssid: Option<MacAddress>,
+    ) -> Result<(), WifiConnectionFailure> {
+        let command = Command::JoinAp { ssid, password, bssid };
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                self.reconnect.remember(ssid, password, bssid);
+                Ok(())
+            }
+            Ok(Response::WifiConnectionFailure(reason)) => {
+                Err(reason)
+            }
+            other => Err(join_failure(self.failed(other))),
+        }
+    }
+
+    /// Join a wifi access-point without blocking while the board associates,
+    /// which takes several seconds.
+    ///
+    /// The first call sends the command, and further calls poll for its
+    /// outcome; they must pass the same credentials until this completes. No
+    /// other commands may be sent in the meantime. Times out once a clock is
+    /// set, see `set_clock`.
+    pub fn join_nb(&mut self, ssid: &str, password: &str) -> nb::Result<(), WifiConnectionFailure> {
+        if !self.joining {
+            let command = Command::JoinAp { ssid, password, bssid: None };
+            self.write_command(&command)
+                .map_err(|error| nb::Error::Other(join_failure(error)))?;
+            self.joining = true;
+            self.join_start = self.now_ms();
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let result = match self.take_response() {
+            Some(Response::Ok) => {
+                self.reconnect.remember(ssid, password, None);
+                Ok(())
+            }
+            Some(Response::WifiConnectionFailure(reason)) => Err(nb::Error::Other(reason)),
+            Some(other) => Err(nb::Error::Other(join_failure(self.unexpected(&other)))),
+            None => {
+                self.timeout = self.timeouts.network;
+                match self.check_timeout(self.join_start) {
+                    Ok(()) => return Err(nb::Error::WouldBlock),
+                    Err(_) => Err(nb::Error::Other(WifiConnectionFailure::Timeout)),
+                }
+            }
+        };
+        self.joining = false;
+        self.join_start = None;
+        result
+    }
+
+    /// Join a WPA2-Enterprise access point.
+    ///
+    /// Only supported by firmware providing `AT+CWJEAP`.
+    pub fn join_enterprise(
+        &mut self,
+        credentials: EnterpriseCredentials,
+    ) -> Result<(), WifiConnectionFailure> {
+        let command = Command::JoinEnterpriseAp(credentials);
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                Ok(())
+            }
+            Ok(Response::WifiConnectionFailure(reason)) => {
+                Err(reason)
+            }
+            other => Err(join_failure(self.failed(other))),
+        }
+    }
+
+    /// Start WPS push-button pairing, joining the access point on which the
+    /// WPS button is pressed within the next two minutes.
+    ///
+    /// Requires station mode. Use `wps_result` to learn when pairing completes.
+    pub fn start_wps(&mut self) -> Result<(), WifiConnectionFailure> {
+        match self.send(Command::StartWps) {
+            Ok(Response::Ok) => {
+                self.wps_state = WpsState::Pairing;
+                Ok(())
+            }
+            other => Err(join_failure(self.failed(other))),
+        }
+    }
+
+    /// Abort WPS pairing.
+    pub fn stop_wps(&mut self) -> Result<(), AdapterError> {
+        match self.send(Command::StopWps) {
+            Ok(Response::Ok) => {
+                self.wps_state = WpsState::Idle;
+                Ok(())
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// The outcome of WPS pairing started with `start_wps`.
+    ///
+    /// Completes once the board has paired and obtained an IP address.
+    pub fn wps_result(&mut self) -> nb::Result<(), WifiConnectionFailure> {
+        self.process_notifications();
+        match self.wps_state {
+            WpsState::Paired if self.wifi_state == WifiState::GotIp => Ok(()),
+            WpsState::Pairing | WpsState::Paired => Err(nb::Error::WouldBlock),
+            WpsState::Failed(reason) => Err(nb::Error::Other(reason)),
+            WpsState::Idle => Err(nb::Error::Other(WifiConnectionFailure::ConnectionFailed)),
+        }
+    }
+
+    /// Start SmartConfig provisioning, waiting for a phone app to send the
+    /// credentials of an access point.
+    ///
+    /// Requires station mode. Progress is reported by `smart_config_event`.
+    pub fn start_smart_config(&mut self, kind: SmartConfigType) -> Result<(), AdapterError> {
+        match self.send(Command::StartSmartConfig(kind)) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Stop SmartConfig provisioning, which must also be done after it succeeded.
+    pub fn stop_smart_config(&mut self) -> Result<(), AdapterError> {
+        match self.send(Command::StopSmartConfig) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// The next SmartConfig provisioning event, if any.
+    pub fn smart_config_event(&mut self) -> Option<SmartConfigEvent> {
+        self.process_notifications();
+        self.smart_config_events.dequeue()
+    }
+
+    /// Configure the soft access point of the board.
+    ///
+    /// The settings take effect immediately, but are not persisted. The Wi-Fi
+    /// mode must include the soft access point, see `set_mode`.
+    pub fn configure_ap(&mut self, config: SoftApConfig) -> Result<(), AdapterError> {
+        let command = Command::ConfigureAp(config);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Retrieve the current configuration of the soft access point.
+    pub fn get_ap_config(&mut self) -> Result<SoftApInfo, AdapterError> {
+        let command = Command::QueryApConfiguration;
+
+        match self.send(command) {
+            Ok(Response::SoftApInfo(info)) => Ok(info),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Retrieve information about the joined access point, including the signal strength.
+    ///
+    /// Returns `None` if the board has not joined an access point.
+    pub fn get_ap_info(&mut self) -> Result<Option<JoinedAccessPoint>, AdapterError> {
+        let command = Command::QueryJoinedAccessPoint;
+
+        match self.send(command) {
+            Ok(Response::JoinedAccessPoint(info)) => Ok(Some(info)),
+            Ok(Response::NoAccessPoint) => Ok(None),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Scan for access points in range.
+    ///
+    /// Found access points are added to `results`, any exceeding its capacity
+    /// are dropped. A scan takes a few seconds.
+    pub fn scan<const N: usize>(&mut self, results: &mut Vec<AccessPoint, N>) -> Result<(), AdapterError> {
+        let command = Command::ScanAccessPoints;
+
+        let mut response = self.send(command);
+        loop {
+            match response {
+                Ok(Response::AccessPoint(ap)) => {
+                    results.push(ap).ok();
+                }
+                Ok(Response::Ok) => return Ok(()),
+                other => return Err(self.failed(other)),
+            }
+            response = self.wait_for_response();
+        }
+    }
+
+    /// Leave the joined access point.
+    ///
+    /// Connections of the station interface are dropped by the board. Their
+    /// sockets no longer deliver data and must be closed.
+    pub fn leave(&mut self) -> Result<(), AdapterError> {
+        let command = Command::LeaveAp;
+        self.reconnect.forget();
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                self.process_notifications();
+                self.wifi_state = WifiState::Disconnected;
+                self.invalidate_sockets();
+                Ok(())
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Set the regulatory domain, restricting the channels used by the board.
+    ///
+    /// Channels range from 1 to 14.
+    pub fn set_country(&mut self, config: CountryConfig) -> Result<(), AdapterError> {
+        let last_channel = config.start_channel as u16 + config.channel_count as u16;
+        if config.start_channel == 0 || config.channel_count == 0 || last_channel > 15 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::SetCountry(config);
+
+        match self.send(command)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Change the baud rate and flow control of the board's UART, until it is reset.
+    ///
+    /// The board acknowledges the command at the current baud rate and then
+    /// switches over, at which point `reconfigure` is invoked with the new baud
+    /// rate to re-clock the host's UART. Data sent by the board in between is lost.
+    pub fn set_baud_rate<F>(
+        &mut self,
+        baud_rate: u32,
+        flow_control: FlowControl,
+        reconfigure: F,
+    ) -> Result<(), AdapterError>
+        where
+            F: FnOnce(u32),
+    {
+        if !(110..=4_608_000).contains(&baud_rate) {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::SetUart { baud_rate, flow_control };
+
+        match self.send(command)? {
+            Response::Ok => {
+                reconfigure(baud_rate);
+                Ok(())
+            }
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Set the power saving mode used while the station is idle.
+    ///
+    /// See `SleepMode` for the latency of each mode.
+    pub fn set_sleep_mode(&mut self, mode: SleepMode) -> Result<(), AdapterError> {
+        let command = Command::SetSleepMode(mode);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Set the maximum transmit power, in units of 0.25 dBm.
+    ///
+    /// Valid values range from 0 up to 82 (20.5 dBm).
+    pub fn set_tx_power(&mut self, power: u8) -> Result<(), AdapterError> {
+        if power > 82 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::SetTxPower(power);
+
+        match self.send(command)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Enable or disable reporting the sender's address along with received data,
+    /// which is required to tell apart the peers of a UDP link.
+    pub fn set_remote_info(&mut self, enabled: bool) -> Result<(), AdapterError> {
+        let command = Command::SetRemoteInfo(enabled);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Put the board into deep sleep for `ms` milliseconds.
+    ///
+    /// The board resets when it wakes, which requires GPIO16 to be wired to
+    /// its reset pin. All sockets are marked closed, and must be closed by their
+    /// owners. Call `reinitialize` once the board has woken up.
+    pub fn deep_sleep(&mut self, ms: u32) -> Result<(), AdapterError> {
+        let command = Command::DeepSleep { ms };
+
+        match self.send(command) {
+            Ok(Response::Ok) => {
+                self.reset_state();
+                Ok(())
+            }
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Wait for the board to report being ready after a reset, then restore
+    /// the settings applied by `initialize`.
+    pub fn reinitialize(&mut self) -> Result<(), AdapterError> {
+        debug!("waiting for adapter to become ready");
+        self.wait_for_restart()?;
+        debug!("adapter is ready");
+        self.apply_init()
+    }
+
+    /// Whether the board restarted on its own, such as after a brown-out,
+    /// and must be recovered using `recover`.
+    pub fn has_restarted(&mut self) -> bool {
+        self.process_notifications();
+        self.restarted
+    }
+
+    /// Recover from the board restarting on its own, restoring the settings
+    /// applied by `initialize`.
+    ///
+    /// All sockets are marked closed, and must be closed by their owners.
+    pub fn recover(&mut self) -> Result<(), AdapterError> {
+        self.reset_state();
+        self.restarted = false;
+        self.apply_init()
+    }
+
+    /// The number of lines received from the board which could not be parsed,
+    /// and were dropped by the ingress, wrapping on overflow.
+    ///
+    /// Increasing counts hint at firmware output this crate doesn't know.
+    pub fn parse_failures(&self) -> usize {
+        ingress::parse_failures()
+    }
+
+    /// Counters of the traffic with the board since initialized, such as for
+    /// publishing the health of the link.
+    pub fn stats(&mut self) -> Stats {
+        self.process_notifications();
+        Stats {
+            bytes_received: ingress::bytes_received(),
+            parse_failures: ingress::parse_failures(),
+            ..self.stats
+        }
+    }
+
+    /// Check the board is alive, by sending a bare `AT`.
+    ///
+    /// Only times out once a clock is set, see `set_clock`; otherwise a hung
+    /// board blocks this indefinitely.
+    pub fn check_alive(&mut self) -> Health {
+        if self.has_restarted() {
+            return Health::Restarted;
+        }
+        if self.write_command(&Command::Attention).is_err() {
+            return Health::Unresponsive;
+        }
+        match self.wait_for_response() {
+            Ok(Response::Ok) => Health::Alive,
+            Ok(Response::Busy) => Health::Busy,
+            Ok(_) => Health::OutOfSync,
+            Err(_) => Health::Unresponsive,
+        }
+    }
+
+    /// Check the board is alive every `interval_ms` from within
+    /// `poll_watchdog`, or never if `None`. Requires a clock, see `set_clock`.
+    pub fn set_watchdog(&mut self, interval_ms: Option<u32>) {
+        self.watchdog_interval = interval_ms;
+        self.last_check = None;
+    }
+
+    /// Meant to be called periodically, such as from the application's main
+    /// loop. Runs `check_alive` once the watchdog interval elapsed since the
+    /// last check, and returns its result.
+    pub fn poll_watchdog(&mut self) -> Option<Health> {
+        let (clock, interval) = match (self.clock, self.watchdog_interval) {
+            (Some(clock), Some(interval)) => (clock, interval),
+            _ => return None,
+        };
+        let now = clock.now_ms();
+        match self.last_check {
+            Some(last) if now.wrapping_sub(last) < interval => None,
+            Some(_) => {
+                self.last_check = Some(now);
+                Some(self.check_alive())
+            }
+            None => {
+                self.last_check = Some(now);
+                None
+            }
+        }
+    }
+
+    fn apply_init(&mut self) -> Result<(), AdapterError> {
+        self.send_init(Command::DisableEcho)?;
+        if let Dialect::EspAt = self.capabilities.dialect {
+            self.send_init(Command::DisablePersistence)?;
+        }
+        self.send_init(Command::EnableMux)?;
+        if self.capabilities.passive_receive {
+            self.send_init(Command::SetRecvMode)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the factory defaults of the board, erasing all persisted settings
+    /// such as the stored access point.
+    ///
+    /// The board resets and is re-initialized; all sockets are marked closed, and
+    /// must be closed by their owners.
+    pub fn factory_restore(&mut self) -> Result<(), AdapterError> {
+        self.restarted = false;
+        match self.send(Command::Restore)? {
+            Response::Ok => {}
+            other => return Err(self.unexpected(&other)),
+        }
+
+        self.reset_state();
+        self.reinitialize()
+    }
+
+    /// Reset the board, recovering it from an unknown state.
+    ///
+    /// All sockets are marked closed, and must be closed by their owners.
+    pub fn reset(&mut self) -> Result<(), AdapterError> {
+        self.restarted = false;
+        match self.send(Command::Reset)? {
+            Response::Ok => {}
+            other => return Err(self.unexpected(&other)),
+        }
+
+        self.reset_state();
+        self.reinitialize()
+    }
+
+    /// Track the state of the board after it has been reset.
+    fn reset_state(&mut self) {
+        self.process_notifications();
+        self.wifi_state = WifiState::Disconnected;
+        self.addressing = Addressing::Dhcp;
+        self.invalidate_sockets();
+    }
+
+    fn send_init(&mut self, command: Command) -> Result<(), AdapterError> {
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            _ => Err(AdapterError::UnableToInitialize),
+        }
+    }
+
+    /// Update the AT firmware of the board over the air, from Espressif's servers.
+    ///
+    /// The board must have joined an access point with internet access. Each
+    /// stage of the update is reported to `progress`. Once updated, the board
+    /// resets and is re-initialized; all sockets are marked closed, and must
+    /// be closed by their owners.
+    pub fn update_firmware<F>(&mut self, mut progress: F) -> Result<(), AdapterError>
+        where
+            F: FnMut(UpdateProgress),
+    {
+        self.restarted = false;
+        // also sets the timeout to `Timeouts::update`
+        self.write_command(&Command::UpdateFirmware)?;
+
+        let start = self.now_ms();
+        loop {
+            if let Some(notification) = self.notification_consumer.dequeue() {
+                match notification {
+                    Response::UpdateProgress(stage) => progress(stage),
+                    notification => self.handle_notification(notification),
+                }
+            }
+            match self.response_consumer.dequeue() {
+                Some(Response::Ok) => break,
+                Some(other) => return Err(self.unexpected(&other)),
+                None => self.check_timeout(start)?,
+            }
+        }
+
+        self.reset_state();
+        self.reinitialize()
+    }
+
+    /// Enable or disable joining the stored access point when the board boots.
+    ///
+    /// The setting is persisted by the board. When disabled, credentials used
+    /// by `join` are still stored, but only used when joining explicitly.
+    pub fn set_auto_connect(&mut self, enabled: bool) -> Result<(), AdapterError> {
+        let command = Command::SetAutoConnect(enabled);
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// The current state of the Wi-Fi station.
+    pub fn wifi_state(&mut self) -> WifiState {
+        self.process_notifications();
+        self.wifi_state
+    }
+
+    /// Wait until an IP address was obtained for the station, such as after
+    /// `join`, which returns before DHCP completed.
+    ///
+    /// Only times out after `timeout_ms` once a clock is set, see `set_clock`.
+    pub fn wait_for_wifi_ip(&mut self, timeout_ms: u32) -> Result<IpAddresses, AdapterError> {
+        let start = self.now_ms();
+        self.timeout = timeout_ms;
+        loop {
+            self.process_notifications();
+            if self.wifi_state == WifiState::GotIp {
+                break;
+            }
+            self.check_timeout(start)?;
+        }
+        self.get_ip_address()
+    }
+
+    /// The next unsolicited event reported by the board, if any.
+    ///
+    /// Events are queued until retrieved; once the queue is full, further
+    /// events are dropped.
+    pub fn event(&mut self) -> Option<Event> {
+        self.process_notifications();
+        self.events.dequeue()
+    }
+
+    /// The next notification parsed by the `UnsolicitedParser` registered
+    /// with the ingress, if any.
+    ///
+    /// Up to two notifications are queued until retrieved, each holding a
+    /// payload slot; once the queue is full, further ones are dropped.
+    pub fn custom_notification(&mut self) -> Option<Custom> {
+        self.process_notifications();
+        self.custom_notifications.dequeue()
+    }
+
+    /// Send a command the driver doesn't wrap, such as `AT+SYSRAM?`, without
+    /// its line end.
+    ///
+    /// Each response to it is passed to `matcher`, until it returns true for
+    /// the one ending the command, which is returned. Lines the driver can't
+    /// parse only reach the `UnsolicitedParser` registered with the ingress,
+    /// if any, see `custom_notification`.
+    ///
+    /// ```ignore
+    /// adapter.send_raw("AT+SYSRAM?", |response| matches!(response, Response::Ok | Response::Error))?;
+    /// ```
+    pub fn send_raw<F>(&mut self, command: &str, mut matcher: F) -> Result<Response, AdapterError>
+        where
+            F: FnMut(&Response) -> bool,
+    {
+        Self::check_raw(command)?;
+        let mut response = self.send(Command::Raw(command))?;
+        while !matcher(&response) {
+            response = self.wait_for_response()?;
+        }
+        Ok(response)
+    }
+
+    /// Write a command the driver doesn't wrap, like `send_raw`, without
+    /// waiting for its responses, which are then taken using `poll_response`.
+    ///
+    /// Unlike `send_raw`, the command isn't retried while the board is busy.
+    pub fn write_raw(&mut self, command: &str) -> Result<(), AdapterError> {
+        Self::check_raw(command)?;
+        self.write_command(&Command::Raw(command))
+    }
+
+    fn check_raw(command: &str) -> Result<(), AdapterError> {
+        if command.len() > MAX_COMMAND_LEN || command.contains(['\r', '\n']) {
+            return Err(AdapterError::InvalidParameter);
+        }
+        Ok(())
+    }
+
+    /// Enable the SNTP client, synchronizing against up to three `servers`.
+    ///
+    /// The `timezone` is an offset in hours from UTC, between -11 and 13.
+    /// When no servers are given, the firmware's defaults are used.
+    pub fn configure_sntp(&mut self, timezone: i8, servers: &[&str]) -> Result<(), AdapterError> {
+        if !(-11..=13).contains(&timezone) || servers.len() > 3 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::ConfigureSntp { timezone, servers };
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Disable the SNTP client.
+    pub fn disable_sntp(&mut self) -> Result<(), AdapterError> {
+        let command = Command::DisableSntp;
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Retrieve the current time from the SNTP client.
+    ///
+    /// Until the first synchronization has completed, the board reports
+    /// a time in 1970.
+    pub fn sntp_time(&mut self) -> Result<DateTime, AdapterError> {
+        let command = Command::QuerySntpTime;
+
+        match self.send(command) {
+            Ok(Response::SntpTime(time)) => Ok(time),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Start announcing the board using mDNS.
+    ///
+    /// Only available while the board has joined an access point. The
+    /// hostname and service may not contain quotes or commas.
+    pub fn enable_mdns(&mut self, config: MdnsConfig) -> Result<(), AdapterError> {
+        let invalid = |name: &str| name.is_empty() || name.contains(&['"', ','][..]);
+        if invalid(config.hostname) || invalid(config.service) {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::EnableMdns(config);
+
+        match self.send(command)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Stop announcing the board using mDNS.
+    pub fn disable_mdns(&mut self) -> Result<(), AdapterError> {
+        match self.send(Command::DisableMdns)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Ping a host by name or IP address, returning the round-trip time in milliseconds.
+    ///
+    /// Returns `AdapterError::Timeout` if the host did not reply.
+    pub fn ping(&mut self, host: &str) -> Result<u32, AdapterError> {
+        let command = Command::Ping { host };
+
+        match self.send(command)? {
+            Response::Ping(time) => Ok(time),
+            Response::PingTimeout => Err(AdapterError::Timeout),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Issue a request using the board's HTTP client, passing each chunk of
+    /// the response body to `on_data` as it arrives.
+    ///
+    /// The URL, which must not contain quotes, and the body, escaped as
+    /// needed, must fit into a single command of up to 256 bytes.
+    pub fn http_request<F>(&mut self, request: HttpRequest, mut on_data: F) -> Result<(), AdapterError>
+        where
+            F: FnMut(&[u8]),
+    {
+        let body_len = request.body.map_or(0, str::len);
+        if request.url.contains('"') || request.url.len() + body_len > MAX_COMMAND_LEN {
+            return Err(AdapterError::InvalidParameter);
+        }
+        self.write_command(&Command::HttpRequest(request))?;
+        loop {
+            match self.wait_for_response()? {
+                Response::HttpData(data) => on_data(&data),
+                Response::Ok => return Ok(()),
+                other => return Err(self.unexpected(&other)),
+            }
+        }
+    }
+
+    pub fn query_dns_resolvers(&mut self) -> Result<ResolverAddresses, AdapterError> {
+        let command = Command::QueryDnsResolvers;
+        match self.send(command) {
+            Ok(Response::Resolvers(resolvers)) => Ok(resolvers),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    pub fn set_dns_resolvers(&mut self, resolver1: Ipv4Addr, resolver2: Option<Ipv4Addr>) -> Result<(), AdapterError> {
+        let command = Command::SetDnsResolvers(
+            ResolverAddresses {
+                resolver1,
+                resolver2
+            }
+        );
+
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Consume the adapter and produce a `NetworkStack`.
+    pub fn into_network_stack(self) -> Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L> {
+        Esp8266IpNetworkDriver::new(self)
+    }
+
+    // ----------------------------------------------------------------------
+    // TCP Stack
+    // ----------------------------------------------------------------------
+
+    pub(crate) fn process_notifications(&mut self) {
+        while let Some(overflow) = ingress::take_overflow() {
+            warn!("ingress overflow: {:?}", overflow);
+            if let Overflow::Notifications | Overflow::Receive = overflow {
+                // data may have been announced in the notifications lost, or
+                // fetched from the board without being read
+                self.available_stale = true;
+            }
+            self.stats.overflows = self.stats.overflows.wrapping_add(1);
+            self.event_occurred(Event::Overflow(overflow));
+        }
+        while let Some(response) = self.notification_consumer.dequeue() {
+            self.handle_notification(response);
+        }
+    }
+
+    fn handle_notification(&mut self, response: Response) {
+        let link_id = match response {
+            Response::DataAvailable { link_id, .. }
+            | Response::DatagramReceived { link_id, .. }
+            | Response::IncomingConnection(link_id)
+            | Response::Closed(link_id) => Some(link_id),
+            _ => None,
+        };
+        if let Some(link_id) = link_id.filter(|link_id| *link_id >= L) {
+            warn!("ignoring notification for untracked link {}", link_id);
+            return;
+        }
+        match response {
+            Response::DataAvailable { link_id, len, remote } => {
+                let socket = &mut self.sockets[link_id];
+                socket.available = socket.available.saturating_add(len);
+                if remote.is_some() {
+                    socket.peer = remote;
+                }
+            }
+            Response::DatagramReceived { link_id, data, remote } => {
+                if remote.is_some() {
+                    self.sockets[link_id].peer = remote;
+                }
+                let datagram = Datagram { link_id, data, read: 0, remote };
+                if self.datagrams.push(datagram).is_err() {
+                    warn!("dropping datagram for link {}", link_id);
+                }
+            }
+            Response::Connect(_) => {}
+            Response::IncomingConnection(link_id) => {
+                self.sockets[link_id].state = SocketState::Connected;
+                self.sockets[link_id].available = 0;
+                self.sockets[link_id].incoming = true;
+                self.sockets[link_id].peer = None;
+            }
+            Response::Closed(link_id) => {
+                self.event_occurred(Event::LinkClosed(link_id));
+                let stats = &mut self.link_stats[link_id];
+                stats.closes = stats.closes.wrapping_add(1);
+                match self.sockets[link_id].state {
+                    SocketState::HalfClosed | SocketState::Closing => {
+                        self.sockets[link_id].state = SocketState::Closed;
+                    }
+                    SocketState::Open | SocketState::Connected => {
+                        self.sockets[link_id].state = SocketState::HalfClosed;
+                    }
+                    SocketState::Closed | SocketState::Connecting => {
+                        // nothing, a failed connect is reported by its response
+                    }
+                }
+            }
+            Response::WifiConnected => {
+                self.event_occurred(Event::WifiConnected);
+                self.wifi_state = WifiState::Connected;
+            }
+            Response::GotIp => {
+                self.event_occurred(Event::GotIp);
+                self.wifi_state = WifiState::GotIp;
+            }
+            Response::WifiDisconnect => {
+                self.event_occurred(Event::WifiDisconnected);
+                if self.wifi_state != WifiState::Disconnected {
+                    let now = self.now_ms();
+                    self.reconnect.disconnected(now);
+                }
+                self.wifi_state = WifiState::Disconnected;
+            }
+            Response::ModuleRestarted => {
+                warn!("adapter restarted");
+                self.event_occurred(Event::ModuleRestarted);
+                self.restarted = true;
+            }
+            Response::WpsSuccess => {
+                self.wps_state = WpsState::Paired;
+            }
+            Response::WpsFailure(reason) => {
+                self.wps_state = WpsState::Failed(reason);
+            }
+            Response::SmartConfig(event) => {
+                let result = self.smart_config_events.enqueue(event);
+                if result.is_err() {
+                    warn!("dropping smart config event");
+                }
+            }
+            Response::Custom(custom) => {
+                if let Err(custom) = self.custom_notifications.enqueue(custom) {
+                    warn!("dropping custom notification {}", custom.kind);
+                }
+            }
+            #[cfg(feature = "mqtt")]
+            Response::Mqtt(response) => {
+                self.mqtt.handle_notification(response);
+            }
+            _ => { /* ignore */ }
+        }
+    }
+
+    fn event_occurred(&mut self, event: Event) {
+        let result = self.events.enqueue(event);
+        if result.is_err() {
+            warn!("dropping event {:?}", event);
+        }
+    }
+
+    pub(crate) fn has_open_sockets(&self) -> bool {
+        self.sockets.iter().any(|socket| !socket.is_closed())
+    }
+
+    /// Mark all sockets in use as no longer delivering data, while keeping
+    /// them allocated until closed by their owner.
+    fn invalidate_sockets(&mut self) {
+        for socket in self.sockets.iter_mut() {
+            if !socket.is_closed() {
+                socket.state = SocketState::HalfClosed;
+            }
+            socket.available = 0;
+            socket.incoming = false;
+        }
+        self.datagrams.clear();
+    }
+
+    /// Reconcile the sockets with the links the board reports connected
+    /// using `AT+CIPSTATUS`, such as after notifications were lost.
+    ///
+    /// Sockets whose link the board no longer reports are handled as if
+    /// reported closed, and links accepted by the board's server which were
+    /// missed are taken up, to be returned by `accept`.
+    pub fn sync_socket_states(&mut self) -> Result<(), AdapterError> {
+        let mut connected = [false; L];
+        let mut accepted = [false; L];
+        let mut response = self.send(Command::QueryConnectionStatus)?;
+        loop {
+            match response {
+                Response::ConnectionStatus(_) => {}
+                Response::LinkStatus(status) if status.link_id < L => {
+                    connected[status.link_id] = true;
+                    accepted[status.link_id] = status.server;
+                }
+                Response::LinkStatus(status) => {
+                    warn!("ignoring status of untracked link {}", status.link_id);
+                }
+                Response::Ok => break,
+                other => return Err(self.unexpected(&other)),
+            }
+            response = self.wait_for_response()?;
+        }
+        // apply whatever was reported while querying first
+        self.process_notifications();
+
+        for link_id in 0..L {
+            match self.sockets[link_id].state {
+                SocketState::Connected | SocketState::Closing if !connected[link_id] => {
+                    warn!("link {} closed without notice", link_id);
+                    self.handle_notification(Response::Closed(link_id));
+                }
+                SocketState::Closed if accepted[link_id] => {
+                    warn!("link {} accepted without notice", link_id);
+                    self.handle_notification(Response::IncomingConnection(link_id));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn open(&mut self) -> Result<usize, AdapterError> {
+        if let Some((index, socket)) = self
+            .sockets
+            .iter_mut()
+            .take(self.capabilities.max_links)
+            .enumerate()
+            .find(|(_, e)| e.is_closed())
+        {
+            socket.state = SocketState::Open;
+            socket.keepalive = None;
+            socket.peer = None;
+            socket.tls = None;
+            socket.write_shutdown = false;
+            return Ok(index);
+        }
+
+        Err(AdapterError::NoAvailableSockets)
+    }
+
+    /// Start listening for inbound TCP connections.
+    ///
+    /// The board assigns link ids to accepted connections itself, so a socket
+    /// which is opened but not yet connected may be claimed by an inbound
+    /// connection.
+    pub(crate) fn start_server(&mut self, port: u16) -> Result<(), AdapterError> {
+        let command = Command::StartServer { port };
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    pub(crate) fn stop_server(&mut self) -> Result<(), AdapterError> {
+        let command = Command::StopServer;
+        match self.send(command) {
+            Ok(Response::Ok) => Ok(()),
+            other => Err(self.failed(other)),
+        }
+    }
+
+    /// Set the time after which the board closes idle inbound connections,
+    /// from 0 (never) to 7200 seconds.
+    pub(crate) fn set_server_timeout(&mut self, seconds: u16) -> Result<(), AdapterError> {
+        if seconds > 7200 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::SetServerTimeout { seconds };
+        match self.send(command)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    pub(crate) fn accept(&mut self) -> nb::Result<usize, AdapterError> {
+        self.process_notifications();
+
+        if let Some((index, socket)) = self
+            .sockets
+            .iter_mut()
+            .enumerate()
+            .find(|(_, e)| e.incoming)
+        {
+            socket.incoming = false;
+            return Ok(index);
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Close a link, only releasing it once the board reported it closed, so
+    /// that its `CLOSED` notification can't be mistaken for one of the link's
+    /// next connection.
+    pub(crate) fn close(&mut self, link_id: usize) -> Result<(), AdapterError> {
+        self.process_notifications();
+        let connected = matches!(
+            self.sockets[link_id].state,
+            SocketState::Connecting | SocketState::Connected
+        );
+        let command = Command::CloseConnection(link_id);
+        match self.send(command) {
+            Ok(Response::Ok) if connected => {
+                self.sockets[link_id].state = SocketState::Closing;
+                if self.wait_for_closed(link_id).is_err() {
+                    warn!("link {} not reported closed", link_id);
+                }
+            }
+            Ok(Response::Ok) | Ok(Response::UnlinkFail) => {}
+            // the board doesn't know the link, so there is nothing left to close
+            Ok(Response::LinkInvalid) => {
+                if matches!(self.sockets[link_id].state, SocketState::Connected) {
+                    self.handle_notification(Response::Closed(link_id));
+                }
+            }
+            Ok(response) => return Err(self.link_error(link_id, &response, self.unexpected(&response))),
+            Err(error) => return Err(self.failed(Err(error))),
+        }
+        self.sockets[link_id].state = SocketState::Closed;
+        self.sockets[link_id].incoming = false;
+        self.sockets[link_id].write_shutdown = false;
+        while let Some(index) = self.datagrams.iter().position(|d| d.link_id == link_id) {
+            self.datagrams.swap_remove(index);
+        }
+        Ok(())
+    }
+
+    /// Stop writing to a link, while still reading the data it receives.
+    ///
+    /// The board cannot half-close a TCP connection, so the peer is not
+    /// notified; the connection is only closed by `close`.
+    pub(crate) fn shutdown(&mut self, link_id: usize) -> Result<(), AdapterError> {
+        let socket = &mut self.sockets[link_id];
+        if socket.is_closed() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        socket.write_shutdown = true;
+        Ok(())
+    }
+
+    fn wait_for_closed(&mut self, link_id: usize) -> Result<(), AdapterError> {
+        let start = self.now_ms();
+        loop {
+            self.process_notifications();
+            if self.sockets[link_id].is_closed() {
+                return Ok(());
+            }
+            self.check_timeout(start)?;
+        }
+    }
+
+    /// Set the interval, in seconds, at which TCP keepalive probes are sent once
+    /// the socket is connected. Must be set before connecting.
+    pub(crate) fn set_keepalive(
+        &mut self,
+        link_id: usize,
+        keepalive: Option<u16>,
+    ) -> Result<(), AdapterError> {
+        if let Some(0) | Some(7201..=u16::MAX) = keepalive {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let socket = &mut self.sockets[link_id];
+        if !socket.is_open() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        socket.keepalive = keepalive;
+        Ok(())
+    }
+
+    pub(crate) fn connect_tcp(
+        &mut self,
+        link_id: usize,
+        remote: RemoteEndpoint,
+    ) -> Result<(), AdapterError> {
+        self.start_connection(link_id, ConnectionType::TCP, remote, None)
+    }
+
+    /// Connect a link using TCP without blocking during the handshake.
+    ///
+    /// The first call sends the command, and further calls poll for its
+    /// outcome; they must pass the same remote until this completes. No other
+    /// commands may be sent in the meantime.
+    pub(crate) fn connect_tcp_nb(
+        &mut self,
+        link_id: usize,
+        remote: RemoteEndpoint,
+    ) -> nb::Result<(), AdapterError> {
+        match self.sockets[link_id].state {
+            SocketState::Open => {
+                if let RemoteEndpoint::Address(SocketAddr::V6(_)) = remote {
+                    return Err(nb::Error::Other(AdapterError::InvalidParameter));
+                }
+                let command = Command::StartConnection {
+                    link_id: Some(link_id),
+                    connection_type: ConnectionType::TCP,
+                    remote,
+                    keepalive: self.sockets[link_id].keepalive,
+                    udp: None,
+                };
+                self.write_command(&command)?;
+                self.sockets[link_id].state = SocketState::Connecting;
+                self.connect_start = self.now_ms();
+                return Err(nb::Error::WouldBlock);
+            }
+            SocketState::Connecting => {}
+            SocketState::Connected => return Ok(()),
+            _ => return Err(nb::Error::Other(AdapterError::InvalidSocket)),
+        }
 
-        match self.send(command) {
-            Ok(Response::Ok) => {
+        let result = match self.take_response() {
+            Some(Response::Connect(..)) => {
+                self.sockets[link_id].state = SocketState::Connected;
+                if let RemoteEndpoint::Address(addr) = remote {
+                    self.sockets[link_id].peer = Some(addr);
+                }
                 Ok(())
             }
-            Ok(Response::WifiConnectionFailure(reason)) => {
-                Err(reason)
-            }
-            _ => {
-                Err(WifiConnectionFailure::ConnectionFailed)
+            Some(response) => Err(self.link_error(link_id, &response, AdapterError::UnableToOpen)),
+            None => {
+                self.timeout = self.timeouts.network;
+                match self.check_timeout(self.connect_start) {
+                    Ok(()) => return Err(nb::Error::WouldBlock),
+                    Err(e) => Err(e),
+                }
             }
+        };
+        if result.is_err() {
+            self.sockets[link_id].state = SocketState::Open;
         }
+        self.connect_start = None;
+        result.map_err(nb::Error::Other)
     }
 
-    pub fn query_dns_resolvers(&mut self) -> Result<ResolverAddresses, ()> {
-        let command = Command::QueryDnsResolvers;
-        if let Ok(Response::Resolvers(resolvers)) = self.send(command) {
-            Ok(resolvers)
-        } else {
-            Err(())
+    pub(crate) fn connect_udp(
+        &mut self,
+        link_id: usize,
+        remote: RemoteEndpoint,
+        options: Option<UdpOptions>,
+    ) -> Result<(), AdapterError> {
+        self.start_connection(link_id, ConnectionType::UDP, remote, options)
+    }
+
+    /// Set the certificates used by a link when connecting using TLS.
+    ///
+    /// The settings are applied on every `connect_tls` of the socket.
+    pub(crate) fn set_tls_config(
+        &mut self,
+        link_id: usize,
+        config: TlsConfig,
+    ) -> Result<(), AdapterError> {
+        if !self.capabilities.tls_config {
+            return Err(AdapterError::Unsupported);
         }
+        let socket = &mut self.sockets[link_id];
+        if socket.is_closed() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        socket.tls = Some(config);
+        Ok(())
     }
 
-    pub fn set_dns_resolvers(&mut self, resolver1: Ipv4Addr, resolver2: Option<Ipv4Addr>) -> Result<(), ()> {
-        let command = Command::SetDnsResolvers(
-            ResolverAddresses {
-                resolver1,
-                resolver2
-            }
-        );
+    /// Set the server name sent using SNI, when the link connects using TLS.
+    pub(crate) fn set_tls_server_name(
+        &mut self,
+        link_id: usize,
+        name: &str,
+    ) -> Result<(), AdapterError> {
+        if self.sockets[link_id].is_closed() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        if !self.capabilities.tls_config {
+            return Err(AdapterError::Unsupported);
+        }
+        if name.is_empty() || name.len() > 64 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        match self.send(Command::SetTlsServerName { link_id, name })? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
 
-        if let Ok(Response::Ok) = self.send(command) {
-            Ok(())
-        } else {
-            Err(())
+    /// Set the pre-shared key and its identity hint, used by the link when it
+    /// connects using TLS-PSK.
+    pub(crate) fn set_tls_psk(
+        &mut self,
+        link_id: usize,
+        key: &[u8],
+        hint: &str,
+    ) -> Result<(), AdapterError> {
+        if self.sockets[link_id].is_closed() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        if !self.capabilities.tls_config {
+            return Err(AdapterError::Unsupported);
+        }
+        if key.is_empty() || key.len() > 32 || hint.len() > 32 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        match self.send(Command::SetTlsPsk { link_id, key, hint })? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
         }
     }
 
-    /// Consume the adapter and produce a `NetworkStack`.
-    pub fn into_network_stack(self) -> Esp8266IpNetworkDriver<'a, Tx> {
-        Esp8266IpNetworkDriver::new(self)
+    /// Set the protocols offered using ALPN, when the link connects using TLS.
+    pub(crate) fn set_tls_alpn(
+        &mut self,
+        link_id: usize,
+        protocols: &[&str],
+    ) -> Result<(), AdapterError> {
+        if self.sockets[link_id].is_closed() {
+            return Err(AdapterError::InvalidSocket);
+        }
+        if !self.capabilities.tls_config {
+            return Err(AdapterError::Unsupported);
+        }
+        if protocols.len() > 3 {
+            return Err(AdapterError::InvalidParameter);
+        }
+        match self.send(Command::SetTlsAlpn { link_id, protocols })? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
     }
 
-    // ----------------------------------------------------------------------
-    // TCP Stack
-    // ----------------------------------------------------------------------
+    /// Set the size of the buffer used for TLS, from 2048 to 4096 bytes.
+    pub(crate) fn set_tls_buffer_size(&mut self, size: u16) -> Result<(), AdapterError> {
+        if !(2048..=4096).contains(&size) {
+            return Err(AdapterError::InvalidParameter);
+        }
+        match self.send(Command::SetTlsBufferSize(size))? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
 
-    fn process_notifications(&mut self) {
-        while let Some(response) = self.notification_consumer.dequeue() {
-            match response {
-                Response::DataAvailable { link_id, len } => {
-                    self.sockets[link_id].available += len;
+    /// The TLS handshake is performed by the board, so the `CONNECT` response
+    /// takes considerably longer to arrive than for plain TCP.
+    pub(crate) fn connect_tls(
+        &mut self,
+        link_id: usize,
+        remote: RemoteEndpoint,
+    ) -> Result<(), AdapterError> {
+        if let Some(config) = self.sockets[link_id].tls {
+            match self.send(Command::ConfigureTls { link_id, config })? {
+                Response::Ok => {}
+                other => return Err(self.unexpected(&other)),
+            }
+        }
+        self.start_connection(link_id, ConnectionType::SSL, remote, None)
+    }
+
+    fn start_connection(
+        &mut self,
+        link_id: usize,
+        connection_type: ConnectionType,
+        remote: RemoteEndpoint,
+        udp: Option<UdpOptions>,
+    ) -> Result<(), AdapterError> {
+        if let RemoteEndpoint::Address(SocketAddr::V6(_)) = remote {
+            return Err(AdapterError::InvalidParameter);
+        }
+        let command = Command::StartConnection {
+            link_id: Some(link_id),
+            connection_type,
+            remote,
+            keepalive: self.sockets[link_id].keepalive,
+            udp,
+        };
+        match self.send(command) {
+            Ok(Response::Connect(..)) => {
+                self.sockets[link_id].state = SocketState::Connected;
+                if let RemoteEndpoint::Address(addr) = remote {
+                    self.sockets[link_id].peer = Some(addr);
                 }
-                Response::Connect(_) => {}
-                Response::Closed(link_id) => {
-                    match self.sockets[link_id].state {
-                        SocketState::HalfClosed => {
-                            self.sockets[link_id].state = SocketState::Closed;
-                        }
-                        SocketState::Open | SocketState::Connected => {
-                            self.sockets[link_id].state = SocketState::HalfClosed;
-                        }
-                        SocketState::Closed => {
-                            // nothing
-                        }
-                    }
+                Ok(())
+            }
+            Ok(response) => Err(self.link_error(link_id, &response, self.unexpected(&response))),
+            Err(error) => Err(self.failed(Err(error))),
+        }
+    }
+
+    /// The error of an operation on a link, which the board answered with
+    /// `response`, or `otherwise` if the board didn't tell why. A link the
+    /// board no longer knows is handled as if reported closed.
+    fn link_error(&mut self, link_id: usize, response: &Response, otherwise: AdapterError) -> AdapterError {
+        match SocketError::from_response(response) {
+            Some(SocketError::LinkInvalid) => {
+                if matches!(self.sockets[link_id].state, SocketState::Connected) {
+                    self.handle_notification(Response::Closed(link_id));
                 }
-                _ => { /* ignore */ }
+                AdapterError::Socket { link_id, error: SocketError::LinkInvalid }
             }
+            Some(error) => AdapterError::Socket { link_id, error },
+            None => otherwise,
         }
     }
 
-    pub(crate) fn open(&mut self) -> Result<usize, AdapterError> {
-        if let Some((index, socket)) = self
-            .sockets
-            .iter_mut()
-            .enumerate()
-            .find(|(_, e)| e.is_closed())
-        {
-            socket.state = SocketState::Open;
-            return Ok(index);
+    pub(crate) fn write(
+        &mut self,
+        link_id: usize,
+        buffer: &[u8],
+    ) -> nb::Result<usize, AdapterError> {
+        self.write_to(link_id, buffer, None)
+    }
+
+    /// Write to a link, sending a UDP datagram to `remote` rather than the
+    /// link's current peer.
+    ///
+    /// Data beyond what a single `AT+CIPSEND` accepts is sent in several
+    /// chunks, which splits UDP datagrams; datagrams sent to `remote` are
+    /// rejected instead. Returns the number of bytes written, which is less
+    /// than `buffer` if a later chunk failed.
+    pub(crate) fn write_to(
+        &mut self,
+        link_id: usize,
+        buffer: &[u8],
+        remote: Option<SocketAddr>,
+    ) -> nb::Result<usize, AdapterError> {
+        self.process_notifications();
+
+        if self.sockets[link_id].write_shutdown {
+            return Err(nb::Error::Other(AdapterError::InvalidSocket));
         }
 
-        Err(AdapterError::NoAvailableSockets)
+        if let Some(IpAddr::V6(_)) = remote.map(|remote| remote.ip()) {
+            return Err(nb::Error::Other(AdapterError::InvalidParameter));
+        }
+
+        if remote.is_some() && buffer.len() > MAX_SEND_LEN {
+            return Err(nb::Error::Other(AdapterError::InvalidParameter));
+        }
+
+        let mut written = 0;
+        for chunk in buffer.chunks(MAX_SEND_LEN) {
+            match self.send_chunk(link_id, &[chunk], remote) {
+                Ok(len) => written += len,
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
     }
 
-    pub(crate) fn close(&mut self, link_id: usize) -> Result<(), AdapterError> {
-        let command = Command::CloseConnection(link_id);
-        match self.send(command) {
-            Ok(Response::Ok) | Ok(Response::UnlinkFail) => {
-                self.sockets[link_id].state = SocketState::Closed;
-                Ok(())
-            },
-            _=> Err(AdapterError::UnableToClose),
+    /// Write all of `buffer` to a link, retrying until the board accepted it.
+    pub(crate) fn write_all(&mut self, link_id: usize, buffer: &[u8]) -> Result<(), AdapterError> {
+        let mut remaining = buffer;
+        while !remaining.is_empty() {
+            match nb::block!(self.write(link_id, remaining))? {
+                0 => return Err(AdapterError::WriteError),
+                len => remaining = &remaining[len..],
+            }
         }
+        Ok(())
     }
 
-    pub(crate) fn connect_tcp(
+    /// Write several buffers to a link, sending as many of them as fit
+    /// together with a single `AT+CIPSEND`.
+    pub(crate) fn write_iovec(
         &mut self,
         link_id: usize,
-        remote: HostSocketAddr,
-    ) -> Result<(), AdapterError> {
-        let command = Command::StartConnection(link_id, ConnectionType::TCP, remote.as_socket_addr());
-        if let Ok(Response::Connect(..)) = self.send(command) {
-            self.sockets[link_id].state = SocketState::Connected;
-            return Ok(());
+        buffers: &[&[u8]],
+    ) -> nb::Result<usize, AdapterError> {
+        self.process_notifications();
+
+        if self.sockets[link_id].write_shutdown {
+            return Err(nb::Error::Other(AdapterError::InvalidSocket));
         }
 
-        Err(AdapterError::UnableToOpen)
+        let mut written = 0;
+        let mut start = 0;
+        while start < buffers.len() {
+            let mut end = start;
+            let mut len = 0;
+            while end < buffers.len() && len + buffers[end].len() <= MAX_SEND_LEN {
+                len += buffers[end].len();
+                end += 1;
+            }
+            let result = if end == start {
+                // too large to coalesce with anything
+                end += 1;
+                self.write_to(link_id, buffers[start], None)
+            } else if len == 0 {
+                Ok(0)
+            } else {
+                self.send_chunk(link_id, &buffers[start..end], None)
+            };
+            match result {
+                Ok(len) => written += len,
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+            start = end;
+        }
+        Ok(written)
     }
 
-    pub(crate) fn write(
+    fn send_chunk(
         &mut self,
         link_id: usize,
-        buffer: &[u8],
+        buffers: &[&[u8]],
+        remote: Option<SocketAddr>,
     ) -> nb::Result<usize, AdapterError> {
-        self.process_notifications();
+        let mut retries = 0;
+        let result = loop {
+            match self.transmit(link_id, buffers, remote) {
+                Err(nb::Error::Other(AdapterError::Socket { error: SocketError::SendFailed, .. }))
+                    if retries < self.send_retries =>
+                {
+                    retries += 1;
+                    self.stats.retries = self.stats.retries.wrapping_add(1);
+                    self.back_off();
+                }
+                result => break result,
+            }
+        };
+        let stats = &mut self.link_stats[link_id];
+        match result {
+            Ok(len) => stats.bytes_sent = stats.bytes_sent.wrapping_add(len),
+            Err(_) => stats.send_failures = stats.send_failures.wrapping_add(1),
+        }
+        result
+    }
 
+    fn transmit(
+        &mut self,
+        link_id: usize,
+        buffers: &[&[u8]],
+        remote: Option<SocketAddr>,
+    ) -> nb::Result<usize, AdapterError> {
+        let total: usize = buffers.iter().map(|buffer| buffer.len()).sum();
         let command = Command::Send {
             link_id,
-            len: buffer.len(),
+            len: total,
+            remote,
         };
 
-        if let Ok(response) = self.send(command) {
-            if let Response::Ok = response {
-                if let Ok(response) = self.wait_for_response() {
-                    if let Response::ReadyForData = response {
-                        for b in buffer.iter() {
-                            nb::block!(self.tx.write(*b))
-                                .map_err(|_| nb::Error::from(AdapterError::WriteError))?;
-                        }
-                        let mut data_sent: Option<usize> = None;
-                        loop {
-                            match self.wait_for_response() {
-                                Ok(Response::ReceivedDataToSend(len)) => {
-                                    data_sent.replace(len);
-                                }
-                                Ok(Response::SendOk) => {
-                                    return Ok(data_sent.unwrap_or_default());
-                                }
-                                _ => {
-                                    break; // unknown response
-                                }
-                            }
-                        }
+        match self.send(command) {
+            Ok(Response::Ok) => {}
+            Ok(response) => {
+                return Err(nb::Error::Other(self.link_error(link_id, &response, AdapterError::WriteError)));
+            }
+            Err(_) => return Err(nb::Error::Other(AdapterError::WriteError)),
+        }
+        if let Ok(Response::ReadyForData) = self.wait_for_response() {
+            for buffer in buffers.iter() {
+                self.write_data(buffer)?;
+            }
+            let mut data_sent: Option<usize> = None;
+            loop {
+                match self.wait_for_response() {
+                    Ok(Response::ReceivedDataToSend(len)) => {
+                        data_sent.replace(len);
+                    }
+                    Ok(Response::SendOk) => {
+                        // newer firmware doesn't report the length
+                        return Ok(data_sent.unwrap_or(total));
+                    }
+                    Ok(Response::SendFail) => {
+                        return Err(nb::Error::Other(AdapterError::Socket { link_id, error: SocketError::SendFailed }));
+                    }
+                    _ => {
+                        break; // unknown response
                     }
                 }
             }
@@ -486,36 +2896,243 @@ impl<'a, Tx> Adapter<'a, Tx>
         if matches!( self.sockets[link_id].state, SocketState::Closed ) {
             return Err(nb::Error::Other(AdapterError::InvalidSocket));
         }
+        self.refresh_stale_available();
+
+        // without passive mode, the board pushes data along with +IPD;
+        // with it, data may have been fetched already by `peek`
+        let mut len = self.take_buffered(link_id, buffer).unwrap_or(0);
+
+        // each response holds at most BUFFER_LEN bytes, so fetch until the
+        // buffer is full or the board has no more data
+        while len < buffer.len() && self.sockets[link_id].available > 0 {
+            match self.receive(link_id, &mut buffer[len..]) {
+                Ok(received) => {
+                    len += received;
+                    if received == 0 {
+                        break;
+                    }
+                }
+                Err(e) if len == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
 
-        if self.sockets[link_id].available == 0 {
-            if matches!( self.sockets[link_id].state, SocketState::HalfClosed ) {
-                return Err(nb::Error::Other(AdapterError::InvalidSocket));
-            } else {
-                return Err(nb::Error::WouldBlock);
+        if len > 0 {
+            self.received(link_id, len);
+            Ok(len)
+        } else if matches!( self.sockets[link_id].state, SocketState::HalfClosed ) {
+            Err(nb::Error::Other(AdapterError::InvalidSocket))
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Copy data received by a link without consuming it, so that the next
+    /// `read` returns it again.
+    ///
+    /// In passive mode, the data is fetched from the board and buffered by
+    /// the adapter, which holds data for up to two links at once, in slots
+    /// of the payload pool.
+    pub(crate) fn peek(
+        &mut self,
+        link_id: usize,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, AdapterError> {
+        self.process_notifications();
+
+        if matches!( self.sockets[link_id].state, SocketState::Closed ) {
+            return Err(nb::Error::Other(AdapterError::InvalidSocket));
+        }
+
+        if !self.datagrams.iter().any(|d| d.link_id == link_id) {
+            if self.sockets[link_id].available == 0 {
+                if matches!( self.sockets[link_id].state, SocketState::HalfClosed ) {
+                    return Err(nb::Error::Other(AdapterError::InvalidSocket));
+                } else {
+                    return Err(nb::Error::WouldBlock);
+                }
+            }
+            if self.datagrams.len() == self.datagrams.capacity() {
+                return Err(nb::Error::Other(AdapterError::ReadError));
             }
+            let data = Payload::fill(|buffer| self.receive(link_id, buffer))
+                .ok_or(nb::Error::Other(AdapterError::ReadError))??;
+            let datagram = Datagram {
+                link_id,
+                data,
+                read: 0,
+                remote: None,
+            };
+            self.datagrams.push(datagram).ok();
         }
 
+        let datagram = self
+            .datagrams
+            .iter()
+            .find(|d| d.link_id == link_id)
+            .ok_or(nb::Error::Other(AdapterError::ReadError))?;
+        let data = datagram.unread();
+        let len = core::cmp::min(data.len(), buffer.len());
+        buffer[0..len].copy_from_slice(&data[0..len]);
+        Ok(len)
+    }
+
+    /// Fetch data the board buffered for a link, which the ingress copies
+    /// straight into `buffer`.
+    fn receive(
+        &mut self,
+        link_id: usize,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, AdapterError> {
         let mut actual_len = buffer.len();
         if actual_len > crate::BUFFER_LEN {
             actual_len = crate::BUFFER_LEN;
         }
+        if actual_len > self.profile.max_receive_len {
+            actual_len = self.profile.max_receive_len;
+        }
 
         let command = Command::Receive {
             link_id,
             len: actual_len,
         };
 
-        match self.send(command) {
-            Ok(Response::DataReceived(inbound, len)) => {
-                for (i, b) in inbound[0..len].iter().enumerate() {
-                    buffer[i] = *b;
-                }
-                self.sockets[link_id].available -= len;
+        let registration = self.shared().register(&mut buffer[0..actual_len]);
+        let response = self.send(command);
+        drop(registration);
+
+        match response {
+            Ok(Response::DataReceived(len)) => {
+                let socket = &mut self.sockets[link_id];
+                socket.available = socket.available.saturating_sub(len);
                 Ok(len)
             }
             Ok(Response::Ok) => Err(nb::Error::WouldBlock),
-            _=> Err(nb::Error::Other(AdapterError::ReadError)),
+            Ok(response) => Err(nb::Error::Other(self.link_error(link_id, &response, AdapterError::ReadError))),
+            Err(_) => Err(nb::Error::Other(AdapterError::ReadError)),
+        }
+    }
+
+    pub(crate) fn read_datagram(
+        &mut self,
+        link_id: usize,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, AdapterError> {
+        self.read_datagram_from(link_id, buffer).map(|(len, _)| len)
+    }
+
+    /// Read a datagram, along with the address of its sender.
+    ///
+    /// The sender is only known once enabled using `set_remote_info`, and
+    /// for datagrams delivered directly by the board.
+    pub(crate) fn read_datagram_from(
+        &mut self,
+        link_id: usize,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, Option<SocketAddr>), AdapterError> {
+        self.process_notifications();
+
+        if let Some(received) = self.take_datagram(link_id, buffer) {
+            self.received(link_id, received.0);
+            return Ok(received);
+        }
+
+        // firmware which supports passive mode for UDP links announces
+        // datagrams like TCP data, so fall back to fetching it.
+        self.read(link_id, buffer).map(|len| (len, None))
+    }
+
+    fn received(&mut self, link_id: usize, len: usize) {
+        let stats = &mut self.link_stats[link_id];
+        stats.bytes_received = stats.bytes_received.wrapping_add(len);
+    }
+
+    /// Counters of a link, or `None` if `link_id` isn't tracked.
+    pub fn link_stats(&self, link_id: usize) -> Option<LinkStats> {
+        self.link_stats.get(link_id).copied()
+    }
+
+    /// Copy the oldest data pushed by the board for a link, discarding
+    /// whatever does not fit into `buffer`.
+    fn take_datagram(
+        &mut self,
+        link_id: usize,
+        buffer: &mut [u8],
+    ) -> Option<(usize, Option<SocketAddr>)> {
+        let index = self.datagrams.iter().position(|d| d.link_id == link_id)?;
+        let datagram = self.datagrams.swap_remove(index);
+        let data = datagram.unread();
+        let len = core::cmp::min(data.len(), buffer.len());
+        buffer[0..len].copy_from_slice(&data[0..len]);
+        Some((len, datagram.remote))
+    }
+
+    /// Copy the oldest data buffered for a link, keeping whatever does not
+    /// fit into `buffer` for the next read.
+    fn take_buffered(&mut self, link_id: usize, buffer: &mut [u8]) -> Option<usize> {
+        let index = self.datagrams.iter().position(|d| d.link_id == link_id)?;
+        let datagram = &mut self.datagrams[index];
+        let data = datagram.unread();
+        let len = core::cmp::min(data.len(), buffer.len());
+        buffer[0..len].copy_from_slice(&data[0..len]);
+        if len < data.len() {
+            datagram.read += len;
+        } else {
+            self.datagrams.swap_remove(index);
+        }
+        Some(len)
+    }
+
+    /// The remote address of a link, as far as known.
+    ///
+    /// For inbound connections and UDP peers, the address is only known once
+    /// data was received, with remote info enabled.
+    pub(crate) fn peer_addr(&self, link_id: usize) -> Option<SocketAddr> {
+        self.sockets[link_id].peer
+    }
+
+    /// Query the bytes the board buffered for each link using
+    /// `AT+CIPRECVLEN?`, replacing the counts tracked from `+IPD`
+    /// notifications, which drift once notifications were lost.
+    ///
+    /// Done by reads once notifications were lost, see `Event::Overflow`.
+    /// Requires passive mode, see `Capabilities::passive_receive`.
+    pub fn refresh_available(&mut self) -> Result<(), AdapterError> {
+        if !self.capabilities.passive_receive {
+            return Err(AdapterError::Unsupported);
         }
+        let lengths = match self.send(Command::QueryReceiveLengths)? {
+            Response::ReceiveLengths(lengths) => lengths,
+            other => return Err(self.unexpected(&other)),
+        };
+        // notifications parsed before the response are accounted for by it
+        self.process_notifications();
+        for (socket, len) in self.sockets.iter_mut().zip(lengths.iter()) {
+            socket.available = *len as usize;
+        }
+        self.available_stale = false;
+        Ok(())
+    }
+
+    fn refresh_stale_available(&mut self) {
+        if self.available_stale && self.capabilities.passive_receive {
+            if let Err(e) = self.refresh_available() {
+                warn!("failed to refresh available data: {:?}", e);
+            }
+        }
+    }
+
+    /// The number of bytes which can be read from a link without blocking.
+    pub(crate) fn available(&mut self, link_id: usize) -> usize {
+        self.process_notifications();
+        self.refresh_stale_available();
+        let pushed: usize = self
+            .datagrams
+            .iter()
+            .filter(|datagram| datagram.link_id == link_id)
+            .map(|datagram| datagram.unread().len())
+            .sum();
+        self.sockets[link_id].available.saturating_add(pushed)
     }
 
     pub(crate) fn is_connected(&self, link_id: usize) -> Result<bool, AdapterError> {
@@ -526,7 +3143,7 @@ impl<'a, Tx> Adapter<'a, Tx>
             SocketState::Closed => {
                 false
             }
-            SocketState::Open => {
+            SocketState::Open | SocketState::Connecting | SocketState::Closing => {
                 false
             }
             SocketState::Connected => {
@@ -539,17 +3156,127 @@ impl<'a, Tx> Adapter<'a, Tx>
     // DNS
     // ----------------------------------------------------------------------
 
-    pub(crate) fn get_host_by_name(&mut self, hostname: &str) -> Result<HostAddr, DnsError> {
-        let command = Command::GetHostByName {
+    /// Resolve a hostname to an IP address, using the board's configured resolvers.
+    pub fn resolve(&mut self, hostname: &str) -> Result<IpAddr, DnsError> {
+        let command = Command::ResolveDomain {
             hostname
         };
 
         if let Ok(IpAddress(ip_addr)) = self.send(command) {
-            Ok(
-                HostAddr::new(ip_addr, Some(String::from(hostname)))
-            )
+            Ok(ip_addr)
         } else {
             Err(DnsError::NoSuchHost)
         }
     }
+
+    pub(crate) fn get_host_by_name(&mut self, hostname: &str) -> Result<HostAddr, DnsError> {
+        let ip_addr = self.resolve(hostname)?;
+        Ok(
+            HostAddr::new(ip_addr, Some(heapless05::String::from(hostname)))
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Serial port recording the bytes written to it, and never receiving any.
+    #[derive(Default)]
+    struct Serial {
+        written: Vec<u8, 128>,
+    }
+
+    impl Write<u8> for Serial {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            self.written.push(word).map_err(|_| nb::Error::Other(()))
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    impl Read<u8> for Serial {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn test_reset_pairs_responses() {
+        let mut responses: Queue<Response, 8> = Queue::new();
+        let mut notifications: Queue<Response, 4> = Queue::new();
+        // AT+RST, then ATE0, AT+CIPMUX=1 and AT+CIPRECVMODE=1 to reinitialize
+        for _ in 0..4 {
+            responses.enqueue(Response::Ok).ok();
+        }
+        responses.enqueue(Response::Ping(12)).ok();
+        notifications.enqueue(Response::ModuleRestarted).ok();
+
+        let profile = ModuleProfile::default();
+        let capabilities = Capabilities::detect(&profile, Dialect::Nonos, None, MAX_LINKS);
+        let (mut adapter, _ingress): Initialized<'_, Serial, Serial, INGRESS_LEN, 8, 4, MAX_LINKS> =
+            build_adapter_and_ingress(
+                Serial::default(),
+                Serial::default(),
+                &mut responses,
+                &mut notifications,
+                profile,
+                capabilities,
+            )
+            .unwrap();
+
+        assert!(adapter.reset().is_ok());
+        assert_eq!(adapter.ping("host").ok(), Some(12));
+        assert_eq!(
+            adapter.tx.written.as_slice(),
+            &b"AT+RST\r\nATE0\r\nAT+CIPMUX=1\r\nAT+CIPRECVMODE=1\r\nAT+PING=\"host\"\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_transparent_read() {
+        let mut responses: Queue<Response, 8> = Queue::new();
+        let mut notifications: Queue<Response, 4> = Queue::new();
+        // AT+CIPMUX=0, AT+CIPMODE=1, AT+CIPSTART, then AT+CIPSEND and its prompt
+        responses.enqueue(Response::Ok).ok();
+        responses.enqueue(Response::Ok).ok();
+        responses.enqueue(Response::Connect(0)).ok();
+        responses.enqueue(Response::Ok).ok();
+        responses.enqueue(Response::ReadyForData).ok();
+
+        let profile = ModuleProfile::default();
+        let capabilities = Capabilities::detect(&profile, Dialect::Nonos, None, MAX_LINKS);
+        let (mut adapter, mut ingress): Initialized<'_, Serial, Serial, INGRESS_LEN, 8, 4, MAX_LINKS> =
+            build_adapter_and_ingress(
+                Serial::default(),
+                Serial::default(),
+                &mut responses,
+                &mut notifications,
+                profile,
+                capabilities,
+            )
+            .unwrap();
+
+        let shared = adapter.shared();
+        let mut socket = adapter
+            .open_transparent(ConnectionType::TCP, RemoteEndpoint::Host("example.com", 80))
+            .unwrap();
+        // as the ingress does once it parsed the prompt
+        shared.set_passthrough(ingress::Passthrough::On);
+        ingress.write_slice(b"hello\r\nworld");
+        assert_eq!(ingress.digest().consumed, 12);
+
+        let mut buffer = [0; 8];
+        assert_eq!(socket.read(&mut buffer).ok(), Some(8));
+        assert_eq!(&buffer, b"hello\r\nw");
+        assert_eq!(socket.read(&mut buffer).ok(), Some(4));
+        assert_eq!(&buffer[0..4], b"orld");
+        assert!(matches!(socket.read(&mut buffer), Err(nb::Error::WouldBlock)));
+    }
 }
@@ -0,0 +1,316 @@
+//! Futures based API, for applications using an async executor such as Embassy.
+//!
+//! The futures are woken by `Ingress::digest`, which therefore has to be
+//! called from the serial interrupt rather than from a polling loop. Commands
+//! still block while the board responds, which is brief; waiting for a join,
+//! a connection or received data doesn't.
+//!
+//! Timeouts are only checked when the futures are woken, so waiting should be
+//! bounded using the executor's timers as well.
+
+use crate::adapter::{Adapter, AdapterError, MAX_LINKS, NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use crate::ingress::{register_waker, wake_all, WAKERS_LEN};
+use crate::protocol::{RemoteEndpoint, WifiConnectionFailure};
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::task::{Context, Poll};
+use drogue_network::addr::HostSocketAddr;
+use embedded_hal::serial::Write;
+
+// Waker slot of operations not bound to a link.
+const ADAPTER_SLOT: usize = WAKERS_LEN - 1;
+
+/// Adapter whose operations return futures, usable from several tasks at once.
+pub struct AsyncAdapter<'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    adapter: RefCell<Adapter<'a, Tx, RQ, NQ, L>>,
+    // set while a join or connect awaits the board's response, during which
+    // no other command may be sent
+    locked: Cell<bool>,
+    // links of connections dropped while locked, closed once unlocked
+    dropped: Cell<u32>,
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> AsyncAdapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    pub fn new(adapter: Adapter<'a, Tx, RQ, NQ, L>) -> Self {
+        Self {
+            adapter: RefCell::new(adapter),
+            locked: Cell::new(false),
+            dropped: Cell::new(0),
+        }
+    }
+
+    pub fn into_inner(self) -> Adapter<'a, Tx, RQ, NQ, L> {
+        self.adapter.into_inner()
+    }
+
+    /// Use the blocking API, such as to configure the adapter, once no join
+    /// or connect is pending.
+    pub async fn with_adapter<R>(&self, f: impl FnOnce(&mut Adapter<'a, Tx, RQ, NQ, L>) -> R) -> R {
+        self.unlocked(ADAPTER_SLOT).await;
+        f(&mut self.adapter.borrow_mut())
+    }
+
+    /// Join a wifi access-point, like `Adapter::join`.
+    pub async fn join(&self, ssid: &str, password: &str) -> Result<(), WifiConnectionFailure> {
+        let _lock = self.lock().await;
+        let mut joining = Completion::new(&self.adapter, ADAPTER_SLOT, |adapter: &mut Adapter<'a, Tx, RQ, NQ, L>| {
+            adapter.join_nb(ssid, password)
+        });
+        poll_fn(|cx| joining.poll(cx)).await
+    }
+
+    /// Open a TCP connection, which is closed once dropped.
+    pub async fn connect(&self, remote: HostSocketAddr) -> Result<AsyncConnection<'_, 'a, Tx, RQ, NQ, L>, AdapterError> {
+        self.connect_endpoint(remote.into()).await
+    }
+
+    async fn connect_endpoint(&self, remote: RemoteEndpoint<'_>) -> Result<AsyncConnection<'_, 'a, Tx, RQ, NQ, L>, AdapterError> {
+        let _lock = self.lock().await;
+        let link_id = self.adapter.borrow_mut().open()?;
+        let connection = AsyncConnection {
+            adapter: self,
+            link_id,
+        };
+        let mut connecting = Completion::new(&self.adapter, link_id, |adapter: &mut Adapter<'a, Tx, RQ, NQ, L>| {
+            adapter.connect_tcp_nb(link_id, remote)
+        });
+        // on failure, the connection is dropped before the lock, so its link
+        // is closed once unlocked
+        poll_fn(|cx| connecting.poll(cx)).await?;
+        Ok(connection)
+    }
+
+    async fn lock(&self) -> Lock<'_, 'a, Tx, RQ, NQ, L> {
+        poll_fn(|cx| {
+            register_waker(ADAPTER_SLOT, cx.waker());
+            if self.locked.get() {
+                Poll::Pending
+            } else {
+                self.locked.set(true);
+                Poll::Ready(())
+            }
+        })
+        .await;
+        Lock { adapter: self }
+    }
+
+    async fn unlocked(&self, slot: usize) {
+        poll_fn(|cx| {
+            register_waker(slot, cx.waker());
+            if self.locked.get() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    /// Run a non-blocking operation until it completes, once no join or
+    /// connect is pending.
+    async fn poll_unlocked<T, E>(
+        &self,
+        slot: usize,
+        mut operation: impl FnMut(&mut Adapter<'a, Tx, RQ, NQ, L>) -> nb::Result<T, E>,
+    ) -> Result<T, E> {
+        poll_fn(|cx| {
+            register_waker(slot, cx.waker());
+            if self.locked.get() {
+                return Poll::Pending;
+            }
+            match operation(&mut self.adapter.borrow_mut()) {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(nb::Error::Other(e)) => Poll::Ready(Err(e)),
+                Err(nb::Error::WouldBlock) => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    fn release(&self, link_id: usize) {
+        if self.locked.get() {
+            self.dropped.set(self.dropped.get() | 1 << link_id);
+        } else if self.adapter.borrow_mut().close(link_id).is_err() {
+            warn!("failed to close link {}", link_id);
+        }
+    }
+}
+
+/// Exclusive use of the board's command channel.
+struct Lock<'r, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    adapter: &'r AsyncAdapter<'a, Tx, RQ, NQ, L>,
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Drop for Lock<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn drop(&mut self) {
+        self.adapter.locked.set(false);
+        let dropped = self.adapter.dropped.replace(0);
+        for link_id in (0..L).filter(|link_id| dropped & 1 << link_id != 0) {
+            self.adapter.release(link_id);
+        }
+        wake_all();
+    }
+}
+
+/// A non-blocking operation awaiting the board's response, which blocks to
+/// complete it if dropped early, so that the response isn't mistaken for that
+/// of the next command.
+struct Completion<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize, F, T, E>
+    where
+        Tx: Write<u8>,
+        F: FnMut(&mut Adapter<'a, Tx, RQ, NQ, L>) -> nb::Result<T, E>,
+{
+    adapter: &'r RefCell<Adapter<'a, Tx, RQ, NQ, L>>,
+    slot: usize,
+    operation: F,
+    done: bool,
+    _result: PhantomData<fn() -> (T, E)>,
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize, F, T, E> Completion<'r, 'a, Tx, RQ, NQ, L, F, T, E>
+    where
+        Tx: Write<u8>,
+        F: FnMut(&mut Adapter<'a, Tx, RQ, NQ, L>) -> nb::Result<T, E>,
+{
+    fn new(adapter: &'r RefCell<Adapter<'a, Tx, RQ, NQ, L>>, slot: usize, operation: F) -> Self {
+        Self {
+            adapter,
+            slot,
+            operation,
+            done: false,
+            _result: PhantomData,
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, E>> {
+        register_waker(self.slot, cx.waker());
+        let result = match (self.operation)(&mut self.adapter.borrow_mut()) {
+            Ok(value) => Ok(value),
+            Err(nb::Error::Other(e)) => Err(e),
+            Err(nb::Error::WouldBlock) => return Poll::Pending,
+        };
+        self.done = true;
+        Poll::Ready(result)
+    }
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize, F, T, E> Drop for Completion<'r, 'a, Tx, RQ, NQ, L, F, T, E>
+    where
+        Tx: Write<u8>,
+        F: FnMut(&mut Adapter<'a, Tx, RQ, NQ, L>) -> nb::Result<T, E>,
+{
+    fn drop(&mut self) {
+        if !self.done {
+            let mut adapter = self.adapter.borrow_mut();
+            nb::block!((self.operation)(&mut adapter)).ok();
+        }
+    }
+}
+
+/// TCP connection of an `AsyncAdapter`, closed once dropped.
+pub struct AsyncConnection<'r, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    adapter: &'r AsyncAdapter<'a, Tx, RQ, NQ, L>,
+    link_id: usize,
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> AsyncConnection<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// Close the connection, rather than dropping it, to learn whether
+    /// closing succeeded.
+    pub async fn close(self) -> Result<(), AdapterError> {
+        let adapter = self.adapter;
+        let link_id = self.link_id;
+        adapter.unlocked(link_id).await;
+        core::mem::forget(self);
+        let result = adapter.adapter.borrow_mut().close(link_id);
+        result
+    }
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Drop for AsyncConnection<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn drop(&mut self) {
+        self.adapter.release(self.link_id);
+    }
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io_async::ErrorType for AsyncConnection<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    type Error = AdapterError;
+}
+
+/// Returns zero once the peer closed the connection and all data was read.
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io_async::Read for AsyncConnection<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, AdapterError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let link_id = self.link_id;
+        let result = self
+            .adapter
+            .poll_unlocked(link_id, |adapter| adapter.read(link_id, buffer))
+            .await;
+        match result {
+            Err(AdapterError::InvalidSocket) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl<'r, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io_async::Write for AsyncConnection<'r, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    async fn write(&mut self, buffer: &[u8]) -> Result<usize, AdapterError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let link_id = self.link_id;
+        self.adapter
+            .poll_unlocked(link_id, |adapter| adapter.write(link_id, buffer))
+            .await
+    }
+
+    /// Data is sent as soon as it is written, so there is nothing to flush.
+    async fn flush(&mut self) -> Result<(), AdapterError> {
+        Ok(())
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_nal_async::TcpConnect for AsyncAdapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    type Error = AdapterError;
+    type Connection<'r> = AsyncConnection<'r, 'a, Tx, RQ, NQ, L> where Self: 'r;
+
+    async fn connect<'r>(&'r self, remote: core::net::SocketAddr) -> Result<AsyncConnection<'r, 'a, Tx, RQ, NQ, L>, AdapterError> {
+        let remote = crate::nal::from_nal(remote)?;
+        self.connect_endpoint(RemoteEndpoint::Address(remote)).await
+    }
+}
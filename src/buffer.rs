@@ -1,23 +1,62 @@
+use crate::ingress::{Shared, Unsolicited, UnsolicitedParser};
 use crate::parser;
-use crate::protocol::Response;
+use crate::payload::Payload;
+use crate::protocol::{Custom, Response};
 use moveslice::Moveslice;
 use core::str::from_utf8;
 
-pub(crate) struct Buffer {
-    buffer: [u8; 4096],
+// Room for the header and trailer of a `+CIPRECVDATA` response
+const RESPONSE_OVERHEAD: usize = 64;
+
+// Responses followed by received data, which may hold anything including
+// line ends
+const DATA_PREFIXES: [&[u8]; 4] = [b"+CIPRECVDATA", b"+IPD,", b"+HTTPCLIENT:", b"+MQTTSUBRECV:"];
+
+fn line_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|w| w == b"\r\n")
+}
+
+/// Whether the line starts a response followed by received data. In passive
+/// mode, `+IPD` only announces the length of the data, without a `:` and the
+/// data following it.
+fn starts_data(line: &[u8]) -> bool {
+    if line.starts_with(b"+IPD,") {
+        return match line_end(line) {
+            Some(end) => line[..end].contains(&b':'),
+            None => true,
+        };
+    }
+    DATA_PREFIXES.iter().any(|prefix| line.starts_with(prefix))
+}
+
+pub(crate) struct Buffer<const N: usize> {
+    buffer: [u8; N],
     pos: usize,
     needs_parse: bool,
 }
 
-impl Buffer {
+impl<const N: usize> Buffer<N> {
+    // Large enough to hold a complete `+CIPRECVDATA` response
+    const LARGE_ENOUGH: () = assert!(
+        N >= crate::BUFFER_LEN + RESPONSE_OVERHEAD,
+        "the ingress buffer can't hold a response of BUFFER_LEN bytes"
+    );
+
     pub fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::LARGE_ENOUGH;
         Buffer {
-            buffer: [0; 4096],
+            buffer: [0; N],
             pos: 0,
             needs_parse: false,
         }
     }
 
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.needs_parse = false;
+    }
+
     pub fn write(&mut self, octet: u8) -> Result<(), u8> {
         if self.pos >= self.buffer.len() {
             Err(octet)
@@ -29,79 +68,275 @@ impl Buffer {
         }
     }
 
-    pub fn parse(&mut self) -> Result<Response, ()> {
+    /// Append as much of `data` as fits, returning how much did.
+    pub fn write_slice(&mut self, data: &[u8]) -> usize {
+        let len = core::cmp::min(data.len(), self.buffer.len() - self.pos);
+        self.buffer[self.pos..self.pos + len].copy_from_slice(&data[..len]);
+        self.pos += len;
+        if len > 0 {
+            self.needs_parse = true;
+        }
+        len
+    }
+
+    /// Drop the oldest line to make room, or everything if no line is
+    /// complete, returning how many bytes were dropped.
+    pub fn drop_oldest(&mut self) -> usize {
+        let len = match line_end(&self.buffer[0..self.pos]) {
+            Some(end) => end + 2,
+            None => self.pos,
+        };
+        if self.pos > len {
+            self.buffer[..].moveslice(len..self.pos, 0);
+        }
+        self.pos -= len;
+        self.needs_parse = self.pos > 0;
+        len
+    }
+
+    /// The length of the first line, including its end, if it is complete but
+    /// doesn't start any response.
+    fn unknown_line(&self) -> Option<usize> {
+        let buffer = &self.buffer[0..self.pos];
+        // received data may hold line ends itself
+        if let Err(nom::Err::Incomplete(_)) = parser::data_received(buffer) {
+            return None;
+        }
+        // `ready` skips any output preceding it, so it can't tell on its own
+        if let Ok(_) | Err(nom::Err::Incomplete(_)) = parser::known(buffer) {
+            return None;
+        }
+        let end = line_end(buffer)?;
+        if buffer[..end].ends_with(b"ready") {
+            return None;
+        }
+        Some(end + 2)
+    }
+
+    /// Take out a notification which the firmware interleaved with the lines
+    /// of a response, returning it along with its length, so that the
+    /// response parses once complete.
+    fn interleaved(&mut self) -> Option<(Response, usize)> {
+        let mut start = 0;
+        loop {
+            let line = &self.buffer[start..self.pos];
+            if starts_data(line) {
+                return None;
+            }
+            // the first line belongs to the response, and blank lines may
+            // be part of its end
+            if start > 0 && !line.starts_with(b"\r\n") {
+                if let Ok((remainder, response)) = parser::unsolicited(line) {
+                    let len = line.len() - remainder.len();
+                    if start + len < self.pos {
+                        self.buffer[..].moveslice(start + len..self.pos, start);
+                    }
+                    self.pos -= len;
+                    self.needs_parse = true;
+                    return Some((response, len));
+                }
+            }
+            start += line_end(line)? + 2;
+        }
+    }
+
+    /// Parse the start of the buffer using the parser registered by the user,
+    /// returning the notification along with its length, if recognized.
+    fn custom(&mut self, parser: &dyn UnsolicitedParser) -> Option<(Response, usize)> {
+        match parser.parse(&self.buffer[0..self.pos]) {
+            Unsolicited::Parsed { len, kind, data } if len > 0 => {
+                let response = match Payload::new(data) {
+                    Some(data) => Response::Custom(Custom { kind, data }),
+                    None => Response::None,
+                };
+                let len = core::cmp::min(len, self.pos);
+                if self.pos > len {
+                    self.buffer[..].moveslice(len..self.pos, 0);
+                }
+                self.pos -= len;
+                self.needs_parse = self.pos > 0;
+                Some((response, len))
+            }
+            Unsolicited::Incomplete => Some((Response::None, 0)),
+            Unsolicited::Parsed { .. } | Unsolicited::Unknown => None,
+        }
+    }
+
+    /// Take up to `BUFFER_LEN` received bytes as data, rather than parsing
+    /// them, returning them along with their length. The bytes are dropped
+    /// if no payload slot is free.
+    pub fn take_data(&mut self) -> (Response, usize) {
+        let len = core::cmp::min(self.pos, crate::BUFFER_LEN);
+        if len == 0 {
+            return (Response::None, 0);
+        }
+        let response = match Payload::new(&self.buffer[0..len]) {
+            Some(data) => Response::TransparentData(data),
+            None => Response::None,
+        };
+        if self.pos > len {
+            self.buffer[..].moveslice(len..self.pos, 0);
+        }
+        self.pos -= len;
+        self.needs_parse = self.pos > 0;
+        (response, len)
+    }
+
+    /// Parse the next response, returning it along with the number of bytes
+    /// it took. A line which can't be parsed is dropped instead, returning its
+    /// length as error, so that the responses following it aren't held up.
+    ///
+    /// Data fetched using `AT+CIPRECVDATA` is copied into the buffer
+    /// registered with `shared`. Lines the driver doesn't know are handed to
+    /// `custom`, if given.
+    pub fn parse(&mut self, shared: &Shared, custom: Option<&dyn UnsolicitedParser>) -> Result<(Response, usize), usize> {
         if self.pos == 0 {
-            return Ok(Response::None);
+            return Ok((Response::None, 0));
         }
         if !self.needs_parse {
-            return Ok(Response::None);
+            return Ok((Response::None, 0));
         }
         self.needs_parse = false;
 
         let str = from_utf8(&self.buffer[0..self.pos]);
         match str {
             Ok(s) => {
-                log::debug!("parsing {} [{}]", self.pos, s);
+                debug!("parsing {} [{}]", self.pos, s);
             },
             Err(e) => {
                 let s = from_utf8(&self.buffer[0..e.valid_up_to()]).unwrap();
-                log::debug!("parsing {} [{}<truncated>] ({})", self.pos, s, e);
+                debug!("parsing {} [{}<truncated>]", self.pos, s);
             },
         }
 
-        let mut ret = Ok(Response::None);
+        let mut ret = Ok((Response::None, 0));
 
-        if let Ok((remainder, response)) = parser::parse(&self.buffer[0..self.pos]) {
-            let len = remainder.len();
-            if len > 0 {
-                let start = self.pos - len;
-                (&mut self.buffer[..]).moveslice(start..start + len, 0);
-                self.pos = len;
-                self.needs_parse = true;
-            } else {
-                self.pos = 0;
+        let parsed = match parser::data_received(&self.buffer[0..self.pos]) {
+            Ok((remainder, data)) => {
+                let len = shared.fill_receive_buffer(data);
+                Ok((remainder, Response::DataReceived(len)))
             }
-            ret = Ok(response);
-        }
+            Err(_) => parser::parse(&self.buffer[0..self.pos]),
+        };
 
-        /*
-        let mut dump_len = self.pos;
-        if dump_len > 10 {
-            dump_len = 10;
+        match parsed {
+            Ok((remainder, response)) => {
+                let len = remainder.len();
+                let consumed = self.pos - len;
+                if len > 0 {
+                    let start = self.pos - len;
+                    (&mut self.buffer[..]).moveslice(start..start + len, 0);
+                    self.pos = len;
+                    self.needs_parse = true;
+                } else {
+                    self.pos = 0;
+                }
+                ret = Ok((response, consumed));
+            }
+            Err(_) => {
+                if let Some((response, len)) = custom.filter(|_| self.unknown_line().is_some()).and_then(|parser| self.custom(parser)) {
+                    ret = Ok((response, len));
+                } else if let Some((response, len)) = self.interleaved() {
+                    ret = Ok((response, len));
+                } else if let Some(len) = self.unknown_line() {
+                    warn!("dropping unparseable line of {} bytes", len);
+                    if self.pos > len {
+                        self.buffer[..].moveslice(len..self.pos, 0);
+                    }
+                    self.pos -= len;
+                    self.needs_parse = self.pos > 0;
+                    ret = Err(len);
+                }
+            }
         }
 
-        log::info!("-------->>");
-        log::info!("remainder: {} bytes", self.pos);
-        for (i, b) in self.buffer[0..dump_len].iter().enumerate() {
-            log::info!( "{}: {} {:#x?}", i, *b as char, *b );
-        }
+        ret
+    }
+}
 
-        let remainder = from_utf8(&self.buffer[0..self.pos]);
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        match remainder {
-            Ok(s) => {
-                log::info!("{}", s);
-            },
-            Err(e) => {
-                let s = from_utf8( &self.buffer[0..e.valid_up_to()]).unwrap();
-                log::info!("{}<truncated>", s)
-            },
-        }
+    #[test]
+    fn test_drop_unparseable_line() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"garbage\r\nOK\r\n");
+        assert_eq!(buffer.parse(&shared, None).err(), Some(9));
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::Ok, 4))));
+    }
 
-        let mut dump_len = self.pos;
-        if dump_len > 30 {
-            dump_len = 30;
-        }
+    #[test]
+    fn test_drop_oldest() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"WIFI CONNECTED\r\nOK\r\n");
+        assert_eq!(buffer.drop_oldest(), 16);
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::Ok, 4))));
+    }
+
+    #[test]
+    fn test_interleaved_notification() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"+CIPSTA:ip:\"192.168.1.5\"\r\nWIFI DISCONNECT\r\n+CIPSTA:gateway:\"192.168.1.1\"\r\n");
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::WifiDisconnect, 17))));
+        buffer.write_slice(b"+CIPSTA:netmask:\"255.255.255.0\"\r\n\r\n1,CLOSED\r\nOK\r\n");
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::Closed(1), 10))));
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::IpAddresses(..), _))));
+        assert_eq!(buffer.pos, 0);
+    }
+
+    #[test]
+    fn test_interleaved_passive_data() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"+CIPSTA:ip:\"192.168.1.5\"\r\n+IPD,0,12\r\n+CIPSTA:gateway:\"192.168.1.1\"\r\n");
+        assert!(matches!(
+            buffer.parse(&shared, None),
+            Ok((Response::DataAvailable { link_id: 0, len: 12, remote: None }, 11))
+        ));
+        buffer.write_slice(b"+CIPSTA:netmask:\"255.255.255.0\"\r\n\r\nOK\r\n");
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::IpAddresses(..), _))));
+        assert_eq!(buffer.pos, 0);
+    }
 
-        log::info!("-------->>");
-        for (i, b ) in self.buffer[self.pos - dump_len..self.pos].iter().enumerate() {
-            log::info!( "{}: {} {:#x?}", i + (self.pos - dump_len), *b as char, *b );
+    struct Vendor;
+
+    impl UnsolicitedParser for Vendor {
+        fn parse<'b>(&self, buffer: &'b [u8]) -> Unsolicited<'b> {
+            match buffer.strip_prefix(b"+VENDOR:") {
+                Some(rest) => match line_end(rest) {
+                    Some(end) => Unsolicited::Parsed { len: 8 + end + 2, kind: 7, data: &rest[..end] },
+                    None => Unsolicited::Incomplete,
+                },
+                None => Unsolicited::Unknown,
+            }
         }
-        log::info!("<<--------");
+    }
 
+    #[test]
+    fn test_custom_notification() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"+VENDOR:abc\r\ngarbage\r\nOK\r\n");
+        match buffer.parse(&shared, Some(&Vendor)) {
+            Ok((Response::Custom(custom), 13)) => {
+                assert_eq!(custom.kind, 7);
+                assert_eq!(&*custom.data, b"abc");
+            }
+            _ => panic!("custom notification not parsed"),
+        }
+        assert_eq!(buffer.parse(&shared, Some(&Vendor)).err(), Some(9));
+        assert!(matches!(buffer.parse(&shared, Some(&Vendor)), Ok((Response::Ok, 4))));
+    }
 
-         */
-        //Ok(Response::None)
-        ret
+    #[test]
+    fn test_keep_incomplete_line() {
+        let shared = Shared::new();
+        let mut buffer = Buffer::<{ crate::INGRESS_LEN }>::new();
+        buffer.write_slice(b"+CIPSTA:ip:\"192.168");
+        assert!(matches!(buffer.parse(&shared, None), Ok((Response::None, 0))));
     }
 }
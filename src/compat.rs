@@ -0,0 +1,150 @@
+//! Use of HALs implementing embedded-hal 1.0 rather than 0.2.
+//!
+//! The adapter is driven by the embedded-hal 0.2 traits, which the wrappers
+//! here implement on top of their successors: `IoTx` and `IoRx` for serial
+//! ports implementing `embedded_io`, `NbTx` and `NbRx` for those implementing
+//! `embedded_hal_nb::serial`, and `Pin` and `Delay` for the remaining
+//! embedded-hal 1.0 traits.
+
+use crate::adapter::{self, AdapterError, InitOptions};
+use crate::ingress::Ingress;
+use crate::protocol::Response;
+use crate::INGRESS_LEN;
+use heapless::spsc::Queue;
+
+/// Serial transmitter implementing `embedded_io::Write`.
+pub struct IoTx<T>(pub T);
+
+impl<T> embedded_hal::serial::Write<u8> for IoTx<T>
+    where
+        T: embedded_io::Write,
+{
+    type Error = T::Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), T::Error> {
+        match self.0.write(&[word])? {
+            0 => Err(nb::Error::WouldBlock),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), T::Error> {
+        Ok(self.0.flush()?)
+    }
+}
+
+/// Serial receiver implementing `embedded_io::Read`, which must also report
+/// whether data can be read without blocking.
+pub struct IoRx<R>(pub R);
+
+impl<R> embedded_hal::serial::Read<u8> for IoRx<R>
+    where
+        R: embedded_io::Read + embedded_io::ReadReady,
+{
+    type Error = R::Error;
+
+    fn read(&mut self) -> nb::Result<u8, R::Error> {
+        if !self.0.read_ready()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut word = [0];
+        match self.0.read(&mut word)? {
+            0 => Err(nb::Error::WouldBlock),
+            _ => Ok(word[0]),
+        }
+    }
+}
+
+/// Serial transmitter implementing `embedded_hal_nb::serial::Write`.
+pub struct NbTx<T>(pub T);
+
+impl<T> embedded_hal::serial::Write<u8> for NbTx<T>
+    where
+        T: embedded_hal_nb::serial::Write<u8>,
+{
+    type Error = T::Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), T::Error> {
+        self.0.write(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), T::Error> {
+        self.0.flush()
+    }
+}
+
+/// Serial receiver implementing `embedded_hal_nb::serial::Read`.
+pub struct NbRx<R>(pub R);
+
+impl<R> embedded_hal::serial::Read<u8> for NbRx<R>
+    where
+        R: embedded_hal_nb::serial::Read<u8>,
+{
+    type Error = R::Error;
+
+    fn read(&mut self) -> nb::Result<u8, R::Error> {
+        self.0.read()
+    }
+}
+
+/// Output pin implementing `embedded_hal::digital::OutputPin` of 1.0.
+pub struct Pin<P>(pub P);
+
+impl<P> embedded_hal::digital::v2::OutputPin for Pin<P>
+    where
+        P: eh1::digital::OutputPin,
+{
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), P::Error> {
+        self.0.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), P::Error> {
+        self.0.set_high()
+    }
+}
+
+/// Delay implementing `embedded_hal::delay::DelayNs` of 1.0.
+pub struct Delay<D>(pub D);
+
+impl<D> embedded_hal::blocking::delay::DelayUs<u16> for Delay<D>
+    where
+        D: eh1::delay::DelayNs,
+{
+    fn delay_us(&mut self, us: u16) {
+        self.0.delay_us(u32::from(us));
+    }
+}
+
+/// Initialize an ESP8266 board, like `adapter::initialize_with_options`, using
+/// a serial port and HAL implementing `embedded_io` and embedded-hal 1.0.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn initialize<'a, Tx, Rx, const RQ: usize, const NQ: usize, EnablePin, ResetPin, D>(
+    tx: Tx,
+    rx: Rx,
+    enable_pin: EnablePin,
+    reset_pin: ResetPin,
+    response_queue: &'a mut Queue<Response, RQ>,
+    notification_queue: &'a mut Queue<Response, NQ>,
+    delay: D,
+    options: InitOptions,
+) -> Result<(adapter::Adapter<'a, IoTx<Tx>, RQ, NQ>, Ingress<'a, IoRx<Rx>, INGRESS_LEN, RQ, NQ>), AdapterError>
+    where
+        Tx: embedded_io::Write,
+        Rx: embedded_io::Read + embedded_io::ReadReady,
+        EnablePin: eh1::digital::OutputPin,
+        ResetPin: eh1::digital::OutputPin,
+        D: eh1::delay::DelayNs,
+{
+    adapter::initialize_with_options(
+        IoTx(tx),
+        IoRx(rx),
+        &mut Pin(enable_pin),
+        &mut Pin(reset_pin),
+        response_queue,
+        notification_queue,
+        &mut Delay(delay),
+        options,
+    )
+}
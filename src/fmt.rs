@@ -0,0 +1,51 @@
+//! Logging macros, forwarding to `defmt` with the `defmt` feature, and to
+//! `log` otherwise. Arguments therefore implement both `defmt::Format` and
+//! `core::fmt::Debug`.
+//!
+//! With the `no-logs` feature, nothing is logged, and neither the messages
+//! nor the formatting of their arguments end up in the binary.
+#![macro_use]
+
+macro_rules! debug {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "no-logs")))]
+        defmt::debug!($fmt $(, $arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "no-logs")))]
+        log::debug!($fmt $(, $arg)*);
+        #[cfg(feature = "no-logs")]
+        let _ = ($(&$arg),*);
+    }};
+}
+
+macro_rules! info {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "no-logs")))]
+        defmt::info!($fmt $(, $arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "no-logs")))]
+        log::info!($fmt $(, $arg)*);
+        #[cfg(feature = "no-logs")]
+        let _ = ($(&$arg),*);
+    }};
+}
+
+macro_rules! warn {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "no-logs")))]
+        defmt::warn!($fmt $(, $arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "no-logs")))]
+        log::warn!($fmt $(, $arg)*);
+        #[cfg(feature = "no-logs")]
+        let _ = ($(&$arg),*);
+    }};
+}
+
+macro_rules! error {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "no-logs")))]
+        defmt::error!($fmt $(, $arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "no-logs")))]
+        log::error!($fmt $(, $arg)*);
+        #[cfg(feature = "no-logs")]
+        let _ = ($(&$arg),*);
+    }};
+}
@@ -1,36 +1,401 @@
-use crate::{buffer::Buffer, protocol::Response};
-use heapless::{
-    consts::{U16, U2},
-    spsc::Producer,
-};
+use crate::{buffer::Buffer, protocol::Response, INGRESS_LEN};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttResponse;
+use crate::adapter::{NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use heapless::spsc::Producer;
 
+#[cfg(feature = "async")]
+use atomic_waker::AtomicWaker;
+#[cfg(feature = "trace")]
+use crate::trace::{Direction, Tracer};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+#[cfg(feature = "async")]
+use core::task::Waker;
 use embedded_hal::serial::Read;
 use nb::Error;
 
-pub struct Ingress<'a, Rx>
+/// Handling of received bytes, which are passed through as data rather than
+/// parsed while a transparent connection is open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Passthrough {
+    Off = 0,
+    /// Passed through once the board prompts for data
+    Armed = 1,
+    On = 2,
+}
+
+/// Largest number of boards driven at once, each by an `Adapter` and its
+/// `Ingress`. Initializing another board fails with `UnableToInitialize`.
+pub const MAX_BOARDS: usize = 4;
+
+/// State shared by an adapter and its ingress, which usually run in
+/// different contexts.
+pub(crate) struct Shared {
+    // whether held by the adapter and by the ingress of a board
+    adapter: AtomicBool,
+    ingress: AtomicBool,
+    passthrough: AtomicU8,
+    // set by the adapter to have the ingress discard its buffer
+    flush: AtomicBool,
+    // buffer of the adapter waiting for received data, null unless registered
+    receive_ptr: AtomicPtr<u8>,
+    receive_len: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED: Shared = Shared::new();
+
+static SHARED: [Shared; MAX_BOARDS] = [UNUSED; MAX_BOARDS];
+
+impl Shared {
+    pub(crate) const fn new() -> Self {
+        Self {
+            adapter: AtomicBool::new(false),
+            ingress: AtomicBool::new(false),
+            passthrough: AtomicU8::new(Passthrough::Off as u8),
+            flush: AtomicBool::new(false),
+            receive_ptr: AtomicPtr::new(core::ptr::null_mut()),
+            receive_len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claim the state of a new board, held by its adapter and its ingress
+    /// until both dropped their hold.
+    pub(crate) fn claim() -> Option<(SharedRef, SharedRef)> {
+        let shared = SHARED.iter().find(|shared| shared.hold())?;
+        shared.passthrough.store(Passthrough::Off as u8, Ordering::SeqCst);
+        shared.flush.store(false, Ordering::SeqCst);
+        shared.receive_ptr.store(core::ptr::null_mut(), Ordering::SeqCst);
+        shared.ingress.store(true, Ordering::SeqCst);
+        Some((
+            SharedRef { shared, ingress: false },
+            SharedRef { shared, ingress: true },
+        ))
+    }
+
+    // the ingress of the previous board may outlive its adapter
+    #[cfg(target_has_atomic = "8")]
+    fn hold(&self) -> bool {
+        !self.ingress.load(Ordering::Acquire)
+            && self
+                .adapter
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    // Without compare-and-swap, this relies on boards being initialized one
+    // at a time.
+    #[cfg(not(target_has_atomic = "8"))]
+    fn hold(&self) -> bool {
+        if self.ingress.load(Ordering::Acquire) || self.adapter.load(Ordering::Acquire) {
+            false
+        } else {
+            self.adapter.store(true, Ordering::Relaxed);
+            true
+        }
+    }
+
+    pub(crate) fn set_passthrough(&self, passthrough: Passthrough) {
+        self.passthrough.store(passthrough as u8, Ordering::SeqCst);
+    }
+
+    fn passthrough(&self) -> Passthrough {
+        match self.passthrough.load(Ordering::SeqCst) {
+            1 => Passthrough::Armed,
+            2 => Passthrough::On,
+            _ => Passthrough::Off,
+        }
+    }
+
+    pub(crate) fn request_flush(&self) {
+        self.flush.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn flush_pending(&self) -> bool {
+        self.flush.load(Ordering::SeqCst)
+    }
+
+    /// Register `buffer` for the ingress to copy received data into.
+    pub(crate) fn register<'b>(&'static self, buffer: &'b mut [u8]) -> ReceiveBuffer<'b> {
+        self.receive_len.store(buffer.len(), Ordering::SeqCst);
+        self.receive_ptr.store(buffer.as_mut_ptr(), Ordering::SeqCst);
+        ReceiveBuffer {
+            shared: self,
+            _buffer: PhantomData,
+        }
+    }
+
+    /// Copy received data into the registered buffer, returning how much of
+    /// it fit. Without a buffer registered, the data is dropped, and reported
+    /// by `Event::Overflow`.
+    pub(crate) fn fill_receive_buffer(&self, data: &[u8]) -> usize {
+        let ptr = self.receive_ptr.load(Ordering::SeqCst);
+        if ptr.is_null() {
+            warn!("dropping {} received bytes, no read is waiting for them", data.len());
+            RECEIVE_OVERFLOW.store(true, Ordering::SeqCst);
+            return 0;
+        }
+        let len = core::cmp::min(data.len(), self.receive_len.load(Ordering::SeqCst));
+        // Safety: the buffer stays borrowed by its registration, which isn't
+        // undone while the ingress is copying.
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, len);
+        }
+        self.receive_ptr.store(core::ptr::null_mut(), Ordering::SeqCst);
+        len
+    }
+}
+
+/// The hold of an adapter or an ingress on the state of its board, released
+/// when dropped.
+pub(crate) struct SharedRef {
+    shared: &'static Shared,
+    ingress: bool,
+}
+
+impl SharedRef {
+    pub(crate) fn get(&self) -> &'static Shared {
+        self.shared
+    }
+}
+
+impl Drop for SharedRef {
+    fn drop(&mut self) {
+        let held = if self.ingress {
+            &self.shared.ingress
+        } else {
+            &self.shared.adapter
+        };
+        held.store(false, Ordering::Release);
+    }
+}
+
+/// Registration of a buffer for the ingress to copy received data into,
+/// undone when dropped.
+///
+/// The ingress fills the buffer at most once, and reports the number of
+/// bytes copied with `Response::DataReceived`. This relies on the adapter not
+/// preempting `Ingress::digest`, which therefore finishes copying before the
+/// registration can be undone.
+pub(crate) struct ReceiveBuffer<'b> {
+    shared: &'static Shared,
+    _buffer: PhantomData<&'b mut [u8]>,
+}
+
+impl<'b> Drop for ReceiveBuffer<'b> {
+    fn drop(&mut self) {
+        self.shared.receive_ptr.store(core::ptr::null_mut(), Ordering::SeqCst);
+    }
+}
+
+// Wakers of the futures of `AsyncAdapter`, one per link and one for the
+// adapter as a whole, all woken once the ingress enqueued anything.
+#[cfg(feature = "async")]
+pub(crate) const WAKERS_LEN: usize = crate::adapter::MAX_LINKS + 1;
+
+#[cfg(feature = "async")]
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[cfg(feature = "async")]
+static WAKERS: [AtomicWaker; WAKERS_LEN] = [NEW_WAKER; WAKERS_LEN];
+
+#[cfg(feature = "async")]
+pub(crate) fn register_waker(slot: usize, waker: &Waker) {
+    WAKERS[slot].register(waker);
+}
+
+#[cfg(feature = "async")]
+pub(crate) fn wake_all() {
+    for waker in WAKERS.iter() {
+        waker.wake();
+    }
+}
+
+// Number of lines dropped by the ingress as unparseable, only written by it.
+static PARSE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+fn parse_failed() {
+    PARSE_FAILURES.store(PARSE_FAILURES.load(Ordering::SeqCst).wrapping_add(1), Ordering::SeqCst);
+}
+
+pub(crate) fn parse_failures() -> usize {
+    PARSE_FAILURES.load(Ordering::SeqCst)
+}
+
+// Number of bytes read from the board, only written by the ingress.
+static BYTES_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn bytes_received() -> usize {
+    BYTES_RECEIVED.load(Ordering::SeqCst)
+}
+
+// Set by the ingress once data was lost, and cleared by the adapter once reported.
+static BUFFER_OVERFLOW: AtomicBool = AtomicBool::new(false);
+static RESPONSES_OVERFLOW: AtomicBool = AtomicBool::new(false);
+static NOTIFICATIONS_OVERFLOW: AtomicBool = AtomicBool::new(false);
+static PAYLOADS_OVERFLOW: AtomicBool = AtomicBool::new(false);
+static RECEIVE_OVERFLOW: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn payloads_exhausted() {
+    PAYLOADS_OVERFLOW.store(true, Ordering::SeqCst);
+}
+
+/// Take the overflow which occurred since last asked, if any.
+pub(crate) fn take_overflow() -> Option<Overflow> {
+    let flags = [
+        (&BUFFER_OVERFLOW, Overflow::Buffer),
+        (&RESPONSES_OVERFLOW, Overflow::Responses),
+        (&NOTIFICATIONS_OVERFLOW, Overflow::Notifications),
+        (&PAYLOADS_OVERFLOW, Overflow::Payloads),
+        (&RECEIVE_OVERFLOW, Overflow::Receive),
+    ];
+    for (flag, overflow) in flags.iter() {
+        if flag.load(Ordering::SeqCst) {
+            flag.store(false, Ordering::SeqCst);
+            return Some(*overflow);
+        }
+    }
+    None
+}
+
+/// Where data was lost, reported by `Event::Overflow`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Overflow {
+    /// The buffer of received bytes was full, see `OverflowPolicy`.
+    Buffer,
+    /// A response to a command was dropped.
+    Responses,
+    /// A notification was dropped, as the adapter didn't process them in time.
+    Notifications,
+    /// Received data was dropped, as all `PAYLOAD_SLOTS` were held.
+    Payloads,
+    /// Data fetched using `AT+CIPRECVDATA` was dropped, as no read was
+    /// waiting for it any more, such as after timing out.
+    Receive,
+}
+
+/// What the ingress does with received bytes once its buffer is full.
+///
+/// Responses and notifications which don't fit their queues are dropped
+/// regardless, as only the adapter can make room in them.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// Drop the oldest buffered line to make room.
+    DropOldest,
+    /// Drop the bytes which don't fit.
+    DropNewest,
+    /// Keep the buffer as is, and fail `isr` and `write_slice`.
+    #[default]
+    Fail,
+}
+
+/// Outcome of `UnsolicitedParser::parse`.
+#[derive(Debug, PartialEq)]
+pub enum Unsolicited<'b> {
+    /// A notification of `len` bytes, passing on `data` with it.
+    Parsed { len: usize, kind: u16, data: &'b [u8] },
+    /// The start of a notification, which needs more bytes to be parsed.
+    Incomplete,
+    /// Not a notification known to the parser.
+    Unknown,
+}
+
+/// Parser of lines unknown to the driver, such as those of newer or
+/// vendor-specific firmware, see `Ingress::set_unsolicited_parser`.
+///
+/// Parsed notifications are passed on as `Response::Custom`, and retrieved
+/// using `Adapter::custom_notification`.
+pub trait UnsolicitedParser: Sync {
+    /// Parse the notification at the start of `buffer`, which holds a line
+    /// the driver failed to parse, along with any bytes received after it.
+    fn parse<'b>(&self, buffer: &'b [u8]) -> Unsolicited<'b>;
+}
+
+/// What a call of `Ingress::digest` did.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DigestSummary {
+    /// Bytes taken from the buffer.
+    pub consumed: usize,
+    /// Responses and notifications passed on to the adapter.
+    pub responses: usize,
+    /// Lines dropped as they could not be parsed.
+    pub dropped_lines: usize,
+}
+
+/// Receiver of the board's output, buffering up to `N` bytes until parsed.
+///
+/// The buffer has to hold a complete `+CIPRECVDATA` response, so `N` must
+/// exceed `BUFFER_LEN` by 64 bytes at least, which is checked at compile time.
+/// See `Initializer::with_ingress_len` to choose another length than
+/// `INGRESS_LEN`. `RQ` and `NQ` are the depths of the response and
+/// notification queues, see `initialize`.
+pub struct Ingress<'a, Rx, const N: usize = INGRESS_LEN, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN>
     where
         Rx: Read<u8>,
 {
     rx: Rx,
-    response_producer: Producer<'a, Response, U2>,
-    notification_producer: Producer<'a, Response, U16>,
-    buffer: Buffer,
+    response_producer: Producer<'a, Response, RQ>,
+    notification_producer: Producer<'a, Response, NQ>,
+    buffer: Buffer<N>,
+    shared: SharedRef,
+    overflow_policy: OverflowPolicy,
+    unsolicited_parser: Option<&'a dyn UnsolicitedParser>,
+    #[cfg(feature = "trace")]
+    tracer: Option<&'a dyn Tracer>,
 }
 
-impl<'a, Rx> Ingress<'a, Rx>
+impl<'a, Rx, const N: usize, const RQ: usize, const NQ: usize> Ingress<'a, Rx, N, RQ, NQ>
     where
         Rx: Read<u8>,
 {
-    pub fn new(
+    pub(crate) fn new(
         rx: Rx,
-        response_producer: Producer<'a, Response, U2>,
-        notification_producer: Producer<'a, Response, U16>,
+        response_producer: Producer<'a, Response, RQ>,
+        notification_producer: Producer<'a, Response, NQ>,
+        shared: SharedRef,
     ) -> Self {
         Self {
             rx,
             response_producer,
             notification_producer,
             buffer: Buffer::new(),
+            shared,
+            overflow_policy: OverflowPolicy::default(),
+            unsolicited_parser: None,
+            #[cfg(feature = "trace")]
+            tracer: None,
+        }
+    }
+
+    /// Set what happens to received bytes once the buffer is full, which
+    /// is reported by `Event::Overflow` in any case. Defaults to `Fail`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    /// Register a parser for lines the driver doesn't know, which are
+    /// dropped otherwise.
+    pub fn set_unsolicited_parser(&mut self, parser: &'a dyn UnsolicitedParser) {
+        self.unsolicited_parser = Some(parser);
+    }
+
+    /// Pass all bytes read from the board to `tracer`, before they are
+    /// buffered, see the `trace` module.
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: &'a dyn Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    fn received(&self, data: &[u8]) {
+        BYTES_RECEIVED.store(bytes_received().wrapping_add(data.len()), Ordering::SeqCst);
+        #[cfg(feature = "trace")]
+        if let Some(tracer) = self.tracer {
+            tracer.trace(Direction::Received, data);
         }
     }
 
@@ -56,30 +421,119 @@ impl<'a, Rx> Ingress<'a, Rx>
         Ok(())
     }
 
+    /// Hand over a chunk of received bytes at once, such as from a DMA or
+    /// idle-line UART driver, rather than reading them one by one using `isr`.
+    ///
+    /// Whenever the chunk doesn't fit, the buffered bytes are digested to make
+    /// room. Returns the number of bytes accepted, which is less than `data`
+    /// holds if no room could be made and the overflow policy is `Fail`; the
+    /// rest must be handed over again.
+    pub fn write_slice(&mut self, data: &[u8]) -> usize {
+        let mut accepted = self.buffer.write_slice(data);
+        while accepted < data.len() {
+            self.digest();
+            let mut len = self.buffer.write_slice(&data[accepted..]);
+            if len == 0 {
+                BUFFER_OVERFLOW.store(true, Ordering::SeqCst);
+                match self.overflow_policy {
+                    OverflowPolicy::Fail => break,
+                    OverflowPolicy::DropNewest => {
+                        accepted = data.len();
+                        break;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        self.buffer.drop_oldest();
+                        len = self.buffer.write_slice(&data[accepted..]);
+                    }
+                }
+            }
+            accepted += len;
+        }
+        self.received(&data[..accepted]);
+        accepted
+    }
+
     fn write(&mut self, octet: u8) -> Result<(), u8> {
-        self.buffer.write(octet)?;
-        Ok(())
+        self.received(&[octet]);
+        if self.buffer.write(octet).is_ok() {
+            return Ok(());
+        }
+        BUFFER_OVERFLOW.store(true, Ordering::SeqCst);
+        match self.overflow_policy {
+            OverflowPolicy::Fail => Err(octet),
+            OverflowPolicy::DropNewest => Ok(()),
+            OverflowPolicy::DropOldest => {
+                self.buffer.drop_oldest();
+                self.buffer.write(octet)
+            }
+        }
     }
 
     /// Digest and process the existing ingressed buffer to
     /// emit appropriate responses and notifications back
-    pub fn digest(&mut self) {
-        let result = self.buffer.parse();
+    ///
+    /// Data fetched using `AT+CIPRECVDATA` is copied straight into the buffer
+    /// of the `Adapter` reading it, so this must not be preempted by code
+    /// using the adapter.
+    ///
+    /// Lines which can't be parsed are dropped, and counted by
+    /// `Adapter::parse_failures`.
+    pub fn digest(&mut self) -> DigestSummary {
+        let mut summary = DigestSummary::default();
+
+        let shared = self.shared.get();
+        if shared.flush_pending() {
+            self.buffer.clear();
+            shared.flush.store(false, Ordering::SeqCst);
+        }
 
+        if shared.passthrough() == Passthrough::On {
+            // keep the data buffered until the adapter catches up
+            if self.response_producer.ready() {
+                let (response, consumed) = self.buffer.take_data();
+                summary.consumed = consumed;
+                if !matches!(response, Response::None) {
+                    self.response_producer.enqueue(response).ok();
+                    summary.responses = 1;
+                    #[cfg(feature = "async")]
+                    wake_all();
+                }
+            }
+            return summary;
+        }
+
+        let parsed = match self.buffer.parse(shared, self.unsolicited_parser) {
+            Ok((response, consumed)) => {
+                summary.consumed = consumed;
+                Some(response)
+            }
+            Err(dropped) => {
+                summary.consumed = dropped;
+                summary.dropped_lines = 1;
+                parse_failed();
+                None
+            }
+        };
 
-        if let Ok(response) = result {
+        if let Some(response) = parsed {
             if ! matches!(response, Response::None ) {
-                log::info!("--> {:?}", response);
+                info!("--> {:?}", response);
             }
-            match response {
-                Response::None => {}
+            if matches!(response, Response::ReadyForData) && shared.passthrough() == Passthrough::Armed {
+                shared.set_passthrough(Passthrough::On);
+            }
+            let enqueued = match response {
+                Response::None => false,
                 Response::Ok
                 | Response::Error
+                | Response::LinkInvalid
+                | Response::AlreadyConnected
                 | Response::FirmwareInfo(..)
                 | Response::Connect(..)
                 | Response::ReadyForData
                 | Response::ReceivedDataToSend(..)
                 | Response::DataReceived(..)
+                | Response::TransparentData(..)
                 | Response::SendOk
                 | Response::SendFail
                 | Response::WifiConnectionFailure(..)
@@ -87,25 +541,67 @@ impl<'a, Rx> Ingress<'a, Rx>
                 | Response::Resolvers(..)
                 | Response::DnsFail
                 | Response::UnlinkFail
-                | Response::IpAddresses(..) => {
-                    if let Err(response) = self.response_producer.enqueue(response) {
-                        log::error!("failed to enqueue response {:?}", response);
-                    }
-                }
-                Response::Closed(..) | Response::DataAvailable { .. } => {
-                    if let Err(response) = self.notification_producer.enqueue(response) {
-                        log::error!("failed to enqueue notification {:?}", response);
-                    }
-                }
-                Response::WifiConnected => {
-                    log::info!("wifi connected");
-                }
-                Response::WifiDisconnect => {
-                    log::info!("wifi disconnect");
-                }
-                Response::GotIp => {
-                    log::info!("wifi got ip");
-                }
+                | Response::SoftApInfo(..)
+                | Response::AccessPoint(..)
+                | Response::JoinedAccessPoint(..)
+                | Response::NoAccessPoint
+                | Response::MacAddress(..)
+                | Response::Station(..)
+                | Response::ConnectionStatus(..)
+                | Response::LinkStatus(..)
+                | Response::ReceiveLengths(..)
+                | Response::SntpTime(..)
+                | Response::Ping(..)
+                | Response::PingTimeout
+                | Response::HttpData(..)
+                | Response::Busy
+                | Response::IpAddresses(..) => self.enqueue_response(response),
+                #[cfg(feature = "mqtt")]
+                Response::Mqtt(MqttResponse::Published(..)) => self.enqueue_response(response),
+                #[cfg(feature = "mqtt")]
+                Response::Mqtt(..) => self.enqueue_notification(response),
+                Response::Closed(..)
+                | Response::IncomingConnection(..)
+                | Response::DataAvailable { .. }
+                | Response::DatagramReceived { .. }
+                | Response::WifiConnected
+                | Response::WifiDisconnect
+                | Response::GotIp
+                | Response::ModuleRestarted
+                | Response::UpdateProgress(..)
+                | Response::WpsSuccess
+                | Response::WpsFailure(..)
+                | Response::SmartConfig(..)
+                | Response::Custom(..) => self.enqueue_notification(response),
+            };
+            if enqueued {
+                summary.responses = 1;
+            }
+            #[cfg(feature = "async")]
+            wake_all();
+        }
+
+        summary
+    }
+
+    fn enqueue_response(&mut self, response: Response) -> bool {
+        match self.response_producer.enqueue(response) {
+            Ok(()) => true,
+            Err(response) => {
+                error!("failed to enqueue response {:?}", response);
+                RESPONSES_OVERFLOW.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    fn enqueue_notification(&mut self, response: Response) -> bool {
+        match self.notification_producer.enqueue(response) {
+            Ok(()) => true,
+            Err(response) => {
+                error!("failed to enqueue notification {:?}", response);
+                NOTIFICATIONS_OVERFLOW.store(true, Ordering::SeqCst);
+                false
             }
         }
     }
@@ -1,21 +1,49 @@
 #![no_std]
 
+// first, so that its macros are available to the other modules
+mod fmt;
+
 pub mod adapter;
+#[cfg(feature = "async")]
+pub mod asynch;
 mod buffer;
+#[cfg(feature = "embedded-hal-1")]
+pub mod compat;
 pub mod ingress;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 pub mod network;
+#[cfg(feature = "embedded-nal")]
+mod nal;
 mod num;
 mod parser;
+pub mod payload;
 pub mod protocol;
+mod queues;
+#[cfg(feature = "critical-section")]
+pub mod shared;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod transparent;
+pub mod wifi;
 
-pub use adapter::initialize;
+pub use queues::StaticQueues;
+pub use adapter::{initialize, initialize_with_options, start_initialize, Capabilities, Clock, CommandError, Event, Health, InitOptions, Initializer, LinkStats, ModuleProfile, Observed, SocketError, Stats, Timeouts};
 
-#[cfg(all(not(feature="1k"),not(feature="2k")))]
+#[cfg(all(not(feature="1k"),not(feature="2k"),not(feature="8k")))]
 pub const BUFFER_LEN: usize = 512;
-#[cfg(all(feature="1k",not(feature="2k")))]
+#[cfg(all(feature="1k",not(feature="2k"),not(feature="8k")))]
 pub const BUFFER_LEN: usize = 1024;
-#[cfg(all(feature="2k",not(feature="1k")))]
+#[cfg(all(feature="2k",not(feature="1k"),not(feature="8k")))]
 pub const BUFFER_LEN: usize = 2048;
+#[cfg(all(feature="8k",not(feature="1k"),not(feature="2k")))]
+pub const BUFFER_LEN: usize = 8192;
+
+/// Default length of the ingress buffer, see `Ingress`.
+#[cfg(not(feature="8k"))]
+pub const INGRESS_LEN: usize = 4096;
+#[cfg(feature="8k")]
+pub const INGRESS_LEN: usize = 8192 + 256;
 
 #[cfg(test)]
 mod tests {
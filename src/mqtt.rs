@@ -0,0 +1,293 @@
+//! Client for the MQTT support built into ESP-AT 2.x firmware.
+//!
+//! The board maintains the connection to the broker itself, so only a single
+//! connection is available. Messages received for subscriptions are queued
+//! until retrieved using `Adapter::mqtt_message`.
+
+use crate::adapter::{Adapter, AdapterError};
+use crate::payload::Payload;
+use crate::protocol::{Command, Escaped, Response};
+use core::fmt::Write as _;
+use embedded_hal::serial::Write;
+use heapless::{
+    spsc::Queue,
+    String,
+};
+
+/// The only link supported by the firmware.
+const LINK_ID: u8 = 0;
+
+/// Transport used to connect to the broker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MqttScheme {
+    Tcp,
+    /// TLS, without verifying certificates
+    Tls,
+    /// TLS, verifying the server certificate
+    TlsVerifyServer,
+    /// TLS, presenting a client certificate
+    TlsClientCertificate,
+    /// TLS, verifying the server and presenting a client certificate
+    TlsMutual,
+}
+
+impl MqttScheme {
+    fn code(self) -> u8 {
+        match self {
+            MqttScheme::Tcp => 1,
+            MqttScheme::Tls => 2,
+            MqttScheme::TlsVerifyServer => 3,
+            MqttScheme::TlsClientCertificate => 4,
+            MqttScheme::TlsMutual => 5,
+        }
+    }
+}
+
+/// Settings of the MQTT client.
+#[derive(Debug)]
+pub struct MqttConfig<'a> {
+    pub scheme: MqttScheme,
+    pub client_id: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// Quality of service of a publication or subscription.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl QoS {
+    fn code(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+        }
+    }
+}
+
+/// A message received for a subscription.
+pub struct MqttMessage {
+    pub topic: String<64>,
+    pub data: Payload,
+}
+
+impl MqttMessage {
+    /// The payload of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl core::fmt::Debug for MqttMessage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MqttMessage")
+            .field("topic", &self.topic)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for MqttMessage {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "MqttMessage {{ topic: {}, len: {} }}", self.topic, self.data.len())
+    }
+}
+
+/// MQTT commands to be sent to the board.
+#[derive(Debug)]
+pub enum MqttCommand<'a> {
+    Configure(MqttConfig<'a>),
+    Connect { host: &'a str, port: u16, reconnect: bool },
+    Publish { topic: &'a str, len: usize, qos: QoS, retain: bool },
+    Subscribe { topic: &'a str, qos: QoS },
+    Unsubscribe { topic: &'a str },
+    Disconnect,
+}
+
+impl<'a> MqttCommand<'a> {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            MqttCommand::Configure(_) => "AT+MQTTUSERCFG",
+            MqttCommand::Connect { .. } => "AT+MQTTCONN",
+            MqttCommand::Publish { .. } => "AT+MQTTPUBRAW",
+            MqttCommand::Subscribe { .. } => "AT+MQTTSUB",
+            MqttCommand::Unsubscribe { .. } => "AT+MQTTUNSUB",
+            MqttCommand::Disconnect => "AT+MQTTCLEAN",
+        }
+    }
+
+    pub(crate) fn encode(&self, s: &mut String<256>) -> core::fmt::Result {
+        match self {
+            MqttCommand::Configure(config) => {
+                write!(
+                    s,
+                    "AT+MQTTUSERCFG={},{},\"{}\",\"{}\",\"{}\",0,0,\"\"",
+                    LINK_ID,
+                    config.scheme.code(),
+                    Escaped(config.client_id),
+                    Escaped(config.username),
+                    Escaped(config.password)
+                )?;
+            }
+            MqttCommand::Connect { host, port, reconnect } => {
+                write!(
+                    s,
+                    "AT+MQTTCONN={},\"{}\",{},{}",
+                    LINK_ID, Escaped(host), port, *reconnect as u8
+                )?;
+            }
+            MqttCommand::Publish { topic, len, qos, retain } => {
+                write!(
+                    s,
+                    "AT+MQTTPUBRAW={},\"{}\",{},{},{}",
+                    LINK_ID,
+                    Escaped(topic),
+                    len,
+                    qos.code(),
+                    *retain as u8
+                )?;
+            }
+            MqttCommand::Subscribe { topic, qos } => {
+                write!(s, "AT+MQTTSUB={},\"{}\",{}", LINK_ID, Escaped(topic), qos.code())?;
+            }
+            MqttCommand::Unsubscribe { topic } => {
+                write!(s, "AT+MQTTUNSUB={},\"{}\"", LINK_ID, Escaped(topic))?;
+            }
+            MqttCommand::Disconnect => {
+                write!(s, "AT+MQTTCLEAN={}", LINK_ID)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// MQTT responses and notifications parsed from the board.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MqttResponse {
+    Connected,
+    Disconnected,
+    Published(bool),
+    Message(MqttMessage),
+}
+
+/// State of the MQTT client, as tracked from its notifications.
+pub(crate) struct MqttState {
+    connected: bool,
+    messages: Queue<MqttMessage, 3>,
+}
+
+impl MqttState {
+    pub(crate) fn new() -> Self {
+        Self {
+            connected: false,
+            messages: Queue::new(),
+        }
+    }
+
+    pub(crate) fn handle_notification(&mut self, response: MqttResponse) {
+        match response {
+            MqttResponse::Connected => {
+                self.connected = true;
+            }
+            MqttResponse::Disconnected => {
+                self.connected = false;
+            }
+            MqttResponse::Message(message) => {
+                let result = self.messages.enqueue(message);
+                if result.is_err() {
+                    warn!("dropping mqtt message");
+                }
+            }
+            MqttResponse::Published(_) => {}
+        }
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Adapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn send_mqtt(&mut self, command: MqttCommand) -> Result<(), AdapterError> {
+        match self.send(Command::Mqtt(command))? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    /// Configure the MQTT client. Must be done before connecting.
+    pub fn mqtt_configure(&mut self, config: MqttConfig) -> Result<(), AdapterError> {
+        self.send_mqtt(MqttCommand::Configure(config))
+    }
+
+    /// Connect to an MQTT broker.
+    ///
+    /// * `reconnect`: Whether the board reconnects automatically when the
+    ///   connection is lost.
+    pub fn mqtt_connect(&mut self, host: &str, port: u16, reconnect: bool) -> Result<(), AdapterError> {
+        self.send_mqtt(MqttCommand::Connect { host, port, reconnect })?;
+        self.process_notifications();
+        Ok(())
+    }
+
+    /// Whether the board is connected to the broker.
+    pub fn mqtt_is_connected(&mut self) -> bool {
+        self.process_notifications();
+        self.mqtt.connected
+    }
+
+    /// Publish a message to a topic.
+    pub fn mqtt_publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), AdapterError> {
+        let command = MqttCommand::Publish {
+            topic,
+            len: payload.len(),
+            qos,
+            retain,
+        };
+        self.send_mqtt(command)?;
+        match self.wait_for_response()? {
+            Response::ReadyForData => {}
+            other => return Err(self.unexpected(&other)),
+        }
+        self.write_data(payload)?;
+        match self.wait_for_response()? {
+            Response::Mqtt(MqttResponse::Published(true)) => Ok(()),
+            _ => Err(AdapterError::WriteError),
+        }
+    }
+
+    /// Subscribe to a topic. Messages are retrieved using `mqtt_message`.
+    pub fn mqtt_subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), AdapterError> {
+        self.send_mqtt(MqttCommand::Subscribe { topic, qos })
+    }
+
+    /// Unsubscribe from a topic.
+    pub fn mqtt_unsubscribe(&mut self, topic: &str) -> Result<(), AdapterError> {
+        self.send_mqtt(MqttCommand::Unsubscribe { topic })
+    }
+
+    /// Disconnect from the broker, discarding the client's configuration.
+    pub fn mqtt_disconnect(&mut self) -> Result<(), AdapterError> {
+        self.send_mqtt(MqttCommand::Disconnect)?;
+        self.mqtt.connected = false;
+        Ok(())
+    }
+
+    /// The next message received for a subscription, if any.
+    pub fn mqtt_message(&mut self) -> Option<MqttMessage> {
+        self.process_notifications();
+        self.mqtt.messages.dequeue()
+    }
+}
@@ -0,0 +1,114 @@
+//! Implementation of the `embedded-nal` UDP traits, for crates such as SNTP
+//! and CoAP clients written against them.
+//!
+//! Sockets are always non-blocking, as expected by `embedded-nal`.
+
+use crate::adapter::AdapterError;
+use crate::network::{Esp8266IpNetworkDriver, UdpSocket};
+use crate::protocol::{RemoteEndpoint, UdpOptions, UdpPeerMode};
+use drogue_network::addr::{IpAddr, Ipv4Addr, SocketAddr};
+use drogue_network::tcp::Mode;
+use embedded_hal::serial::Write;
+use embedded_nal::{UdpClientStack, UdpFullStack};
+
+pub(crate) fn from_nal(addr: core::net::SocketAddr) -> Result<SocketAddr, AdapterError> {
+    match addr {
+        core::net::SocketAddr::V4(addr) => {
+            let [a, b, c, d] = addr.ip().octets();
+            Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), addr.port()))
+        }
+        core::net::SocketAddr::V6(_) => Err(AdapterError::InvalidParameter),
+    }
+}
+
+pub(crate) fn to_nal(addr: SocketAddr) -> core::net::SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            let ip = core::net::Ipv4Addr::new(a, b, c, d);
+            core::net::SocketAddr::new(core::net::IpAddr::V4(ip), addr.port())
+        }
+        IpAddr::V6(ip) => {
+            let ip = core::net::Ipv6Addr::from(ip.octets());
+            core::net::SocketAddr::new(core::net::IpAddr::V6(ip), addr.port())
+        }
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> UdpClientStack for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    type UdpSocket = UdpSocket;
+    type Error = AdapterError;
+
+    /// Allocate a link, which is only started by `connect` or `bind`.
+    fn socket(&mut self) -> Result<UdpSocket, AdapterError> {
+        let link_id = self.adapter.get_mut().open()?;
+        Ok(UdpSocket {
+            link_id,
+            mode: Mode::NonBlocking,
+        })
+    }
+
+    fn connect(&mut self, socket: &mut UdpSocket, remote: core::net::SocketAddr) -> Result<(), AdapterError> {
+        let remote = from_nal(remote)?;
+        self.adapter
+            .get_mut()
+            .connect_udp(socket.link_id, RemoteEndpoint::Address(remote), None)
+    }
+
+    fn send(&mut self, socket: &mut UdpSocket, buffer: &[u8]) -> nb::Result<(), AdapterError> {
+        self.adapter.get_mut().write(socket.link_id, buffer).map(|_| ())
+    }
+
+    /// The sender is only known once enabled using `Adapter::set_remote_info`;
+    /// otherwise the remote the socket was connected to is reported.
+    fn receive(
+        &mut self,
+        socket: &mut UdpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, core::net::SocketAddr), AdapterError> {
+        let adapter = self.adapter.get_mut();
+        let (len, remote) = adapter.read_datagram_from(socket.link_id, buffer)?;
+        let remote = remote
+            .or_else(|| adapter.peer_addr(socket.link_id))
+            .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+        Ok((len, to_nal(remote)))
+    }
+
+    fn close(&mut self, socket: UdpSocket) -> Result<(), AdapterError> {
+        self.adapter.get_mut().close(socket.link_id)
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> UdpFullStack for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// Start the link listening on `local_port`, replying to the sender of
+    /// the most recent datagram.
+    fn bind(&mut self, socket: &mut UdpSocket, local_port: u16) -> Result<(), AdapterError> {
+        let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let options = UdpOptions {
+            local_port,
+            peer_mode: UdpPeerMode::ChangeAlways,
+        };
+        self.adapter
+            .get_mut()
+            .connect_udp(socket.link_id, RemoteEndpoint::Address(any), Some(options))
+    }
+
+    fn send_to(
+        &mut self,
+        socket: &mut UdpSocket,
+        remote: core::net::SocketAddr,
+        buffer: &[u8],
+    ) -> nb::Result<(), AdapterError> {
+        let remote = from_nal(remote)?;
+        self.adapter
+            .get_mut()
+            .write_to(socket.link_id, buffer, Some(remote))
+            .map(|_| ())
+    }
+}
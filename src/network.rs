@@ -1,11 +1,14 @@
-use crate::adapter::{Adapter, AdapterError};
+use crate::adapter::{Adapter, AdapterError, CommandError, LinkStats, Observed, SocketError, MAX_LINKS, NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use crate::protocol::{RemoteEndpoint, TlsConfig, UdpOptions, UdpPeerMode};
 use embedded_hal::serial::Write;
 
 use core::cell::RefCell;
 use drogue_network::addr::{
     HostAddr,
     HostSocketAddr,
-    IpAddr
+    IpAddr,
+    Ipv4Addr,
+    SocketAddr,
 };
 use drogue_network::tcp::{
     Mode,
@@ -15,24 +18,31 @@ use drogue_network::tcp::{
 };
 use core::fmt::Debug;
 use nom::lib::std::fmt::Formatter;
-use heapless::{
+use heapless05::{
     String,
     consts::{
         U256,
     },
 };
+use drogue_network::udp::UdpStack;
 use drogue_network::IpNetworkDriver;
 use drogue_network::dns::{Dns, DnsError, AddrType};
 /// Network driver based on the ESP8266 board
-pub struct Esp8266IpNetworkDriver<'a, Tx>
+///
+/// Sockets share the driver through `&self`, and each operation only borrows
+/// the adapter while it runs, so that several sockets can be serviced in turn.
+/// Blocking reads borrow it once per attempt rather than for the whole wait,
+/// and with `Mode::Timeout` give up once the timeout elapsed, which requires
+/// a clock, see `Adapter::set_clock`.
+pub struct Esp8266IpNetworkDriver<'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
     where
         Tx: Write<u8>,
 {
-    adapter: RefCell<Adapter<'a, Tx>>,
+    pub(crate) adapter: RefCell<Adapter<'a, Tx, RQ, NQ, L>>,
 }
 
 
-impl<'a, Tx> Debug for Esp8266IpNetworkDriver<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Debug for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
@@ -41,18 +51,376 @@ impl<'a, Tx> Debug for Esp8266IpNetworkDriver<'a, Tx>
     }
 }
 
-impl<'a, Tx> Esp8266IpNetworkDriver<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
-    pub(crate) fn new(adapter: Adapter<'a, Tx>) -> Self {
+    pub(crate) fn new(adapter: Adapter<'a, Tx, RQ, NQ, L>) -> Self {
         Self {
             adapter: RefCell::new(adapter),
         }
     }
+
+    /// Run a socket operation until it completes, as far as `mode` allows.
+    fn poll_mode<T, E>(
+        &self,
+        mode: &Mode,
+        timeout: E,
+        mut operation: impl FnMut(&mut Adapter<'a, Tx, RQ, NQ, L>) -> nb::Result<T, E>,
+    ) -> nb::Result<T, E> {
+        let deadline = match mode {
+            Mode::NonBlocking => return operation(&mut self.adapter.borrow_mut()),
+            Mode::Blocking => None,
+            // without a clock, this blocks like `Mode::Blocking`
+            Mode::Timeout(ms) => self
+                .adapter
+                .borrow()
+                .now_ms()
+                .map(|start| (start, u32::from(*ms))),
+        };
+        loop {
+            match operation(&mut self.adapter.borrow_mut()) {
+                Err(nb::Error::WouldBlock) => {}
+                result => return result,
+            }
+            if let Some((start, ms)) = deadline {
+                let now = self.adapter.borrow().now_ms().unwrap_or(start);
+                if now.wrapping_sub(start) >= ms {
+                    return Err(nb::Error::Other(timeout));
+                }
+            }
+        }
+    }
+
+    /// Connect an opened socket to a remote host, using TLS.
+    ///
+    /// The handshake is performed by the board itself, which takes noticeably
+    /// longer than a plain TCP connect. The ESP8266 firmware only supports a
+    /// single TLS connection at a time.
+    ///
+    /// The remote may be given as a domain name, which the board resolves.
+    pub fn connect_tls<'r, R>(
+        &self,
+        socket: TcpSocket,
+        remote: R,
+    ) -> Result<TcpSocket, TcpError>
+        where
+            R: Into<RemoteEndpoint<'r>>,
+    {
+        let mut adapter = self.adapter.borrow_mut();
+
+        adapter.connect_tls(socket.link_id, remote.into())?;
+        Ok(socket)
+    }
+
+    /// Select the certificates, stored on the board, used by a socket when
+    /// connecting using `connect_tls`.
+    ///
+    /// Requires firmware providing `AT+CIPSSLCCONF`. The settings are kept
+    /// until the socket is closed, and re-applied when reconnecting.
+    pub fn set_tls_config(&self, socket: &TcpSocket, config: TlsConfig) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_tls_config(socket.link_id, config).map_err(TcpError::from)
+    }
+
+    /// Set the server name indication (SNI) sent by a socket when connecting
+    /// using `connect_tls`. Most TLS frontends reject connections without it.
+    ///
+    /// Requires firmware providing `AT+CIPSSLCSNI`.
+    pub fn set_tls_server_name(&self, socket: &TcpSocket, name: &str) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_tls_server_name(socket.link_id, name).map_err(TcpError::from)
+    }
+
+    /// Use a pre-shared key rather than certificates, when a socket connects
+    /// using `connect_tls`.
+    ///
+    /// * `key`: The key, up to 32 bytes.
+    /// * `hint`: The identity of the key, up to 32 characters.
+    ///
+    /// Requires firmware providing `AT+CIPSSLCPSK`.
+    pub fn set_tls_psk(&self, socket: &TcpSocket, key: &[u8], hint: &str) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_tls_psk(socket.link_id, key, hint).map_err(TcpError::from)
+    }
+
+    /// Set up to three protocols offered using ALPN, such as `x-amzn-mqtt-ca`,
+    /// when a socket connects using `connect_tls`.
+    ///
+    /// Requires firmware providing `AT+CIPSSLCALPN`.
+    pub fn set_tls_alpn(&self, socket: &TcpSocket, protocols: &[&str]) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_tls_alpn(socket.link_id, protocols).map_err(TcpError::from)
+    }
+
+    /// Set the size of the buffer the board uses for TLS, from 2048 (the default)
+    /// to 4096 bytes. Must be set before connecting.
+    ///
+    /// Servers sending large certificate chains fail the handshake with the
+    /// default size.
+    pub fn set_tls_buffer_size(&self, size: u16) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_tls_buffer_size(size).map_err(TcpError::from)
+    }
+
+    /// Connect an opened socket to a remote host by its domain name.
+    ///
+    /// The name is resolved by the board while connecting, which does not
+    /// require a separate DNS lookup.
+    pub fn connect_host(
+        &self,
+        socket: TcpSocket,
+        host: &str,
+        port: u16,
+    ) -> Result<TcpSocket, TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+
+        adapter.connect_tcp(socket.link_id, RemoteEndpoint::Host(host, port))?;
+        Ok(socket)
+    }
+
+    /// Connect an opened socket to a remote host, like `TcpStack::connect`,
+    /// without blocking during the handshake.
+    ///
+    /// Must be called repeatedly, with the same remote, until it completes. No
+    /// other operations may be performed in the meantime.
+    pub fn connect_nb<'r, R>(&self, socket: &TcpSocket, remote: R) -> nb::Result<(), TcpError>
+        where
+            R: Into<RemoteEndpoint<'r>>,
+    {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter
+            .connect_tcp_nb(socket.link_id, remote.into())
+            .map_err(|e| e.map(TcpError::from))
+    }
+
+    /// Write all of `buffer` to a TCP socket, blocking until it was sent.
+    pub fn write_all(&self, socket: &mut TcpSocket, buffer: &[u8]) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.write_all(socket.link_id, buffer).map_err(TcpError::from)
+    }
+
+    /// Write several buffers to a TCP socket, such as a header and a body,
+    /// sending them together rather than one command each.
+    ///
+    /// Returns the number of bytes written, which is less than the buffers
+    /// hold if sending failed part way.
+    pub fn write_iovec(&self, socket: &mut TcpSocket, buffers: &[&[u8]]) -> nb::Result<usize, TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter
+            .write_iovec(socket.link_id, buffers)
+            .map_err(|e| e.map(TcpError::from))
+    }
+
+    /// Wrap a TCP socket to write formatted text to it, using `write!`.
+    pub fn fmt_writer<'s>(&'s self, socket: &'s mut TcpSocket) -> SocketWriter<'s, 'a, Tx, RQ, NQ, L> {
+        SocketWriter {
+            network: self,
+            socket,
+            error: None,
+        }
+    }
+
+    /// Stop writing to a socket, while still reading the response of the peer.
+    /// Further writes fail.
+    ///
+    /// The board cannot half-close a TCP connection, so the peer does not
+    /// learn about it; protocols must delimit requests by other means, such as
+    /// `Content-Length`.
+    pub fn shutdown(&self, socket: &mut TcpSocket) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.shutdown(socket.link_id).map_err(TcpError::from)
+    }
+
+    /// Enable TCP keepalive for an opened socket, probing the connection every
+    /// `seconds` (1 to 7200) once connected. Must be called before connecting.
+    ///
+    /// Without keepalive, idle connections may be dropped silently by NAT gateways.
+    pub fn set_keepalive(&self, socket: &TcpSocket, seconds: Option<u16>) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_keepalive(socket.link_id, seconds).map_err(TcpError::from)
+    }
+
+    /// Open a UDP socket, like `UdpStack::open`, optionally setting its local port.
+    ///
+    /// Depending on the peer mode of `options`, datagrams are sent to the sender
+    /// of received datagrams rather than to `remote`.
+    pub fn open_udp<'r, R>(
+        &self,
+        remote: R,
+        mode: Mode,
+        options: Option<UdpOptions>,
+    ) -> Result<UdpSocket, AdapterError>
+        where
+            R: Into<RemoteEndpoint<'r>>,
+    {
+        let mut adapter = self.adapter.borrow_mut();
+        let link_id = adapter.open()?;
+        if let Err(e) = adapter.connect_udp(link_id, remote.into(), options) {
+            // release the link again, the board never started it.
+            adapter.close(link_id).ok();
+            return Err(e);
+        }
+        Ok(UdpSocket {
+            link_id,
+            mode,
+        })
+    }
+
+    /// Open a UDP socket sending broadcast datagrams to `port`, and receiving on
+    /// `local_port`.
+    ///
+    /// The `broadcast` address is either `255.255.255.255`, or the subnet broadcast
+    /// from `IpAddresses::broadcast`. Replies do not redirect the socket, so all
+    /// datagrams written keep being broadcast.
+    pub fn open_broadcast(
+        &self,
+        broadcast: Ipv4Addr,
+        port: u16,
+        local_port: u16,
+        mode: Mode,
+    ) -> Result<UdpSocket, AdapterError> {
+        let remote = SocketAddr::new(IpAddr::V4(broadcast), port);
+        let options = UdpOptions {
+            local_port,
+            peer_mode: UdpPeerMode::Fixed,
+        };
+        self.open_udp(remote, mode, Some(options))
+    }
+
+    /// The number of bytes received by a TCP socket which can be read without
+    /// blocking.
+    pub fn bytes_available(&self, socket: &TcpSocket) -> usize {
+        self.adapter.borrow_mut().available(socket.link_id)
+    }
+
+    /// Whether data can be read from a TCP socket without blocking.
+    pub fn readable(&self, socket: &TcpSocket) -> bool {
+        self.bytes_available(socket) > 0
+    }
+
+    /// Read data received by a TCP socket without consuming it, such as to
+    /// tell TLS from plaintext on an accepted connection.
+    ///
+    /// Returns at most `BUFFER_LEN` bytes, even if more were received.
+    pub fn peek(&self, socket: &mut TcpSocket, buffer: &mut [u8]) -> nb::Result<usize, TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.peek(socket.link_id, buffer).map_err(|e| e.map(TcpError::from))
+    }
+
+    /// The remote address of a TCP socket, as far as known.
+    ///
+    /// For accepted connections, the address is only known once data was
+    /// received, with remote info enabled using `InitOptions::remote_info`.
+    pub fn peer_addr(&self, socket: &TcpSocket) -> Option<SocketAddr> {
+        self.adapter.borrow().peer_addr(socket.link_id)
+    }
+
+    /// Counters of a link, such as for spotting a misbehaving connection, or
+    /// `None` if `link_id` isn't tracked. See `TcpSocket::link_id`.
+    pub fn link_stats(&self, link_id: usize) -> Option<LinkStats> {
+        self.adapter.borrow().link_stats(link_id)
+    }
+
+    /// The address of the sender of the most recent datagram on a UDP socket,
+    /// or its remote address if none was received yet.
+    pub fn udp_peer_addr(&self, socket: &UdpSocket) -> Option<SocketAddr> {
+        self.adapter.borrow().peer_addr(socket.link_id)
+    }
+
+    /// Send a datagram to `remote`, rather than the socket's current peer.
+    pub fn send_to(
+        &self,
+        socket: &mut UdpSocket,
+        buffer: &[u8],
+        remote: HostSocketAddr,
+    ) -> nb::Result<(), AdapterError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter
+            .write_to(socket.link_id, buffer, Some(remote.as_socket_addr()))
+            .map(|_| ())
+    }
+
+    /// Read a datagram, along with the address of its sender.
+    ///
+    /// The sender is only reported once enabled using `Adapter::set_remote_info`.
+    pub fn receive_from(
+        &self,
+        socket: &mut UdpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, Option<SocketAddr>), AdapterError> {
+        let link_id = socket.link_id;
+        self.poll_mode(&socket.mode, AdapterError::Timeout, |adapter| {
+            adapter.read_datagram_from(link_id, buffer)
+        })
+    }
+
+    /// Start a TCP server listening on the given port.
+    ///
+    /// Inbound connections are retrieved using `accept()`.
+    pub fn listen(&self, port: u16) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.start_server(port).map_err(TcpError::from)
+    }
+
+    /// Stop the TCP server. Connections already accepted remain open.
+    pub fn stop_listening(&self) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.stop_server().map_err(TcpError::from)
+    }
+
+    /// Close inbound connections idle for longer than `seconds`, up to 7200.
+    /// Zero disables the timeout.
+    ///
+    /// Requires a running server, see `listen()`. Stale clients otherwise keep
+    /// occupying the board's links.
+    pub fn set_server_timeout(&self, seconds: u16) -> Result<(), TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.set_server_timeout(seconds).map_err(TcpError::from)
+    }
+
+    /// Retrieve the next inbound connection, if one is pending.
+    ///
+    /// * `mode`: The mode of the returned socket.
+    pub fn accept(&self, mode: Mode) -> nb::Result<TcpSocket, TcpError> {
+        let mut adapter = self.adapter.borrow_mut();
+        let link_id = adapter.accept().map_err(|e| e.map(TcpError::from))?;
+        Ok(TcpSocket {
+            link_id,
+            mode,
+        })
+    }
+
+    /// Open a TCP socket, like `TcpStack::open`, wrapped in a handle which
+    /// closes it when dropped.
+    pub fn open_connection(&self, mode: Mode) -> Result<TcpConnection<'_, 'a, Tx, RQ, NQ, L>, TcpError> {
+        let socket = TcpStack::open(self, mode)?;
+        Ok(TcpConnection {
+            network: self,
+            socket: Some(socket),
+        })
+    }
+
+    /// Open a UDP socket, like `open_udp`, wrapped in a handle which closes it
+    /// when dropped.
+    pub fn open_udp_connection<'r, R>(
+        &self,
+        remote: R,
+        mode: Mode,
+        options: Option<UdpOptions>,
+    ) -> Result<UdpConnection<'_, 'a, Tx, RQ, NQ, L>, AdapterError>
+        where
+            R: Into<RemoteEndpoint<'r>>,
+    {
+        let socket = self.open_udp(remote, mode, options)?;
+        Ok(UdpConnection {
+            network: self,
+            socket: Some(socket),
+        })
+    }
 }
 
-impl<'a, Tx> IpNetworkDriver for Esp8266IpNetworkDriver<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> IpNetworkDriver for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
@@ -75,27 +443,331 @@ pub struct TcpSocket {
     mode: Mode,
 }
 
+impl TcpSocket {
+    /// The link of the board used by this socket.
+    pub fn link_id(&self) -> usize {
+        self.link_id
+    }
+}
+
 impl Debug for TcpSocket {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("TcpSocket")
             .field("link_id", &self.link_id)
-            .field("mode",
-                   &match self.mode {
-                       Mode::Blocking => {
-                           "blocking"
-                       }
-                       Mode::NonBlocking => {
-                           "non-blocking"
-                       }
-                       Mode::Timeout(_t) => {
-                           "timeout"
-                       }
-                   },
-            )
+            .field("mode", &mode_name(&self.mode))
+            .finish()
+    }
+}
+
+/// Handle to a TCP socket, which is closed when dropped, see
+/// `Esp8266IpNetworkDriver::open_connection`.
+///
+/// Keeps the board's few links from leaking on early returns and error paths.
+pub struct TcpConnection<'n, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    network: &'n Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>,
+    // only taken when closed or released
+    socket: Option<TcpSocket>,
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn socket(&mut self) -> &mut TcpSocket {
+        self.socket.as_mut().unwrap()
+    }
+
+    /// Connect to a remote host, like `TcpStack::connect`.
+    pub fn connect(&mut self, remote: HostSocketAddr) -> Result<(), TcpError> {
+        let link_id = self.socket().link_id;
+        let mut adapter = self.network.adapter.borrow_mut();
+        adapter.connect_tcp(link_id, remote.into()).map_err(TcpError::from)
+    }
+
+    /// Whether the socket is connected, like `TcpStack::is_connected`.
+    pub fn is_connected(&mut self) -> Result<bool, TcpError> {
+        TcpStack::is_connected(self.network, self.socket())
+    }
+
+    /// Write to the socket, like `TcpStack::write`.
+    pub fn write(&mut self, buffer: &[u8]) -> nb::Result<usize, TcpError> {
+        let network = self.network;
+        TcpStack::write(network, self.socket(), buffer)
+    }
+
+    /// Write all of `buffer`, see `Esp8266IpNetworkDriver::write_all`.
+    pub fn write_all(&mut self, buffer: &[u8]) -> Result<(), TcpError> {
+        let network = self.network;
+        network.write_all(self.socket(), buffer)
+    }
+
+    /// Write several buffers, see `Esp8266IpNetworkDriver::write_iovec`.
+    pub fn write_iovec(&mut self, buffers: &[&[u8]]) -> nb::Result<usize, TcpError> {
+        let network = self.network;
+        network.write_iovec(self.socket(), buffers)
+    }
+
+    /// Wrap the socket to write formatted text to it, see
+    /// `Esp8266IpNetworkDriver::fmt_writer`.
+    pub fn fmt_writer(&mut self) -> SocketWriter<'_, 'a, Tx, RQ, NQ, L> {
+        let network = self.network;
+        network.fmt_writer(self.socket.as_mut().unwrap())
+    }
+
+    /// Read from the socket, like `TcpStack::read`.
+    pub fn read(&mut self, buffer: &mut [u8]) -> nb::Result<usize, TcpError> {
+        let network = self.network;
+        TcpStack::read(network, self.socket(), buffer)
+    }
+
+    /// The number of bytes which can be read without blocking.
+    pub fn bytes_available(&mut self) -> usize {
+        let network = self.network;
+        network.bytes_available(self.socket())
+    }
+
+    /// Read data without consuming it, see `Esp8266IpNetworkDriver::peek`.
+    pub fn peek(&mut self, buffer: &mut [u8]) -> nb::Result<usize, TcpError> {
+        let network = self.network;
+        network.peek(self.socket(), buffer)
+    }
+
+    /// Stop writing to the socket, see `Esp8266IpNetworkDriver::shutdown`.
+    pub fn shutdown(&mut self) -> Result<(), TcpError> {
+        let network = self.network;
+        network.shutdown(self.socket())
+    }
+
+    /// Close the socket, reporting whether the board closed it.
+    pub fn close(mut self) -> Result<(), TcpError> {
+        let socket = self.socket.take().unwrap();
+        TcpStack::close(self.network, socket)
+    }
+
+    /// Release the socket from the handle, leaving it to be closed using
+    /// `TcpStack::close`.
+    pub fn into_socket(mut self) -> TcpSocket {
+        self.socket.take().unwrap()
+    }
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Drop for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            match self.network.adapter.try_borrow_mut() {
+                Ok(mut adapter) => {
+                    if adapter.close(socket.link_id).is_err() {
+                        warn!("failed to close link {}", socket.link_id);
+                    }
+                }
+                Err(_) => warn!("leaking link {}, adapter in use", socket.link_id),
+            }
+        }
+    }
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Debug for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TcpConnection")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+/// Writes formatted text to a TCP socket, see
+/// `Esp8266IpNetworkDriver::fmt_writer`.
+///
+/// Each piece of text is sent as soon as it is formatted, rather than
+/// staged in a buffer, which takes one `AT+CIPSEND` each.
+pub struct SocketWriter<'s, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    network: &'s Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>,
+    socket: &'s mut TcpSocket,
+    error: Option<TcpError>,
+}
+
+impl<'s, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> SocketWriter<'s, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// The error which failed the last write, which `core::fmt::Error`
+    /// does not convey.
+    pub fn error(&self) -> Option<&TcpError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'s, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> core::fmt::Write for SocketWriter<'s, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+        self.network
+            .write_all(self.socket, s.as_bytes())
+            .map_err(|e| {
+                self.error.replace(e);
+                core::fmt::Error
+            })
+    }
+}
+
+impl<'s, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Debug for SocketWriter<'s, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SocketWriter")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+/// Handle to a UDP socket, which is closed when dropped, see
+/// `Esp8266IpNetworkDriver::open_udp_connection`.
+pub struct UdpConnection<'n, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    network: &'n Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>,
+    // only taken when closed or released
+    socket: Option<UdpSocket>,
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> UdpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn socket(&mut self) -> &mut UdpSocket {
+        self.socket.as_mut().unwrap()
+    }
+
+    /// Send a datagram, like `UdpStack::write`.
+    pub fn write(&mut self, buffer: &[u8]) -> nb::Result<(), AdapterError> {
+        let network = self.network;
+        UdpStack::write(network, self.socket(), buffer)
+    }
+
+    /// Receive a datagram, like `UdpStack::read`.
+    pub fn read(&mut self, buffer: &mut [u8]) -> nb::Result<usize, AdapterError> {
+        let network = self.network;
+        UdpStack::read(network, self.socket(), buffer)
+    }
+
+    /// Send a datagram to `remote`, see `Esp8266IpNetworkDriver::send_to`.
+    pub fn send_to(&mut self, buffer: &[u8], remote: HostSocketAddr) -> nb::Result<(), AdapterError> {
+        let network = self.network;
+        network.send_to(self.socket(), buffer, remote)
+    }
+
+    /// Receive a datagram along with its sender, see
+    /// `Esp8266IpNetworkDriver::receive_from`.
+    pub fn receive_from(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, Option<SocketAddr>), AdapterError> {
+        let network = self.network;
+        network.receive_from(self.socket(), buffer)
+    }
+
+    /// The sender of the most recent datagram, see
+    /// `Esp8266IpNetworkDriver::udp_peer_addr`.
+    pub fn peer_addr(&mut self) -> Option<SocketAddr> {
+        let network = self.network;
+        network.udp_peer_addr(self.socket())
+    }
+
+    /// Close the socket, reporting whether the board closed it.
+    pub fn close(mut self) -> Result<(), AdapterError> {
+        let socket = self.socket.take().unwrap();
+        UdpStack::close(self.network, socket)
+    }
+
+    /// Release the socket from the handle, leaving it to be closed using
+    /// `UdpStack::close`.
+    pub fn into_socket(mut self) -> UdpSocket {
+        self.socket.take().unwrap()
+    }
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Drop for UdpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            match self.network.adapter.try_borrow_mut() {
+                Ok(mut adapter) => {
+                    if adapter.close(socket.link_id).is_err() {
+                        warn!("failed to close link {}", socket.link_id);
+                    }
+                }
+                Err(_) => warn!("leaking link {}, adapter in use", socket.link_id),
+            }
+        }
+    }
+}
+
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Debug for UdpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("UdpConnection")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+/// Handle to a UDP socket.
+pub struct UdpSocket {
+    pub(crate) link_id: usize,
+    pub(crate) mode: Mode,
+}
+
+impl UdpSocket {
+    /// The link of the board used by this socket.
+    pub fn link_id(&self) -> usize {
+        self.link_id
+    }
+}
+
+impl Debug for UdpSocket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("UdpSocket")
+            .field("link_id", &self.link_id)
+            .field("mode", &mode_name(&self.mode))
             .finish()
     }
 }
 
+fn mode_name(mode: &Mode) -> &'static str {
+    match mode {
+        Mode::Blocking => {
+            "blocking"
+        }
+        Mode::NonBlocking => {
+            "non-blocking"
+        }
+        Mode::Timeout(_t) => {
+            "timeout"
+        }
+    }
+}
+
 /*
 impl Into<TcpError> for AdapterError {
     fn into(self) -> TcpError {
@@ -120,18 +792,23 @@ impl Into<TcpError> for AdapterError {
 impl From<AdapterError> for TcpError {
     fn from(error: AdapterError) -> Self {
         match error {
-            AdapterError::Timeout => {
+            AdapterError::Timeout
+            | AdapterError::ResponseTimeout(_)
+            | AdapterError::Command(CommandError { observed: Observed::Timeout(_), .. }) => {
                 TcpError::Timeout
             }
-            AdapterError::WriteError => {
+            AdapterError::WriteError | AdapterError::Socket { error: SocketError::SendFailed, .. } => {
                 TcpError::WriteError
             }
             AdapterError::ReadError => {
                 TcpError::ReadError
             }
-            AdapterError::InvalidSocket => {
+            AdapterError::InvalidSocket | AdapterError::Socket { error: SocketError::LinkInvalid, .. } => {
                 TcpError::SocketNotOpen
             }
+            AdapterError::Socket { error: SocketError::Rejected, .. } => {
+                TcpError::Busy
+            }
             _ => {
                 TcpError::Impl(TcpImplError::Unknown)
             }
@@ -139,7 +816,7 @@ impl From<AdapterError> for TcpError {
     }
 }
 
-impl<'a, Tx> TcpStack for Esp8266IpNetworkDriver<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> TcpStack for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
@@ -161,7 +838,7 @@ impl<'a, Tx> TcpStack for Esp8266IpNetworkDriver<'a, Tx>
     ) -> Result<Self::TcpSocket, Self::Error> {
         let mut adapter = self.adapter.borrow_mut();
 
-        adapter.connect_tcp(socket.link_id, remote)?;
+        adapter.connect_tcp(socket.link_id, remote.into())?;
         Ok(socket)
     }
 
@@ -183,24 +860,12 @@ impl<'a, Tx> TcpStack for Esp8266IpNetworkDriver<'a, Tx>
         socket: &mut Self::TcpSocket,
         buffer: &mut [u8],
     ) -> nb::Result<usize, Self::Error> {
-        let mut adapter = self.adapter.borrow_mut();
-
-        match socket.mode {
-            Mode::Blocking => {
-                nb::block!(
-                adapter.read(socket.link_id, buffer))
-                    .map_err(|e|
-                        nb::Error::from(TcpError::from(e))
-                    )
-            }
-            Mode::NonBlocking => {
-                adapter.read(socket.link_id, buffer)
-                    .map_err(|e|
-                        e.map(TcpError::from)
-                    )
-            }
-            Mode::Timeout(_) => unimplemented!(),
-        }
+        let link_id = socket.link_id;
+        self.poll_mode(&socket.mode, TcpError::Timeout, |adapter| {
+            adapter
+                .read(link_id, buffer)
+                .map_err(|e| e.map(TcpError::from))
+        })
     }
 
     fn close(&self, socket: Self::TcpSocket) -> Result<(), Self::Error> {
@@ -209,7 +874,40 @@ impl<'a, Tx> TcpStack for Esp8266IpNetworkDriver<'a, Tx>
     }
 }
 
-impl<'a, Tx> Dns for Esp8266IpNetworkDriver<'a, Tx>
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> UdpStack for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    type UdpSocket = UdpSocket;
+    type Error = AdapterError;
+
+    fn open(&self, remote: HostSocketAddr, mode: Mode) -> Result<Self::UdpSocket, Self::Error> {
+        self.open_udp(remote, mode, None)
+    }
+
+    fn write(&self, socket: &mut Self::UdpSocket, buffer: &[u8]) -> nb::Result<(), Self::Error> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.write(socket.link_id, buffer).map(|_| ())
+    }
+
+    fn read(
+        &self,
+        socket: &mut Self::UdpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<usize, Self::Error> {
+        let link_id = socket.link_id;
+        self.poll_mode(&socket.mode, AdapterError::Timeout, |adapter| {
+            adapter.read_datagram(link_id, buffer)
+        })
+    }
+
+    fn close(&self, socket: Self::UdpSocket) -> Result<(), Self::Error> {
+        let mut adapter = self.adapter.borrow_mut();
+        adapter.close(socket.link_id)
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Dns for Esp8266IpNetworkDriver<'a, Tx, RQ, NQ, L>
     where
         Tx: Write<u8>,
 {
@@ -227,8 +925,91 @@ impl<'a, Tx> Dns for Esp8266IpNetworkDriver<'a, Tx>
         }
     }
 
+    // The AT firmware has no reverse lookup.
     fn gethostbyaddr(&self, _addr: IpAddr) -> Result<String<U256>, Self::Error> {
-        unimplemented!()
+        Err(DnsError::NoSuchHost)
+    }
+}
+
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Error for AdapterError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            AdapterError::Timeout
+            | AdapterError::ResponseTimeout(_)
+            | AdapterError::Command(CommandError { observed: Observed::Timeout(_), .. }) => {
+                embedded_io::ErrorKind::TimedOut
+            }
+            AdapterError::InvalidSocket | AdapterError::Socket { error: SocketError::LinkInvalid, .. } => {
+                embedded_io::ErrorKind::NotConnected
+            }
+            AdapterError::InvalidParameter => embedded_io::ErrorKind::InvalidInput,
+            AdapterError::Unsupported => embedded_io::ErrorKind::Unsupported,
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io::ErrorType for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    type Error = AdapterError;
+}
+
+/// Blocks until data was received, regardless of the socket's mode. Returns
+/// zero once the peer closed the connection and all data was read.
+#[cfg(feature = "embedded-io")]
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io::Read for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, AdapterError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let link_id = self.socket().link_id;
+        let result = self.network.poll_mode(&Mode::Blocking, AdapterError::Timeout, |adapter| {
+            adapter.read(link_id, buffer)
+        });
+        match result {
+            Ok(len) => Ok(len),
+            Err(nb::Error::Other(AdapterError::InvalidSocket)) => Ok(0),
+            Err(nb::Error::Other(e)) => Err(e),
+            Err(nb::Error::WouldBlock) => Err(AdapterError::Timeout),
+        }
     }
 }
 
+#[cfg(feature = "embedded-io")]
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io::ReadReady for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn read_ready(&mut self) -> Result<bool, AdapterError> {
+        Ok(self.bytes_available() > 0)
+    }
+}
+
+/// Blocks until the board sent the data, regardless of the socket's mode.
+#[cfg(feature = "embedded-io")]
+impl<'n, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> embedded_io::Write for TcpConnection<'n, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, AdapterError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let link_id = self.socket().link_id;
+        let mut adapter = self.network.adapter.borrow_mut();
+        nb::block!(adapter.write(link_id, buffer))
+    }
+
+    /// Data is sent as soon as it is written, so there is nothing to flush.
+    fn flush(&mut self) -> Result<(), AdapterError> {
+        Ok(())
+    }
+}
@@ -16,28 +16,32 @@ pub(crate) fn ascii_to_digit(character: u8) -> Option<u8> {
 
 pub(crate) fn atoi_u8(digits: &[u8]) -> Option<u8> {
     let mut num: u8 = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
+    for digit in digits.iter() {
         let digit = ascii_to_digit(*digit)?;
-        let mut exp = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
-        }
-        num += exp * digit;
+        num = num.checked_mul(10)?.checked_add(digit)?;
     }
     Some(num)
 }
 
 pub(crate) fn atoi_usize(digits: &[u8]) -> Option<usize> {
     let mut num: usize = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
+    for digit in digits.iter() {
         let digit = ascii_to_digit(*digit)? as usize;
-        let mut exp = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
-        }
-        num += exp * digit;
+        num = num.checked_mul(10)?.checked_add(digit)?;
+    }
+    Some(num)
+}
+
+pub(crate) fn hex_to_u8(digits: &[u8]) -> Option<u8> {
+    let mut num: u8 = 0;
+    for digit in digits.iter() {
+        let value = match digit {
+            b'0'..=b'9' => digit - b'0',
+            b'a'..=b'f' => digit - b'a' + 10,
+            b'A'..=b'F' => digit - b'A' + 10,
+            _ => return None,
+        };
+        num = num.checked_mul(16)?.checked_add(value)?;
     }
     Some(num)
 }
@@ -4,7 +4,9 @@ use nom::character::streaming::digit1;
 use nom::do_parse;
 use nom::named;
 use nom::opt;
+use nom::preceded;
 use nom::tag;
+use nom::terminated;
 use nom::take;
 use nom::take_until;
 use nom::tuple;
@@ -13,24 +15,90 @@ use nom::IResult;
 use drogue_network::addr::{
     IpAddr,
     Ipv4Addr,
+    SocketAddr,
 };
 
-use crate::protocol::{FirmwareInfo, ResolverAddresses};
-use crate::protocol::IpAddresses;
+use heapless::{String, Vec};
+
+use core::str::from_utf8;
+
+use crate::protocol::{AccessPoint, ConnectionType, DateTime, Encryption, FirmwareInfo, JoinedAccessPoint, LinkStatus, MacAddress, ResolverAddresses, SoftApInfo, StationInfo};
+use crate::protocol::{Addressing, IpAddresses};
+use crate::payload::Payload;
 use crate::protocol::Response;
 use crate::protocol::WifiConnectionFailure;
+use crate::protocol::Weekday;
+use crate::protocol::UpdateProgress;
+use crate::protocol::SmartConfigEvent;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{MqttMessage, MqttResponse};
 
-use crate::num::{atoi_u8, atoi_usize};
+use crate::adapter::MAX_LINKS;
+use crate::num::{atoi_u8, atoi_usize, hex_to_u8};
 
 fn parse_u8(input: &[u8]) -> IResult<&[u8], u8> {
-    let (input, digits) = digit1(input)?;
-    IResult::Ok((input, atoi_u8(digits).unwrap()))
+    let (remainder, digits) = digit1(input)?;
+    match atoi_u8(digits) {
+        Some(num) => IResult::Ok((remainder, num)),
+        None => IResult::Err(nom::Err::Error((input, nom::error::ErrorKind::TooLarge))),
+    }
 }
 
 fn parse_usize(input: &[u8]) -> IResult<&[u8], usize> {
-    let (input, digits) = digit1(input)?;
-    let num = atoi_usize(digits).unwrap();
-    IResult::Ok((input, num))
+    let (remainder, digits) = digit1(input)?;
+    match atoi_usize(digits) {
+        Some(num) => IResult::Ok((remainder, num)),
+        None => IResult::Err(nom::Err::Error((input, nom::error::ErrorKind::TooLarge))),
+    }
+}
+
+// Link ids beyond those the adapter can track are rejected here, so that
+// firmware output can't index past its sockets.
+fn parse_link_id(input: &[u8]) -> IResult<&[u8], usize> {
+    let (remainder, link_id) = parse_usize(input)?;
+    if link_id >= MAX_LINKS {
+        return IResult::Err(nom::Err::Error((input, nom::error::ErrorKind::Verify)));
+    }
+    IResult::Ok((remainder, link_id))
+}
+
+fn parse_i8(input: &[u8]) -> IResult<&[u8], i8> {
+    let (input, sign) = opt!(input, char!('-'))?;
+    let (input, num) = parse_u8(input)?;
+    let num = num as i16;
+    let num = if sign.is_some() { -num } else { num };
+    IResult::Ok((input, num as i8))
+}
+
+fn parse_hex_u8(input: &[u8]) -> IResult<&[u8], u8> {
+    let (remainder, digits) = nom::bytes::streaming::take(2usize)(input)?;
+    match hex_to_u8(digits) {
+        Some(num) => IResult::Ok((remainder, num)),
+        None => IResult::Err(nom::Err::Error((input, nom::error::ErrorKind::HexDigit))),
+    }
+}
+
+fn parse_string<const N: usize>(input: &[u8]) -> IResult<&[u8], String<N>> {
+    let (input, _) = nom::character::streaming::char('"')(input)?;
+    let (input, value) = nom::bytes::streaming::take_until("\"")(input)?;
+    let (input, _) = nom::character::streaming::char('"')(input)?;
+    let mut string = String::new();
+    from_utf8(value)
+        .ok()
+        .and_then(|value| string.push_str(value).ok())
+        .ok_or(nom::Err::Error((input, nom::error::ErrorKind::Char)))?;
+    IResult::Ok((input, string))
+}
+
+fn parse_line<const N: usize>(input: &[u8]) -> IResult<&[u8], String<N>> {
+    let (input, value) = nom::bytes::streaming::take_until("\r\n")(input)?;
+    let (input, _) = nom::bytes::streaming::tag("\r\n")(input)?;
+    let mut string = String::new();
+    from_utf8(value)
+        .ok()
+        .and_then(|value| string.push_str(value).ok())
+        .ok_or(nom::Err::Error((input, nom::error::ErrorKind::TakeUntil)))?;
+    IResult::Ok((input, string))
 }
 
 #[rustfmt::skip]
@@ -56,31 +124,88 @@ named!(
 );
 
 named!(
-    pub reason<()>,
+    pub error<Response>,
+    alt!(
+          link_invalid
+        | already_connected
+        | plain_error
+    )
+);
+
+named!(
+    plain_error<Response>,
     do_parse!(
-        // use "!alt" once we identified an additional reason
-        link_invalid
-        >> ()
+        opt!(crlf) >>
+        opt!(crlf) >>
+        tag!("ERROR") >>
+        crlf >>
+        (
+            Response::Error
+        )
     )
 );
 
+// A command for a link which isn't connected
 named!(
-    pub link_invalid<()>,
+    link_invalid<Response>,
     do_parse!(
-        tag!("link is not valid") >> ()
+        tag!("link is not valid") >>
+        plain_error >>
+        (
+            Response::LinkInvalid
+        )
     )
 );
 
+// AT+CIPSTART for a link which is connected already
 named!(
-    pub error<Response>,
+    already_connected<Response>,
     do_parse!(
-        opt!(reason) >>
-        opt!(crlf) >>
-        opt!(crlf) >>
-        tag!("ERROR") >>
+        tag!("ALREADY CONNECTED") >>
+        plain_error >>
+        (
+            Response::AlreadyConnected
+        )
+    )
+);
+
+// Older firmware repeats the duration before acknowledging AT+GSLP
+#[rustfmt::skip]
+named!(
+    pub deep_sleep<Response>,
+    do_parse!(
+        parse_usize >>
         crlf >>
+        ok >>
         (
-            Response::Error
+            Response::Ok
+        )
+    )
+);
+
+// Echo of a command, while echo is enabled after a reset
+#[rustfmt::skip]
+named!(
+    pub echo<Response>,
+    do_parse!(
+        tag!("AT") >>
+        take_until!("\r\r\n") >>
+        tag!("\r\r\n") >>
+        (
+            Response::None
+        )
+    )
+);
+
+// Boot messages precede `ready`, and are sent at a different baud rate
+#[rustfmt::skip]
+named!(
+    pub ready<Response>,
+    do_parse!(
+        take_until!("ready\r\n") >>
+        tag!("ready\r\n") >>
+        (
+            Response::ModuleRestarted
         )
     )
 );
@@ -130,7 +255,7 @@ named!(
 named!(
     pub wifi_connection_failure<Response>,
     do_parse!(
-        tag!("+CWJAP:") >>
+        alt!(tag!("+CWJAP:") | tag!("+CWJEAP:")) >>
         code: parse_u8 >>
         crlf >>
         crlf >>
@@ -195,6 +320,9 @@ named!(
         netmask: ip_addr >>
         tag!("\"") >>
         crlf >>
+        // ESP-AT also reports the IPv6 link-local and global addresses
+        opt!(tuple!(tag!("+CIPSTA:ip6ll:"), take_until!("\r\n"), crlf)) >>
+        opt!(tuple!(tag!("+CIPSTA:ip6gl:"), take_until!("\r\n"), crlf)) >>
         crlf >>
         ok >>
         (
@@ -203,6 +331,8 @@ named!(
                     ip,
                     gateway,
                     netmask,
+                    // the firmware doesn't report it, the adapter knows better
+                    addressing: Addressing::Dhcp,
                 }
             )
         )
@@ -213,12 +343,42 @@ named!(
 named!(
     pub connect<Response>,
     do_parse!(
-        link_id: parse_u8 >>
+        // without a link id in single connection mode
+        link_id: opt!(terminated!(parse_link_id, char!(','))) >>
+        tag!("CONNECT") >>
+        crlf >>
+        ok >>
+        (
+            Response::Connect(link_id.unwrap_or(0))
+        )
+    )
+);
+
+// A server link announces itself with a bare `<id>,CONNECT`, unlike a
+// client connection which is followed by an `OK`. It must therefore be
+// tried after `connect`.
+#[rustfmt::skip]
+named!(
+    pub incoming_connection<Response>,
+    do_parse!(
+        link_id: parse_link_id >>
         tag!(",CONNECT") >>
         crlf >>
+        (
+            Response::IncomingConnection(link_id)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub no_change<Response>,
+    do_parse!(
+        tag!("no change") >>
+        crlf >>
         ok >>
         (
-            Response::Connect(link_id as usize)
+            Response::Ok
         )
     )
 );
@@ -272,54 +432,88 @@ named!(
     )
 );
 
+// Remote address of +IPD and +CIPRECVDATA, with AT+CIPDINFO=1.
+// Older firmware does not quote the IP address.
+#[rustfmt::skip]
+named!(
+    remote_addr<SocketAddr>,
+    do_parse!(
+        opt!(char!('"')) >>
+        ip: ip_addr >>
+        opt!(char!('"')) >>
+        char!(',') >>
+        port: parse_usize >>
+        (
+            SocketAddr::new(IpAddr::V4(ip), port as u16)
+        )
+    )
+);
+
 named!(
     pub data_available<Response>,
     do_parse!(
         opt!( crlf ) >>
         tag!( "+IPD,") >>
-        link_id: parse_usize >>
+        link_id: parse_link_id >>
         char!(',') >>
         len: parse_usize >>
+        remote: opt!(preceded!(char!(','), remote_addr)) >>
         crlf >>
         (
-            Response::DataAvailable {link_id, len }
+            Response::DataAvailable {link_id, len, remote }
         )
     )
 );
 
+named!(
+    pub datagram_received<Response>,
+    do_parse!(
+        opt!( crlf ) >>
+        tag!( "+IPD,") >>
+        link_id: parse_link_id >>
+        char!(',') >>
+        len: parse_usize >>
+        remote: opt!(preceded!(char!(','), remote_addr)) >>
+        char!(':') >>
+        data: take!(len) >>
+        ( match Payload::new(data) {
+            Some(data) => Response::DatagramReceived { link_id, data, remote },
+            None => Response::None,
+        } )
+    )
+);
+
 named!(
     pub closed<Response>,
     do_parse!(
         opt!(crlf) >>
-        link_id: parse_usize >>
-        tag!(",CLOSED") >>
+        link_id: opt!(terminated!(parse_link_id, char!(','))) >>
+        tag!("CLOSED") >>
         crlf >>
         (
-            Response::Closed(link_id)
+            Response::Closed(link_id.unwrap_or(0))
         )
     )
 );
 
+// The payload of a +CIPRECVDATA response, which the ingress copies straight
+// into the buffer of the read waiting for it.
 named!(
-    pub data_received<Response>,
+    pub data_received<&[u8]>,
     do_parse!(
         opt!(tag!("\r")) >>
         opt!(tag!("\n")) >>
         tag!("+CIPRECVDATA:") >>
         len: parse_usize >>
         char!(',') >>
+        // only the quoted form can be told apart from the data
+        opt!(terminated!(preceded!(char!('"'), remote_addr), char!(','))) >>
         data: take!(len) >>
         crlf >>
         ok >>
-        ( {
-            let mut buf = [0; crate::BUFFER_LEN];
-            for (i, b) in data.iter().enumerate() {
-                //log::info!( "copy {} @ {}", *b as char, i);
-                buf[i] = *b;
-            }
-            //log::info!("------------> onwards {:?}", buf);
-            Response::DataReceived(buf, len)
-        } )
+        (
+            data
+        )
     )
 );
 
@@ -353,7 +547,9 @@ named!(
     pub dns_lookup<Response>,
     do_parse!(
         tag!("+CIPDOMAIN:") >>
+        opt!(char!('"')) >>
         ip_addr: ip_addr >>
+        opt!(char!('"')) >>
         crlf >>
         ok >>
         (
@@ -387,28 +583,838 @@ named!(
     )
 );
 
+#[rustfmt::skip]
 named!(
-    pub parse<Response>,
+    mac_addr<MacAddress>,
+    do_parse!(
+        opt!(char!('"')) >>
+        a: parse_hex_u8 >>
+        char!(':') >>
+        b: parse_hex_u8 >>
+        char!(':') >>
+        c: parse_hex_u8 >>
+        char!(':') >>
+        d: parse_hex_u8 >>
+        char!(':') >>
+        e: parse_hex_u8 >>
+        char!(':') >>
+        f: parse_hex_u8 >>
+        opt!(char!('"')) >>
+        (
+            MacAddress([a, b, c, d, e, f])
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub soft_ap_info<Response>,
+    do_parse!(
+        alt!(tag!("+CWSAP_CUR:") | tag!("+CWSAP:")) >>
+        ssid: parse_string >>
+        char!(',') >>
+        password: parse_string >>
+        char!(',') >>
+        channel: parse_u8 >>
+        char!(',') >>
+        encryption: parse_u8 >>
+        char!(',') >>
+        max_connections: parse_u8 >>
+        char!(',') >>
+        hidden: parse_u8 >>
+        crlf >>
+        ok >>
+        (
+            Response::SoftApInfo(
+                SoftApInfo {
+                    ssid,
+                    password,
+                    channel,
+                    encryption: Encryption::from(encryption),
+                    max_connections,
+                    hidden: hidden != 0,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub access_point<Response>,
+    do_parse!(
+        tag!("+CWLAP:(") >>
+        encryption: parse_u8 >>
+        char!(',') >>
+        ssid: parse_string >>
+        char!(',') >>
+        rssi: parse_i8 >>
+        char!(',') >>
+        bssid: mac_addr >>
+        char!(',') >>
+        channel: parse_u8 >>
+        // further fields depend on the firmware version and AT+CWLAPOPT
+        take_until!(")") >>
+        char!(')') >>
+        crlf >>
+        (
+            Response::AccessPoint(
+                AccessPoint {
+                    ssid,
+                    bssid,
+                    rssi,
+                    channel,
+                    encryption: Encryption::from(encryption),
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub joined_access_point<Response>,
+    do_parse!(
+        alt!(tag!("+CWJAP_CUR:") | tag!("+CWJAP:")) >>
+        ssid: parse_string >>
+        char!(',') >>
+        bssid: mac_addr >>
+        char!(',') >>
+        channel: parse_u8 >>
+        char!(',') >>
+        rssi: parse_i8 >>
+        take_until!("\r\n") >>
+        crlf >>
+        ok >>
+        (
+            Response::JoinedAccessPoint(
+                JoinedAccessPoint {
+                    ssid,
+                    bssid,
+                    channel,
+                    rssi,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub no_access_point<Response>,
+    do_parse!(
+        tag!("No AP") >>
+        crlf >>
+        ok >>
+        (
+            Response::NoAccessPoint
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub mac_address<Response>,
+    do_parse!(
+        alt!(
+              tag!("+CIPSTAMAC_CUR:")
+            | tag!("+CIPSTAMAC:")
+            | tag!("+CIPAPMAC_CUR:")
+            | tag!("+CIPAPMAC:")
+        ) >>
+        mac: mac_addr >>
+        crlf >>
+        ok >>
+        (
+            Response::MacAddress(mac)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub connection_status<Response>,
+    do_parse!(
+        tag!("STATUS:") >>
+        status: parse_u8 >>
+        crlf >>
+        (
+            Response::ConnectionStatus(status)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    connection_type<ConnectionType>,
+    alt!(
+          tag!("\"TCP\"") => { |_| ConnectionType::TCP }
+        | tag!("\"UDP\"") => { |_| ConnectionType::UDP }
+        | tag!("\"SSL\"") => { |_| ConnectionType::SSL }
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub link_status<Response>,
+    do_parse!(
+        tag!("+CIPSTATUS:") >>
+        link_id: parse_link_id >>
+        char!(',') >>
+        connection_type: connection_type >>
+        char!(',') >>
+        remote: remote_addr >>
+        char!(',') >>
+        local_port: parse_usize >>
+        char!(',') >>
+        server: parse_u8 >>
+        crlf >>
+        (
+            Response::LinkStatus(
+                LinkStatus {
+                    link_id,
+                    connection_type,
+                    remote,
+                    local_port: local_port as u16,
+                    server: server == 1,
+                }
+            )
+        )
+    )
+);
+
+// Links which aren't connected are reported with a length of -1 by some
+// firmware, and lengths of links beyond `MAX_LINKS` are dropped.
+pub fn receive_lengths(input: &[u8]) -> IResult<&[u8], Response> {
+    let (mut input, _) = nom::bytes::streaming::tag("+CIPRECVLEN:")(input)?;
+    let mut lengths = Vec::new();
+    loop {
+        let (remainder, len) = alt!(input, tag!("-1") => { |_| 0 } | parse_usize)?;
+        lengths.push(len as u32).ok();
+        let (remainder, more) = opt!(remainder, char!(','))?;
+        input = remainder;
+        if more.is_none() {
+            break;
+        }
+    }
+    let (input, _) = crlf(input)?;
+    let (input, _) = ok(input)?;
+    IResult::Ok((input, Response::ReceiveLengths(lengths)))
+}
+
+// Older firmware reports stations without a prefix: `<ip>,<mac>`
+#[rustfmt::skip]
+named!(
+    pub station<Response>,
+    do_parse!(
+        opt!(tag!("+CWLIF:")) >>
+        opt!(char!('"')) >>
+        ip: ip_addr >>
+        opt!(char!('"')) >>
+        char!(',') >>
+        mac: mac_addr >>
+        crlf >>
+        (
+            Response::Station(
+                StationInfo {
+                    ip,
+                    mac,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    weekday<Weekday>,
+    alt!(
+          tag!("Mon") => { |_| Weekday::Monday }
+        | tag!("Tue") => { |_| Weekday::Tuesday }
+        | tag!("Wed") => { |_| Weekday::Wednesday }
+        | tag!("Thu") => { |_| Weekday::Thursday }
+        | tag!("Fri") => { |_| Weekday::Friday }
+        | tag!("Sat") => { |_| Weekday::Saturday }
+        | tag!("Sun") => { |_| Weekday::Sunday }
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    month<u8>,
+    alt!(
+          tag!("Jan") => { |_| 1 }
+        | tag!("Feb") => { |_| 2 }
+        | tag!("Mar") => { |_| 3 }
+        | tag!("Apr") => { |_| 4 }
+        | tag!("May") => { |_| 5 }
+        | tag!("Jun") => { |_| 6 }
+        | tag!("Jul") => { |_| 7 }
+        | tag!("Aug") => { |_| 8 }
+        | tag!("Sep") => { |_| 9 }
+        | tag!("Oct") => { |_| 10 }
+        | tag!("Nov") => { |_| 11 }
+        | tag!("Dec") => { |_| 12 }
+    )
+);
+
+// +CIPSNTPTIME:Thu Aug 04 14:48:05 2016
+#[rustfmt::skip]
+named!(
+    pub sntp_time<Response>,
+    do_parse!(
+        tag!("+CIPSNTPTIME:") >>
+        weekday: weekday >>
+        char!(' ') >>
+        month: month >>
+        char!(' ') >>
+        opt!(char!(' ')) >>
+        day: parse_u8 >>
+        char!(' ') >>
+        hour: parse_u8 >>
+        char!(':') >>
+        minute: parse_u8 >>
+        char!(':') >>
+        second: parse_u8 >>
+        char!(' ') >>
+        year: parse_usize >>
+        crlf >>
+        ok >>
+        (
+            Response::SntpTime(
+                DateTime {
+                    year: year as u16,
+                    month,
+                    day,
+                    hour,
+                    minute,
+                    second,
+                    weekday,
+                }
+            )
+        )
+    )
+);
+
+// Smart get wifi info
+// ssid:<ssid>
+// password:<password>
+#[rustfmt::skip]
+named!(
+    pub smart_config_credentials<Response>,
+    do_parse!(
+        tag!("Smart get wifi info") >>
+        crlf >>
+        tag!("ssid:") >>
+        ssid: parse_line >>
+        tag!("password:") >>
+        password: parse_line >>
+        (
+            Response::SmartConfig(
+                SmartConfigEvent::CredentialsReceived {
+                    ssid,
+                    password,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub smart_config_connected<Response>,
+    do_parse!(
+        tag!("smartconfig connected wifi") >>
+        crlf >>
+        (
+            Response::SmartConfig(SmartConfigEvent::Connected)
+        )
+    )
+);
+
+// smartconfig type:ESPTOUCH, and other progress reports
+#[rustfmt::skip]
+named!(
+    pub smart_config_info<Response>,
+    do_parse!(
+        tag!("smartconfig ") >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::None
+        )
+    )
+);
+
+// wps success,connecting ap ...
+#[rustfmt::skip]
+named!(
+    pub wps_success<Response>,
+    do_parse!(
+        tag!("wps success") >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::WpsSuccess
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub wps_failure<Response>,
+    do_parse!(
+        tag!("wps ") >>
+        reason: alt!(
+              tag!("timeout") => { |_| WifiConnectionFailure::Timeout }
+            | tag!("fail") => { |_| WifiConnectionFailure::ConnectionFailed }
+        ) >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::WpsFailure(reason)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub update_progress<Response>,
+    do_parse!(
+        tag!("+CIPUPDATE:") >>
+        stage: parse_u8 >>
+        crlf >>
+        (
+            Response::UpdateProgress(UpdateProgress::from(stage))
+        )
+    )
+);
+
+// Older firmware reports the round-trip time without a prefix: `+<time>`
+#[rustfmt::skip]
+named!(
+    pub ping<Response>,
+    do_parse!(
+        char!('+') >>
+        opt!(tag!("PING:")) >>
+        time: parse_usize >>
+        crlf >>
+        ok >>
+        (
+            Response::Ping(time as u32)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub ping_timeout<Response>,
+    do_parse!(
+        char!('+') >>
+        opt!(tag!("PING:")) >>
+        alt!(tag!("timeout") | tag!("TIMEOUT")) >>
+        crlf >>
+        error >>
+        (
+            Response::PingTimeout
+        )
+    )
+);
+
+// busy p... while processing a previous command, busy s... while sending
+#[rustfmt::skip]
+named!(
+    pub busy<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        tag!("busy ") >>
+        alt!(tag!("p") | tag!("s")) >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::Busy
+        )
+    )
+);
+
+// A chunk of the body, possibly one of several: +HTTPCLIENT:<len>,<data>
+#[rustfmt::skip]
+named!(
+    pub http_data<Response>,
+    do_parse!(
+        tag!("+HTTPCLIENT:") >>
+        len: parse_usize >>
+        char!(',') >>
+        data: take!(len) >>
+        opt!(crlf) >>
+        ( match Payload::new(data) {
+            Some(data) => Response::HttpData(data),
+            None => Response::None,
+        } )
+    )
+);
+
+#[cfg(feature = "mqtt")]
+#[rustfmt::skip]
+named!(
+    pub mqtt_message<Response>,
+    do_parse!(
+        tag!("+MQTTSUBRECV:") >>
+        parse_u8 >>
+        char!(',') >>
+        topic: parse_string >>
+        char!(',') >>
+        len: parse_usize >>
+        char!(',') >>
+        data: take!(len) >>
+        opt!(crlf) >>
+        ( match Payload::new(data) {
+            Some(data) => Response::Mqtt(MqttResponse::Message(MqttMessage { topic, data })),
+            None => Response::None,
+        } )
+    )
+);
+
+// +MQTTCONNECTED:<link>,<scheme>,"<host>","<port>","<path>",<reconnect>
+#[cfg(feature = "mqtt")]
+#[rustfmt::skip]
+named!(
+    pub mqtt_connected<Response>,
+    do_parse!(
+        tag!("+MQTTCONNECTED:") >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::Mqtt(MqttResponse::Connected)
+        )
+    )
+);
+
+#[cfg(feature = "mqtt")]
+#[rustfmt::skip]
+named!(
+    pub mqtt_disconnected<Response>,
+    do_parse!(
+        tag!("+MQTTDISCONNECTED:") >>
+        take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::Mqtt(MqttResponse::Disconnected)
+        )
+    )
+);
+
+#[cfg(feature = "mqtt")]
+#[rustfmt::skip]
+named!(
+    pub mqtt_published<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        tag!("+MQTTPUB:") >>
+        success: alt!(
+              tag!("OK") => { |_| true }
+            | tag!("FAIL") => { |_| false }
+        ) >>
+        crlf >>
+        (
+            Response::Mqtt(MqttResponse::Published(success))
+        )
+    )
+);
+
+#[cfg(feature = "mqtt")]
+named!(
+    mqtt<Response>,
+    alt!(
+          mqtt_message
+        | mqtt_connected
+        | mqtt_disconnected
+        | mqtt_published
+    )
+);
+
+#[cfg(not(feature = "mqtt"))]
+fn mqtt(input: &[u8]) -> IResult<&[u8], Response> {
+    Err(nom::Err::Error((input, nom::error::ErrorKind::Alt)))
+}
+
+// All responses but `ready`, which skips any unknown output preceding it
+named!(
+    pub known<Response>,
     alt!(
           ok
         | error
+        | deep_sleep
         | firmware_info
+        | echo
         | wifi_connected
         | wifi_disconnect
         | wifi_connection_failure
         | got_ip
         | ip_addresses
         | connect
+        | incoming_connection
         | closed
         | ready_for_data
         | received_data_to_send
         | send_ok
         | send_fail
         | data_available
-        | data_received
+        | datagram_received
         | dns_resolvers
         | dns_lookup
         | dns_fail
         | unlink_fail
+        | no_change
+        | soft_ap_info
+        | access_point
+        | joined_access_point
+        | no_access_point
+        | mac_address
+        | station
+        | connection_status
+        | link_status
+        | receive_lengths
+        | sntp_time
+        | update_progress
+        | wps_success
+        | wps_failure
+        | smart_config_credentials
+        | smart_config_connected
+        | smart_config_info
+        | ping
+        | ping_timeout
+        | busy
+        | http_data
+        | mqtt
+    )
+);
+
+named!(
+    pub parse<Response>,
+    alt!(
+          known
+        | ready
+    )
+);
+
+// Notifications of a single line, which the firmware may interleave with the
+// lines of a response
+named!(
+    pub unsolicited<Response>,
+    alt!(
+          wifi_connected
+        | wifi_disconnect
+        | got_ip
+        | closed
+        | incoming_connection
+        | data_available
     )
 );
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_connect() {
+        let result = parse(b"0,CONNECT\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"", Response::Connect(0)))));
+    }
+
+    #[test]
+    fn test_single_connection_connect() {
+        let result = parse(b"CONNECT\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"", Response::Connect(0)))));
+    }
+
+    #[test]
+    fn test_incoming_connection() {
+        let result = parse(b"1,CONNECT\r\n\r\n+IPD,1,10\r\n");
+        assert!(matches!(result, Ok((_, Response::IncomingConnection(1)))));
+    }
+
+    #[test]
+    fn test_link_invalid() {
+        let result = parse(b"link is not valid\r\n\r\nERROR\r\n");
+        assert!(matches!(result, Ok((b"", Response::LinkInvalid))));
+    }
+
+    #[test]
+    fn test_already_connected() {
+        let result = parse(b"ALREADY CONNECTED\r\n\r\nERROR\r\n");
+        assert!(matches!(result, Ok((b"", Response::AlreadyConnected))));
+    }
+
+    #[test]
+    fn test_soft_ap_info() {
+        let result = parse(b"+CWSAP_CUR:\"drogue\",\"secret12\",5,3,4,0\r\n\r\nOK\r\n");
+        if let Ok((b"", Response::SoftApInfo(info))) = result {
+            assert_eq!(info.ssid, "drogue");
+            assert_eq!(info.password, "secret12");
+            assert_eq!(info.channel, 5);
+            assert_eq!(info.encryption, Encryption::Wpa2Psk);
+            assert!(!info.hidden);
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_access_point() {
+        let result = parse(b"+CWLAP:(3,\"drogue\",-61,\"5c:cf:7f:0a:1b:2c\",11,-12,0)\r\n");
+        if let Ok((b"", Response::AccessPoint(ap))) = result {
+            assert_eq!(ap.ssid, "drogue");
+            assert_eq!(ap.rssi, -61);
+            assert_eq!(ap.bssid, MacAddress([0x5c, 0xcf, 0x7f, 0x0a, 0x1b, 0x2c]));
+            assert_eq!(ap.channel, 11);
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_station() {
+        let result = parse(b"192.168.4.2,5c:cf:7f:0a:1b:2c\r\n");
+        if let Ok((b"", Response::Station(station))) = result {
+            assert_eq!(station.ip, Ipv4Addr::new(192, 168, 4, 2));
+            assert_eq!(station.mac, MacAddress([0x5c, 0xcf, 0x7f, 0x0a, 0x1b, 0x2c]));
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_link_status() {
+        let result = parse(b"+CIPSTATUS:1,\"TCP\",\"192.168.1.10\",1883,50012,0\r\n");
+        if let Ok((b"", Response::LinkStatus(status))) = result {
+            assert_eq!(status.link_id, 1);
+            assert_eq!(status.connection_type, ConnectionType::TCP);
+            assert_eq!(status.remote, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 1883));
+            assert_eq!(status.local_port, 50012);
+            assert!(!status.server);
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_untracked_link_id() {
+        assert!(parse(b"16,CLOSED\r\n").is_err());
+        assert!(parse(b"+IPD,99,4:abcd").is_err());
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        assert!(parse(b"+IPD,0,99999999999999999999999\r\n").is_err());
+    }
+
+    #[test]
+    fn test_receive_lengths() {
+        let result = parse(b"+CIPRECVLEN:0,1460,-1,0,0\r\n\r\nOK\r\n");
+        if let Ok((b"", Response::ReceiveLengths(lengths))) = result {
+            assert_eq!(lengths.as_slice(), &[0, 1460, 0, 0, 0]);
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_sntp_time() {
+        let result = parse(b"+CIPSNTPTIME:Thu Aug 04 14:48:05 2016\r\nOK\r\n");
+        if let Ok((b"", Response::SntpTime(time))) = result {
+            assert_eq!(time.year, 2016);
+            assert_eq!(time.month, 8);
+            assert_eq!(time.day, 4);
+            assert_eq!((time.hour, time.minute, time.second), (14, 48, 5));
+            assert_eq!(time.weekday, Weekday::Thursday);
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        let result = parse(b"+PING:32\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"", Response::Ping(32)))));
+        let result = parse(b"+32\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"", Response::Ping(32)))));
+        let result = parse(b"+timeout\r\n\r\nERROR\r\n");
+        assert!(matches!(result, Ok((b"", Response::PingTimeout))));
+    }
+
+    #[test]
+    fn test_ready() {
+        let result = parse(b"ATE0\r\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"\r\nOK\r\n", Response::None))));
+        let result = parse(b"\x00\xfe\x8c\r\nready\r\n");
+        assert!(matches!(result, Ok((b"", Response::ModuleRestarted))));
+    }
+
+    #[test]
+    fn test_datagram_received() {
+        let result = parse(b"+IPD,1,3,192.168.1.2,5683:abc");
+        if let Ok((b"", Response::DatagramReceived { link_id, data, remote })) = result {
+            assert_eq!(link_id, 1);
+            assert_eq!(&data[..], b"abc");
+            assert_eq!(remote, Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 5683)));
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_smart_config_credentials() {
+        let result = parse(b"Smart get wifi info\r\nssid:drogue\r\npassword:secret12\r\n");
+        if let Ok((b"", Response::SmartConfig(SmartConfigEvent::CredentialsReceived { ssid, password }))) = result {
+            assert_eq!(ssid, "drogue");
+            assert_eq!(password, "secret12");
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_http_data() {
+        let result = parse(b"+HTTPCLIENT:5,hello\r\n");
+        if let Ok((b"", Response::HttpData(data))) = result {
+            assert_eq!(&data[..], b"hello");
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_mqtt_message() {
+        let result = parse(b"+MQTTSUBRECV:0,\"drogue/led\",2,on\r\n");
+        if let Ok((b"", Response::Mqtt(MqttResponse::Message(message)))) = result {
+            assert_eq!(message.topic, "drogue/led");
+            assert_eq!(message.payload(), b"on");
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_ip_addresses_ipv6() {
+        let result = parse(b"+CIPSTA:ip:\"192.168.1.5\"\r\n+CIPSTA:gateway:\"192.168.1.1\"\r\n+CIPSTA:netmask:\"255.255.255.0\"\r\n+CIPSTA:ip6ll:\"fe80::1\"\r\n\r\nOK\r\n");
+        if let Ok((b"", Response::IpAddresses(addresses))) = result {
+            assert_eq!(addresses.ip, Ipv4Addr::new(192, 168, 1, 5));
+        } else {
+            panic!("unexpected result: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_busy() {
+        let result = parse(b"busy p...\r\n");
+        assert!(matches!(result, Ok((b"", Response::Busy))));
+    }
+
+    #[test]
+    fn test_data_received() {
+        let result = data_received(b"+CIPRECVDATA:5,hello\r\n\r\nOK\r\n");
+        assert!(matches!(result, Ok((b"", b"hello"))));
+    }
+}
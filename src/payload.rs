@@ -0,0 +1,148 @@
+//! Pool of buffers for received data, so that the responses carrying it
+//! refer to a slot of the pool rather than holding `BUFFER_LEN` bytes each,
+//! and the response and notification queues cost bytes per item rather than
+//! kilobytes.
+//!
+//! Payloads are taken by the ingress, while parsing, and by the adapter for
+//! data it buffers, and released by whoever drops them.
+
+use crate::ingress;
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of payloads held at once, by queued responses and notifications,
+/// by datagrams and peeked data not yet read, and by MQTT messages not yet
+/// taken. Data received while all are held is
+/// dropped, and reported by `Event::Overflow`.
+pub const PAYLOAD_SLOTS: usize = 4;
+
+struct Slot {
+    used: AtomicBool,
+    // set by the adapter while claiming the slot
+    #[cfg(not(target_has_atomic = "8"))]
+    reserved: AtomicBool,
+    data: UnsafeCell<[u8; crate::BUFFER_LEN]>,
+}
+
+// Safety: the data of a slot is only accessed by the owner of its payload,
+// which is whoever claimed the slot.
+unsafe impl Sync for Slot {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_SLOT: Slot = Slot {
+    used: AtomicBool::new(false),
+    #[cfg(not(target_has_atomic = "8"))]
+    reserved: AtomicBool::new(false),
+    data: UnsafeCell::new([0; crate::BUFFER_LEN]),
+};
+
+static SLOTS: [Slot; PAYLOAD_SLOTS] = [FREE_SLOT; PAYLOAD_SLOTS];
+
+impl Slot {
+    #[cfg(target_has_atomic = "8")]
+    fn claim(&self) -> bool {
+        self.used
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[cfg(target_has_atomic = "8")]
+    fn claim_preemptible(&self) -> bool {
+        self.claim()
+    }
+
+    // Without compare-and-swap, this relies on the ingress not being
+    // preempted by the adapter, which reserves the slots it claims.
+    #[cfg(not(target_has_atomic = "8"))]
+    fn claim(&self) -> bool {
+        if self.used.load(Ordering::Acquire) || self.reserved.load(Ordering::Acquire) {
+            false
+        } else {
+            self.used.store(true, Ordering::Relaxed);
+            true
+        }
+    }
+
+    // Claim the slot from the adapter, which the ingress may preempt.
+    #[cfg(not(target_has_atomic = "8"))]
+    fn claim_preemptible(&self) -> bool {
+        self.reserved.store(true, Ordering::SeqCst);
+        let claimed = !self.used.load(Ordering::SeqCst);
+        if claimed {
+            self.used.store(true, Ordering::SeqCst);
+        }
+        self.reserved.store(false, Ordering::SeqCst);
+        claimed
+    }
+}
+
+/// Data received by the board, held in a slot of the pool until dropped.
+pub struct Payload {
+    slot: usize,
+    len: usize,
+}
+
+impl Payload {
+    /// Copy up to `BUFFER_LEN` bytes of `data` into a free slot, if any.
+    pub(crate) fn new(data: &[u8]) -> Option<Self> {
+        let slot = match SLOTS.iter().position(Slot::claim) {
+            Some(slot) => slot,
+            None => {
+                warn!("dropping {} bytes, no payload slot available", data.len());
+                ingress::payloads_exhausted();
+                return None;
+            }
+        };
+        let len = core::cmp::min(data.len(), crate::BUFFER_LEN);
+        // Safety: the slot was just claimed, so nobody else accesses its data.
+        let buffer = unsafe { &mut *SLOTS[slot].data.get() };
+        buffer[0..len].copy_from_slice(&data[0..len]);
+        Some(Self { slot, len })
+    }
+
+    /// Fill a free slot using `fill`, which returns the number of bytes it
+    /// wrote, or `None` if all slots are held. Used by the adapter, unlike
+    /// `new`.
+    pub(crate) fn fill<E>(fill: impl FnOnce(&mut [u8]) -> Result<usize, E>) -> Option<Result<Self, E>> {
+        let slot = SLOTS.iter().position(Slot::claim_preemptible)?;
+        // Safety: the slot was just claimed, so nobody else accesses its data.
+        let buffer = unsafe { &mut *SLOTS[slot].data.get() };
+        // released when dropped, should filling fail
+        let mut payload = Self { slot, len: 0 };
+        Some(fill(buffer).map(|len| {
+            payload.len = core::cmp::min(len, crate::BUFFER_LEN);
+            payload
+        }))
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: the slot is held by this payload until dropped.
+        let buffer = unsafe { &*SLOTS[self.slot].data.get() };
+        &buffer[0..self.len]
+    }
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        SLOTS[self.slot].used.store(false, Ordering::Release);
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Payload").field("len", &self.len).finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Payload {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Payload {{ len: {} }}", self.len)
+    }
+}
@@ -1,24 +1,89 @@
+use core::convert::TryFrom;
 use core::fmt;
 use core::fmt::{Debug, Write};
-use drogue_network::addr::{IpAddr, Ipv4Addr, SocketAddr};
-use heapless::{
-    String,
-    consts::{
-        U128,
-    }
-};
+use drogue_network::addr::{HostSocketAddr, IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{MqttCommand, MqttResponse};
+use crate::payload::Payload;
+use crate::adapter::{AdapterError, MAX_LINKS};
+use heapless::{String, Vec};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ResolverAddresses {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub resolver1: Ipv4Addr,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub resolver2: Option<Ipv4Addr>,
 }
 
 /// Type of socket connection.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ConnectionType {
     TCP,
     UDP,
+    SSL,
+}
+
+/// Remote end of a connection.
+#[derive(Debug, Clone, Copy)]
+pub enum RemoteEndpoint<'a> {
+    Address(SocketAddr),
+    /// A domain name and port, resolved by the board when connecting.
+    Host(&'a str, u16),
+}
+
+impl<'a> From<SocketAddr> for RemoteEndpoint<'a> {
+    fn from(addr: SocketAddr) -> Self {
+        RemoteEndpoint::Address(addr)
+    }
+}
+
+impl<'a> From<HostSocketAddr> for RemoteEndpoint<'a> {
+    fn from(addr: HostSocketAddr) -> Self {
+        RemoteEndpoint::Address(addr.as_socket_addr())
+    }
+}
+
+/// Which peer a UDP link sends to, after receiving datagrams.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UdpPeerMode {
+    /// Always send to the remote given when opening the link.
+    Fixed,
+    /// Send to the sender of the first datagram received.
+    ChangeOnce,
+    /// Send to the sender of the most recent datagram received.
+    ChangeAlways,
+}
+
+/// Local settings of a UDP link.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpOptions {
+    pub local_port: u16,
+    pub peer_mode: UdpPeerMode,
+}
+
+/// Which side of a TLS connection is authenticated using certificates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsAuthentication {
+    None,
+    /// The board presents a client certificate to the server.
+    Client,
+    /// The board verifies the server's certificate.
+    Server,
+    /// Both the board and the server present certificates.
+    Mutual,
+}
+
+/// Certificate settings of a TLS link, using certificates stored on the board.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsConfig {
+    pub authentication: TlsAuthentication,
+    /// Slot of the client certificate and key.
+    pub pki_slot: u8,
+    /// Slot of the CA certificate verifying the server.
+    pub ca_slot: u8,
 }
 
 /// Mode of the Wi-Fi stack
@@ -32,129 +97,1116 @@ pub enum WiFiMode {
     SoftAccessPointAndStation,
 }
 
+/// Encryption used by a Wi-Fi access point
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encryption {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+    /// A scheme reported by the firmware which is not known to this driver
+    Unknown(u8),
+}
+
+impl Encryption {
+    /// The numeric code used by the AT firmware
+    pub fn code(&self) -> u8 {
+        match self {
+            Encryption::Open => 0,
+            Encryption::Wep => 1,
+            Encryption::WpaPsk => 2,
+            Encryption::Wpa2Psk => 3,
+            Encryption::WpaWpa2Psk => 4,
+            Encryption::Wpa2Enterprise => 5,
+            Encryption::Unknown(code) => *code,
+        }
+    }
+}
+
+impl From<u8> for Encryption {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => Encryption::Open,
+            1 => Encryption::Wep,
+            2 => Encryption::WpaPsk,
+            3 => Encryption::Wpa2Psk,
+            4 => Encryption::WpaWpa2Psk,
+            5 => Encryption::Wpa2Enterprise,
+            _ => Encryption::Unknown(code),
+        }
+    }
+}
+
+/// A MAC address, such as the BSSID of an access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            o[0], o[1], o[2], o[3], o[4], o[5]
+        )
+    }
+}
+
+/// An access point found by a Wi-Fi scan.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AccessPoint {
+    pub ssid: String<32>,
+    pub bssid: MacAddress,
+    pub rssi: i8,
+    pub channel: u8,
+    pub encryption: Encryption,
+}
+
+/// The access point the board has joined.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JoinedAccessPoint {
+    pub ssid: String<32>,
+    pub bssid: MacAddress,
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// A station connected to the board's soft access point.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StationInfo {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub ip: Ipv4Addr,
+    pub mac: MacAddress,
+}
+
+/// Settings for running the board as a soft access point.
+///
+/// The firmware does not support WEP for the soft access point, and requires
+/// a password of at least 8 characters unless the access point is open.
+#[derive(Debug)]
+pub struct SoftApConfig<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str,
+    pub channel: u8,
+    pub encryption: Encryption,
+}
+
+/// A link the board reports as connected, see `Adapter::sync_socket_states`.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStatus {
+    pub link_id: usize,
+    pub connection_type: ConnectionType,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub remote: SocketAddr,
+    pub local_port: u16,
+    /// Whether the board accepted the connection as a server.
+    pub server: bool,
+}
+
+/// The soft access point configuration, as reported by the board.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SoftApInfo {
+    pub ssid: String<32>,
+    pub password: String<64>,
+    pub channel: u8,
+    pub encryption: Encryption,
+    pub max_connections: u8,
+    pub hidden: bool,
+}
+
+/// Protocol used by the phone app provisioning the board with SmartConfig.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmartConfigType {
+    EspTouch,
+    AirKiss,
+    EspTouchAndAirKiss,
+}
+
+/// Progress of SmartConfig provisioning.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SmartConfigEvent {
+    /// The credentials of the access point, as sent by the phone app.
+    CredentialsReceived { ssid: String<32>, password: String<64> },
+    /// The board has joined the access point, and SmartConfig may be stopped.
+    Connected,
+}
+
+/// Settings for announcing the board using mDNS.
+///
+/// The board becomes reachable as `<hostname>.local`, offering the service
+/// `_<service>._tcp` on the given port.
+#[derive(Debug)]
+pub struct MdnsConfig<'a> {
+    pub hostname: &'a str,
+    pub service: &'a str,
+    pub port: u16,
+}
+
+/// Method of a request issued by the board's HTTP client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpMethod {
+    Head,
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+/// Content type of the body of a request issued by the board's HTTP client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpContentType {
+    FormUrlEncoded,
+    Json,
+    Multipart,
+    Xml,
+}
+
+/// A request issued by the board's HTTP client.
+///
+/// HTTPS is used when the URL starts with `https://`.
+#[derive(Debug)]
+pub struct HttpRequest<'a> {
+    pub method: HttpMethod,
+    pub url: &'a str,
+    pub content_type: HttpContentType,
+    pub body: Option<&'a str>,
+}
+
+/// Authentication method for joining a WPA2-Enterprise access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EapMethod {
+    Peap,
+    Ttls,
+}
+
+/// Credentials for joining a WPA2-Enterprise access point.
+///
+/// The server certificate is not validated.
+#[derive(Debug)]
+pub struct EnterpriseCredentials<'a> {
+    pub ssid: &'a str,
+    pub method: EapMethod,
+    /// Outer identity, sent unencrypted
+    pub identity: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// ISO 3166-1 alpha-2 country code, such as `DE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    /// Returns `None` unless `code` consists of two ASCII letters.
+    pub fn new(code: &str) -> Option<Self> {
+        match code.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Some(CountryCode([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.0[0] as char, self.0[1] as char)
+    }
+}
+
+/// Whether the board adopts the regulatory domain advertised by the access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CountryPolicy {
+    FollowAccessPoint,
+    Fixed,
+}
+
+/// Regulatory domain of the board, restricting the channels it may use.
+#[derive(Debug, Clone, Copy)]
+pub struct CountryConfig {
+    pub code: CountryCode,
+    pub policy: CountryPolicy,
+    pub start_channel: u8,
+    pub channel_count: u8,
+}
+
+/// Hardware flow control of the board's UART.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowControl {
+    None,
+    Rts,
+    Cts,
+    RtsCts,
+}
+
+impl FlowControl {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            FlowControl::None => 0,
+            FlowControl::Rts => 1,
+            FlowControl::Cts => 2,
+            FlowControl::RtsCts => 3,
+        }
+    }
+}
+
+/// Power saving mode of the board while idle.
+///
+/// The association with the access point is kept in all modes, but the
+/// board only wakes up for beacons while sleeping, delaying incoming data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepMode {
+    /// Radio always on. Lowest latency, highest current.
+    None,
+    /// CPU and radio suspended between beacons. Lowest current, but incoming
+    /// data may be delayed by up to the access point's beacon interval.
+    Light,
+    /// Radio suspended between beacons. The default for station mode, adding
+    /// up to one beacon interval of latency to incoming data.
+    Modem,
+}
+
+impl SleepMode {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            SleepMode::None => 0,
+            SleepMode::Light => 1,
+            SleepMode::Modem => 2,
+        }
+    }
+}
+
+/// Day of the week, as reported by the board's SNTP client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// Calendar time, as reported by the board's SNTP client.
+///
+/// The time is local to the timezone configured with the SNTP servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub weekday: Weekday,
+}
+
+/// Progress of a firmware update, as reported by the board.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateProgress {
+    ServerFound,
+    ServerConnected,
+    VersionReceived,
+    Updating,
+    /// A stage reported by the firmware which is not known to this driver
+    Unknown(u8),
+}
+
+impl From<u8> for UpdateProgress {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => UpdateProgress::ServerFound,
+            2 => UpdateProgress::ServerConnected,
+            3 => UpdateProgress::VersionReceived,
+            4 => UpdateProgress::Updating,
+            _ => UpdateProgress::Unknown(code),
+        }
+    }
+}
+
+/// Network interface of the board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interface {
+    Station,
+    SoftAccessPoint,
+}
+
 /// Commands to be sent to the ESP board.
 #[derive(Debug)]
 pub enum Command<'a> {
     QueryFirmwareInfo,
     SetMode(WiFiMode),
-    JoinAp { ssid: &'a str, password: &'a str },
+    JoinAp { ssid: &'a str, password: &'a str, bssid: Option<MacAddress> },
+    JoinEnterpriseAp(EnterpriseCredentials<'a>),
+    LeaveAp,
+    SetAutoConnect(bool),
+    QueryMacAddress(Interface),
+    SetMacAddress(Interface, MacAddress),
+    SetDhcp { interface: Interface, enabled: bool },
+    ConfigureDhcpServer { lease_minutes: u16, start: Ipv4Addr, end: Ipv4Addr },
+    ResetDhcpServer,
+    QueryStations,
+    QueryConnectionStatus,
+    QueryReceiveLengths,
     QueryIpAddress,
-    StartConnection(usize, ConnectionType, SocketAddr),
+    SetIpAddress { ip: Ipv4Addr, gateway: Ipv4Addr, netmask: Ipv4Addr },
+    StartConnection {
+        /// None in single connection mode
+        link_id: Option<usize>,
+        connection_type: ConnectionType,
+        remote: RemoteEndpoint<'a>,
+        keepalive: Option<u16>,
+        udp: Option<UdpOptions>,
+    },
     CloseConnection(usize),
-    Send { link_id: usize, len: usize },
+    Send { link_id: usize, len: usize, remote: Option<SocketAddr> },
     Receive { link_id: usize, len: usize },
     QueryDnsResolvers,
     SetDnsResolvers(ResolverAddresses),
-    GetHostByName{ hostname: &'a str},
+    ResolveDomain { hostname: &'a str },
+    StartServer { port: u16 },
+    StopServer,
+    SetServerTimeout { seconds: u16 },
+    SetTlsBufferSize(u16),
+    ConfigureTls { link_id: usize, config: TlsConfig },
+    SetTlsServerName { link_id: usize, name: &'a str },
+    SetTlsPsk { link_id: usize, key: &'a [u8], hint: &'a str },
+    SetTlsAlpn { link_id: usize, protocols: &'a [&'a str] },
+    ConfigureAp(SoftApConfig<'a>),
+    QueryApConfiguration,
+    ScanAccessPoints,
+    QueryJoinedAccessPoint,
+    ConfigureSntp { timezone: i8, servers: &'a [&'a str] },
+    DisableSntp,
+    QuerySntpTime,
+    Ping { host: &'a str },
+    DeepSleep { ms: u32 },
+    SetSleepMode(SleepMode),
+    SetTxPower(u8),
+    SetCountry(CountryConfig),
+    SetUart { baud_rate: u32, flow_control: FlowControl },
+    UpdateFirmware,
+    Restore,
+    Reset,
+    SetRemoteInfo(bool),
+    EnableMdns(MdnsConfig<'a>),
+    DisableMdns,
+    StartWps,
+    StopWps,
+    StartSmartConfig(SmartConfigType),
+    StopSmartConfig,
+    Attention,
+    DisableEcho,
+    DisablePersistence,
+    EnableMux,
+    DisableMux,
+    SetTransparentMode(bool),
+    SendTransparent,
+    CloseSingleConnection,
+    SetRecvMode,
+    HttpRequest(HttpRequest<'a>),
+    /// A command not wrapped by the driver, sent as is.
+    Raw(&'a str),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttCommand<'a>),
+}
+
+/// Command set of the AT firmware running on the board.
+///
+/// Defaults to `Nonos`, or to `EspAt` when built with the `esp-at` feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// NONOS AT firmware, up to version 1.7
+    Nonos,
+    /// ESP-AT firmware, version 2.0 and later
+    EspAt,
+}
+
+impl Dialect {
+    /// The dialect of the given firmware version.
+    pub fn for_firmware(info: &FirmwareInfo) -> Self {
+        if info.major >= 2 {
+            Dialect::EspAt
+        } else {
+            Dialect::Nonos
+        }
+    }
+
+    /// Start a command applied until the board restarts. NONOS firmware
+    /// needs the `_CUR` variant, ESP-AT dropped it in favor of AT+SYSSTORE.
+    fn transient(self, command: &str, suffix: &str) -> String<256> {
+        let mut s = String::try_from(command).unwrap();
+        if let Dialect::Nonos = self {
+            s.push_str("_CUR").unwrap();
+        }
+        s.push_str(suffix).unwrap();
+        s
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        if cfg!(feature = "esp-at") {
+            Dialect::EspAt
+        } else {
+            Dialect::Nonos
+        }
+    }
+}
+
+/// Commands which don't fit into a line are rejected, as are those the board
+/// can't encode, such as for IPv6 addresses.
+fn overflow<E>(_: E) -> AdapterError {
+    AdapterError::InvalidParameter
+}
+
+/// A string parameter formatted for quoting, escaping the quotes, commas and
+/// backslashes the firmware takes as delimiters otherwise.
+pub(crate) struct Escaped<'s>(pub(crate) &'s str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if matches!(c, '"' | ',' | '\\') {
+                f.write_char('\\')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
 }
 
 impl<'a> Command<'a> {
-    pub fn as_bytes(&self) -> String<U128> {
-        match self {
-            Command::QueryFirmwareInfo => String::from("AT+GMR"),
-            Command::QueryIpAddress => String::from("AT+CIPSTA?"),
+    pub fn encode(&self, dialect: Dialect) -> Result<String<256>, AdapterError> {
+        let s = match self {
+            Command::QueryFirmwareInfo => String::try_from("AT+GMR").unwrap(),
+            Command::QueryIpAddress => String::try_from("AT+CIPSTA?").unwrap(),
+            Command::SetIpAddress { ip, gateway, netmask } => {
+                let mut s = dialect.transient("AT+CIPSTA", "=");
+                write!(
+                    s,
+                    "\"{}\",\"{}\",\"{}\"",
+                    ip, gateway, netmask
+                )
+                .map_err(overflow)?;
+                s
+            }
             Command::SetMode(mode)=> match mode {
-                WiFiMode::Station => String::from("AT+CWMODE=1"),
-                WiFiMode::SoftAccessPoint => String::from("AT+CWMODE=2"),
-                WiFiMode::SoftAccessPointAndStation => String::from("AT+CWMODE=3"),
+                WiFiMode::Station => String::try_from("AT+CWMODE=1").unwrap(),
+                WiFiMode::SoftAccessPoint => String::try_from("AT+CWMODE=2").unwrap(),
+                WiFiMode::SoftAccessPointAndStation => String::try_from("AT+CWMODE=3").unwrap(),
             }
-            Command::JoinAp { ssid, password } => {
-                let mut s = String::from("AT+CWJAP=\"");
-                s.push_str(ssid).unwrap();
-                s.push_str("\",\"").unwrap();
-                s.push_str(password).unwrap();
-                s.push_str("\"").unwrap();
+            Command::JoinAp { ssid, password, bssid } => {
+                let mut s = String::try_from("AT+CWJAP=\"").unwrap();
+                s.push_str(ssid).map_err(overflow)?;
+                s.push_str("\",\"").map_err(overflow)?;
+                s.push_str(password).map_err(overflow)?;
+                s.push_str("\"").map_err(overflow)?;
+                if let Some(bssid) = bssid {
+                    write!(s, ",\"{}\"", bssid).map_err(overflow)?;
+                }
                 s
             }
-            Command::StartConnection(link_id, connection_type, socket_addr) => {
-                let mut s = String::from("AT+CIPSTART=");
-                write!(s, "{},", link_id).unwrap();
+            Command::JoinEnterpriseAp(credentials) => {
+                let method = match credentials.method {
+                    EapMethod::Peap => 1,
+                    EapMethod::Ttls => 2,
+                };
+                let mut s = String::try_from("AT+CWJEAP=").unwrap();
+                write!(
+                    s,
+                    "\"{}\",{},\"{}\",\"{}\",\"{}\",0",
+                    credentials.ssid,
+                    method,
+                    Escaped(credentials.identity),
+                    Escaped(credentials.username),
+                    Escaped(credentials.password)
+                )
+                .map_err(overflow)?;
+                s
+            }
+            Command::LeaveAp => String::try_from("AT+CWQAP").unwrap(),
+            Command::SetAutoConnect(enabled) => {
+                let mut s = String::try_from("AT+CWAUTOCONN=").unwrap();
+                write!(s, "{}", *enabled as u8).map_err(overflow)?;
+                s
+            }
+            Command::QueryMacAddress(interface) => match interface {
+                Interface::Station => dialect.transient("AT+CIPSTAMAC", "?"),
+                Interface::SoftAccessPoint => dialect.transient("AT+CIPAPMAC", "?"),
+            },
+            Command::SetMacAddress(interface, mac) => {
+                let mut s = match interface {
+                    Interface::Station => dialect.transient("AT+CIPSTAMAC", "="),
+                    Interface::SoftAccessPoint => dialect.transient("AT+CIPAPMAC", "="),
+                };
+                write!(s, "\"{}\"", mac).map_err(overflow)?;
+                s
+            }
+            Command::SetDhcp { interface, enabled } => {
+                let mut s = dialect.transient("AT+CWDHCP", "=");
+                match dialect {
+                    Dialect::Nonos => {
+                        let mode = match interface {
+                            Interface::SoftAccessPoint => 0,
+                            Interface::Station => 1,
+                        };
+                        write!(s, "{},{}", mode, *enabled as u8).map_err(overflow)?;
+                    }
+                    Dialect::EspAt => {
+                        // the interfaces are selected by a bit mask
+                        let mask = match interface {
+                            Interface::Station => 1,
+                            Interface::SoftAccessPoint => 2,
+                        };
+                        write!(s, "{},{}", *enabled as u8, mask).map_err(overflow)?;
+                    }
+                }
+                s
+            }
+            Command::ConfigureDhcpServer { lease_minutes, start, end } => {
+                let mut s = dialect.transient("AT+CWDHCPS", "=1,");
+                write!(s, "{},\"{}\",\"{}\"", lease_minutes, start, end).map_err(overflow)?;
+                s
+            }
+            Command::ResetDhcpServer => dialect.transient("AT+CWDHCPS", "=0"),
+            Command::QueryStations => String::try_from("AT+CWLIF").unwrap(),
+            Command::QueryConnectionStatus => String::try_from("AT+CIPSTATUS").unwrap(),
+            Command::QueryReceiveLengths => String::try_from("AT+CIPRECVLEN?").unwrap(),
+            Command::StartConnection { link_id, connection_type, remote, keepalive, udp } => {
+                let mut s = String::try_from("AT+CIPSTART=").unwrap();
+                if let Some(link_id) = link_id {
+                    write!(s, "{},", link_id).map_err(overflow)?;
+                }
                 match connection_type {
                     ConnectionType::TCP => {
-                        write!(s, "\"TCP\"").unwrap();
+                        write!(s, "\"TCP\"").map_err(overflow)?;
                     }
                     ConnectionType::UDP => {
-                        write!(s, "\"UDP\"").unwrap();
+                        write!(s, "\"UDP\"").map_err(overflow)?;
+                    }
+                    ConnectionType::SSL => {
+                        write!(s, "\"SSL\"").map_err(overflow)?;
                     }
                 }
-                write!(s, ",").unwrap();
-                match socket_addr.ip() {
-                    IpAddr::V4(ip) => {
-                        let octets = ip.octets();
-                        write!(
-                            s,
-                            "\"{}.{}.{}.{}\",{}",
-                            octets[0],
-                            octets[1],
-                            octets[2],
-                            octets[3],
-                            socket_addr.port()
-                        )
-                        .unwrap();
+                write!(s, ",").map_err(overflow)?;
+                match remote {
+                    RemoteEndpoint::Address(socket_addr) => match socket_addr.ip() {
+                        IpAddr::V4(ip) => {
+                            let octets = ip.octets();
+                            write!(
+                                s,
+                                "\"{}.{}.{}.{}\",{}",
+                                octets[0],
+                                octets[1],
+                                octets[2],
+                                octets[3],
+                                socket_addr.port()
+                            )
+                            .map_err(overflow)?;
+                        }
+                        IpAddr::V6(_) => return Err(AdapterError::InvalidParameter),
+                    },
+                    RemoteEndpoint::Host(host, port) => {
+                        write!(s, "\"{}\",{}", host, port).map_err(overflow)?;
                     }
-                    IpAddr::V6(_) => panic!("IPv6 not supported"),
                 }
-                s as String<U128>
+                match connection_type {
+                    ConnectionType::UDP => {
+                        if let Some(udp) = udp {
+                            let peer_mode = match udp.peer_mode {
+                                UdpPeerMode::Fixed => 0,
+                                UdpPeerMode::ChangeOnce => 1,
+                                UdpPeerMode::ChangeAlways => 2,
+                            };
+                            write!(s, ",{},{}", udp.local_port, peer_mode).map_err(overflow)?;
+                        }
+                    }
+                    ConnectionType::TCP | ConnectionType::SSL => {
+                        if let Some(keepalive) = keepalive {
+                            write!(s, ",{}", keepalive).map_err(overflow)?;
+                        }
+                    }
+                }
+                s as String<256>
             }
             Command::CloseConnection(link_id) => {
-                let mut s = String::from("AT+CIPCLOSE=");
-                write!(s, "{}", link_id).unwrap();
+                let mut s = String::try_from("AT+CIPCLOSE=").unwrap();
+                write!(s, "{}", link_id).map_err(overflow)?;
                 s
             }
-            Command::Send { link_id, len } => {
-                let mut s = String::from("AT+CIPSEND=");
-                write!(s, "{},{}", link_id, len).unwrap();
+            Command::Send { link_id, len, remote } => {
+                let mut s = String::try_from("AT+CIPSEND=").unwrap();
+                write!(s, "{},{}", link_id, len).map_err(overflow)?;
+                if let Some(remote) = remote {
+                    write!(s, ",\"{}\",{}", remote.ip(), remote.port()).map_err(overflow)?;
+                }
                 s
             }
             Command::Receive { link_id, len } => {
-                let mut s = String::from("AT+CIPRECVDATA=");
-                write!(s, "{},{}", link_id, len).unwrap();
+                let mut s = String::try_from("AT+CIPRECVDATA=").unwrap();
+                write!(s, "{},{}", link_id, len).map_err(overflow)?;
                 s
             }
             Command::QueryDnsResolvers => {
-                String::from("AT+CIPDNS?")
+                String::try_from("AT+CIPDNS?").unwrap()
             }
             Command::SetDnsResolvers(addr) => {
-                let mut s = String::from("AT+CIPDNS=1,");
-                write!(s, "\"{}\"", addr.resolver1).unwrap();
+                let mut s = String::try_from("AT+CIPDNS=1,").unwrap();
+                write!(s, "\"{}\"", addr.resolver1).map_err(overflow)?;
                 if let Some(resolver2) = addr.resolver2 {
-                    write!(s, ",\"{}\"", resolver2 ).unwrap()
+                    write!(s, ",\"{}\"", resolver2 ).map_err(overflow)?
+                }
+                s
+            }
+            Command::ResolveDomain { hostname } => {
+                let mut s = String::try_from("AT+CIPDOMAIN=").unwrap();
+                write!(s, "\"{}\"", hostname).map_err(overflow)?;
+                s
+            }
+            Command::StartServer { port } => {
+                let mut s = String::try_from("AT+CIPSERVER=1,").unwrap();
+                write!(s, "{}", port).map_err(overflow)?;
+                s
+            }
+            Command::StopServer => {
+                String::try_from("AT+CIPSERVER=0").unwrap()
+            }
+            Command::SetTlsBufferSize(size) => {
+                let mut s = String::try_from("AT+CIPSSLSIZE=").unwrap();
+                write!(s, "{}", size).map_err(overflow)?;
+                s
+            }
+            Command::ConfigureTls { link_id, config } => {
+                let authentication = match config.authentication {
+                    TlsAuthentication::None => 0,
+                    TlsAuthentication::Client => 1,
+                    TlsAuthentication::Server => 2,
+                    TlsAuthentication::Mutual => 3,
+                };
+                let mut s = String::try_from("AT+CIPSSLCCONF=").unwrap();
+                write!(
+                    s,
+                    "{},{},{},{}",
+                    link_id, authentication, config.pki_slot, config.ca_slot
+                )
+                .map_err(overflow)?;
+                s
+            }
+            Command::SetTlsServerName { link_id, name } => {
+                let mut s = String::try_from("AT+CIPSSLCSNI=").unwrap();
+                write!(s, "{},\"{}\"", link_id, name).map_err(overflow)?;
+                s
+            }
+            Command::SetTlsPsk { link_id, key, hint } => {
+                let mut s = String::try_from("AT+CIPSSLCPSK=").unwrap();
+                write!(s, "{},\"", link_id).map_err(overflow)?;
+                for b in key.iter() {
+                    write!(s, "{:02x}", b).map_err(overflow)?;
+                }
+                write!(s, "\",\"{}\"", hint).map_err(overflow)?;
+                s
+            }
+            Command::SetTlsAlpn { link_id, protocols } => {
+                let mut s = String::try_from("AT+CIPSSLCALPN=").unwrap();
+                write!(s, "{},{}", link_id, protocols.len()).map_err(overflow)?;
+                for protocol in protocols.iter() {
+                    write!(s, ",\"{}\"", protocol).map_err(overflow)?;
+                }
+                s
+            }
+            Command::SetServerTimeout { seconds } => {
+                let mut s = String::try_from("AT+CIPSTO=").unwrap();
+                write!(s, "{}", seconds).map_err(overflow)?;
+                s
+            }
+            Command::ConfigureAp(config) => {
+                let mut s = dialect.transient("AT+CWSAP", "=");
+                write!(
+                    s,
+                    "\"{}\",\"{}\",{},{}",
+                    config.ssid,
+                    config.password,
+                    config.channel,
+                    config.encryption.code()
+                )
+                .map_err(overflow)?;
+                s
+            }
+            Command::QueryApConfiguration => {
+                dialect.transient("AT+CWSAP", "?")
+            }
+            Command::ScanAccessPoints => {
+                String::try_from("AT+CWLAP").unwrap()
+            }
+            Command::QueryJoinedAccessPoint => {
+                dialect.transient("AT+CWJAP", "?")
+            }
+            Command::ConfigureSntp { timezone, servers } => {
+                let mut s = String::try_from("AT+CIPSNTPCFG=1,").unwrap();
+                write!(s, "{}", timezone).map_err(overflow)?;
+                for server in servers.iter() {
+                    write!(s, ",\"{}\"", server).map_err(overflow)?;
+                }
+                s
+            }
+            Command::DisableSntp => {
+                String::try_from("AT+CIPSNTPCFG=0").unwrap()
+            }
+            Command::QuerySntpTime => {
+                String::try_from("AT+CIPSNTPTIME?").unwrap()
+            }
+            Command::DeepSleep { ms } => {
+                let mut s = String::try_from("AT+GSLP=").unwrap();
+                write!(s, "{}", ms).map_err(overflow)?;
+                s
+            }
+            Command::SetSleepMode(mode) => {
+                let mut s = String::try_from("AT+SLEEP=").unwrap();
+                write!(s, "{}", mode.code()).map_err(overflow)?;
+                s
+            }
+            Command::SetTxPower(power) => {
+                let mut s = String::try_from("AT+RFPOWER=").unwrap();
+                write!(s, "{}", power).map_err(overflow)?;
+                s
+            }
+            Command::SetCountry(config) => {
+                let mut s = dialect.transient("AT+CWCOUNTRY", "=");
+                let policy = match config.policy {
+                    CountryPolicy::FollowAccessPoint => 0,
+                    CountryPolicy::Fixed => 1,
+                };
+                write!(
+                    s,
+                    "{},\"{}\",{},{}",
+                    policy,
+                    config.code,
+                    config.start_channel,
+                    config.channel_count
+                )
+                .map_err(overflow)?;
+                s
+            }
+            Command::SetUart { baud_rate, flow_control } => {
+                // 8 data bits, 1 stop bit, no parity
+                let mut s = String::try_from("AT+UART_CUR=").unwrap();
+                write!(s, "{},8,1,0,{}", baud_rate, flow_control.code()).map_err(overflow)?;
+                s
+            }
+            Command::UpdateFirmware => String::try_from("AT+CIUPDATE").unwrap(),
+            Command::Restore => String::try_from("AT+RESTORE").unwrap(),
+            Command::Reset => String::try_from("AT+RST").unwrap(),
+            Command::EnableMdns(config) => {
+                let mut s = String::try_from("AT+MDNS=1,").unwrap();
+                write!(
+                    s,
+                    "\"{}\",\"{}\",{}",
+                    config.hostname,
+                    config.service,
+                    config.port
+                )
+                .map_err(overflow)?;
+                s
+            }
+            Command::DisableMdns => String::try_from("AT+MDNS=0").unwrap(),
+            Command::StartWps => String::try_from("AT+WPS=1").unwrap(),
+            Command::StopWps => String::try_from("AT+WPS=0").unwrap(),
+            Command::StartSmartConfig(kind) => {
+                let kind = match kind {
+                    SmartConfigType::EspTouch => 1,
+                    SmartConfigType::AirKiss => 2,
+                    SmartConfigType::EspTouchAndAirKiss => 3,
+                };
+                let mut s = String::try_from("AT+CWSTARTSMART=").unwrap();
+                write!(s, "{}", kind).map_err(overflow)?;
+                s
+            }
+            Command::StopSmartConfig => String::try_from("AT+CWSTOPSMART").unwrap(),
+            Command::SetRemoteInfo(enabled) => {
+                let mut s = String::try_from("AT+CIPDINFO=").unwrap();
+                write!(s, "{}", *enabled as u8).map_err(overflow)?;
+                s
+            }
+            Command::Attention => String::try_from("AT").unwrap(),
+            Command::Raw(command) => String::try_from(*command).map_err(overflow)?,
+            Command::DisableEcho => String::try_from("ATE0").unwrap(),
+            Command::DisablePersistence => String::try_from("AT+SYSSTORE=0").unwrap(),
+            Command::EnableMux => String::try_from("AT+CIPMUX=1").unwrap(),
+            Command::DisableMux => String::try_from("AT+CIPMUX=0").unwrap(),
+            Command::SetTransparentMode(enabled) => {
+                let mut s = String::try_from("AT+CIPMODE=").unwrap();
+                write!(s, "{}", *enabled as u8).map_err(overflow)?;
+                s
+            }
+            Command::SendTransparent => String::try_from("AT+CIPSEND").unwrap(),
+            Command::CloseSingleConnection => String::try_from("AT+CIPCLOSE").unwrap(),
+            Command::SetRecvMode => String::try_from("AT+CIPRECVMODE=1").unwrap(),
+            Command::Ping { host } => {
+                let mut s = String::try_from("AT+PING=").unwrap();
+                write!(s, "\"{}\"", host).map_err(overflow)?;
+                s
+            }
+            Command::HttpRequest(request) => {
+                let method = match request.method {
+                    HttpMethod::Head => 1,
+                    HttpMethod::Get => 2,
+                    HttpMethod::Post => 3,
+                    HttpMethod::Put => 4,
+                    HttpMethod::Delete => 5,
+                };
+                let content_type = match request.content_type {
+                    HttpContentType::FormUrlEncoded => 0,
+                    HttpContentType::Json => 1,
+                    HttpContentType::Multipart => 2,
+                    HttpContentType::Xml => 3,
+                };
+                let transport = if request.url.starts_with("https://") { 2 } else { 1 };
+                let mut s = String::try_from("AT+HTTPCLIENT=").unwrap();
+                write!(
+                    s,
+                    "{},{},\"{}\",,,{}",
+                    method, content_type, request.url, transport
+                )
+                .map_err(overflow)?;
+                if let Some(body) = request.body {
+                    write!(s, ",\"{}\"", Escaped(body)).map_err(overflow)?;
                 }
                 s
             }
-            Command::GetHostByName { hostname } => {
-                let mut s = String::from("AT+CIPDOMAIN=");
-                write!(s, "\"{}\"", hostname).unwrap();
+            #[cfg(feature = "mqtt")]
+            Command::Mqtt(command) => {
+                let mut s = String::new();
+                command.encode(&mut s).map_err(overflow)?;
                 s
             }
+        };
+        Ok(s)
+    }
+
+    /// The AT command, without its arguments, such as `AT+CIPSTART`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::QueryFirmwareInfo => "AT+GMR",
+            Command::SetMode(_) => "AT+CWMODE",
+            Command::JoinAp { .. } => "AT+CWJAP",
+            Command::JoinEnterpriseAp(_) => "AT+CWJEAP",
+            Command::LeaveAp => "AT+CWQAP",
+            Command::SetAutoConnect(_) => "AT+CWAUTOCONN",
+            Command::QueryMacAddress(Interface::Station)
+            | Command::SetMacAddress(Interface::Station, _) => "AT+CIPSTAMAC",
+            Command::QueryMacAddress(Interface::SoftAccessPoint)
+            | Command::SetMacAddress(Interface::SoftAccessPoint, _) => "AT+CIPAPMAC",
+            Command::SetDhcp { .. } => "AT+CWDHCP",
+            Command::ConfigureDhcpServer { .. } | Command::ResetDhcpServer => "AT+CWDHCPS",
+            Command::QueryStations => "AT+CWLIF",
+            Command::QueryConnectionStatus => "AT+CIPSTATUS",
+            Command::QueryReceiveLengths => "AT+CIPRECVLEN",
+            Command::QueryIpAddress | Command::SetIpAddress { .. } => "AT+CIPSTA",
+            Command::StartConnection { .. } => "AT+CIPSTART",
+            Command::CloseConnection(_) | Command::CloseSingleConnection => "AT+CIPCLOSE",
+            Command::Send { .. } | Command::SendTransparent => "AT+CIPSEND",
+            Command::Receive { .. } => "AT+CIPRECVDATA",
+            Command::QueryDnsResolvers | Command::SetDnsResolvers(_) => "AT+CIPDNS",
+            Command::ResolveDomain { .. } => "AT+CIPDOMAIN",
+            Command::StartServer { .. } | Command::StopServer => "AT+CIPSERVER",
+            Command::SetServerTimeout { .. } => "AT+CIPSTO",
+            Command::SetTlsBufferSize(_) => "AT+CIPSSLSIZE",
+            Command::ConfigureTls { .. } => "AT+CIPSSLCCONF",
+            Command::SetTlsServerName { .. } => "AT+CIPSSLCSNI",
+            Command::SetTlsPsk { .. } => "AT+CIPSSLCPSK",
+            Command::SetTlsAlpn { .. } => "AT+CIPSSLCALPN",
+            Command::ConfigureAp(_) | Command::QueryApConfiguration => "AT+CWSAP",
+            Command::ScanAccessPoints => "AT+CWLAP",
+            Command::QueryJoinedAccessPoint => "AT+CWJAP",
+            Command::ConfigureSntp { .. } | Command::DisableSntp => "AT+CIPSNTPCFG",
+            Command::QuerySntpTime => "AT+CIPSNTPTIME",
+            Command::Ping { .. } => "AT+PING",
+            Command::DeepSleep { .. } => "AT+GSLP",
+            Command::SetSleepMode(_) => "AT+SLEEP",
+            Command::SetTxPower(_) => "AT+RFPOWER",
+            Command::SetCountry(_) => "AT+CWCOUNTRY",
+            Command::SetUart { .. } => "AT+UART_CUR",
+            Command::UpdateFirmware => "AT+CIUPDATE",
+            Command::Restore => "AT+RESTORE",
+            Command::Reset => "AT+RST",
+            Command::SetRemoteInfo(_) => "AT+CIPDINFO",
+            Command::EnableMdns(_) | Command::DisableMdns => "AT+MDNS",
+            Command::StartWps | Command::StopWps => "AT+WPS",
+            Command::StartSmartConfig(_) => "AT+CWSTARTSMART",
+            Command::StopSmartConfig => "AT+CWSTOPSMART",
+            Command::Attention => "AT",
+            Command::DisableEcho => "ATE0",
+            Command::DisablePersistence => "AT+SYSSTORE",
+            Command::EnableMux | Command::DisableMux => "AT+CIPMUX",
+            Command::SetTransparentMode(_) => "AT+CIPMODE",
+            Command::SetRecvMode => "AT+CIPRECVMODE",
+            Command::HttpRequest(_) => "AT+HTTPCLIENT",
+            Command::Raw(_) => "raw",
+            #[cfg(feature = "mqtt")]
+            Command::Mqtt(command) => command.name(),
         }
     }
+
+    /// The link the command applies to, if any.
+    pub fn link_id(&self) -> Option<usize> {
+        match self {
+            Command::StartConnection { link_id, .. } => *link_id,
+            Command::CloseConnection(link_id)
+            | Command::Send { link_id, .. }
+            | Command::Receive { link_id, .. }
+            | Command::ConfigureTls { link_id, .. }
+            | Command::SetTlsServerName { link_id, .. }
+            | Command::SetTlsPsk { link_id, .. }
+            | Command::SetTlsAlpn { link_id, .. } => Some(*link_id),
+            _ => None,
+        }
+    }
+}
+
+/// A notification parsed by an `UnsolicitedParser`, see
+/// `Adapter::custom_notification`.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Custom {
+    /// Identifies the notification, as chosen by the parser.
+    pub kind: u16,
+    /// Data passed on by the parser.
+    pub data: Payload,
 }
 
 /// Responses (including unsolicited) which may be parsed from the board.
-#[allow(clippy::large_enum_variant)]
 pub enum Response {
     None,
     Ok,
     Error,
+    /// `ERROR` for a link which isn't connected.
+    LinkInvalid,
+    /// `ERROR` for connecting a link which is connected already.
+    AlreadyConnected,
     FirmwareInfo(FirmwareInfo),
     ReadyForData,
     ReceivedDataToSend(usize),
     SendOk,
     SendFail,
-    DataAvailable { link_id: usize, len: usize },
-    DataReceived([u8; crate::BUFFER_LEN], usize),
+    DataAvailable { link_id: usize, len: usize, remote: Option<SocketAddr> },
+    /// Data was copied into the buffer registered by the adapter; holds
+    /// the number of bytes copied.
+    DataReceived(usize),
+    /// Data received in transparent transmission mode.
+    TransparentData(Payload),
+    DatagramReceived {
+        link_id: usize,
+        data: Payload,
+        remote: Option<SocketAddr>,
+    },
     WifiConnected,
     WifiConnectionFailure(WifiConnectionFailure),
     WifiDisconnect,
     GotIp,
     IpAddresses(IpAddresses),
     Connect(usize),
+    IncomingConnection(usize),
     Closed(usize),
     Resolvers(ResolverAddresses),
     IpAddress(IpAddr),
     DnsFail,
     UnlinkFail,
+    SoftApInfo(SoftApInfo),
+    AccessPoint(AccessPoint),
+    JoinedAccessPoint(JoinedAccessPoint),
+    NoAccessPoint,
+    MacAddress(MacAddress),
+    Station(StationInfo),
+    /// The overall status reported first by `AT+CIPSTATUS`.
+    ConnectionStatus(u8),
+    LinkStatus(LinkStatus),
+    /// Bytes buffered by the board in passive mode, by link id.
+    ReceiveLengths(Vec<u32, MAX_LINKS>),
+    SntpTime(DateTime),
+    Ping(u32),
+    PingTimeout,
+    /// The board booted, whether reset on purpose or not.
+    ModuleRestarted,
+    UpdateProgress(UpdateProgress),
+    WpsSuccess,
+    WpsFailure(WifiConnectionFailure),
+    SmartConfig(SmartConfigEvent),
+    HttpData(Payload),
+    Busy,
+    Custom(Custom),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttResponse),
+}
+
+impl Response {
+    /// The name of the variant, as reported by `Observed::Response`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Response::None => "None",
+            Response::Ok => "Ok",
+            Response::Error => "Error",
+            Response::LinkInvalid => "LinkInvalid",
+            Response::AlreadyConnected => "AlreadyConnected",
+            Response::FirmwareInfo(_) => "FirmwareInfo",
+            Response::ReadyForData => "ReadyForData",
+            Response::ReceivedDataToSend(_) => "ReceivedDataToSend",
+            Response::SendOk => "SendOk",
+            Response::SendFail => "SendFail",
+            Response::DataAvailable { .. } => "DataAvailable",
+            Response::DataReceived(_) => "DataReceived",
+            Response::DatagramReceived { .. } => "DatagramReceived",
+            Response::WifiConnected => "WifiConnected",
+            Response::WifiConnectionFailure(_) => "WifiConnectionFailure",
+            Response::WifiDisconnect => "WifiDisconnect",
+            Response::GotIp => "GotIp",
+            Response::IpAddresses(_) => "IpAddresses",
+            Response::Connect(_) => "Connect",
+            Response::IncomingConnection(_) => "IncomingConnection",
+            Response::Closed(_) => "Closed",
+            Response::Resolvers(_) => "Resolvers",
+            Response::IpAddress(_) => "IpAddress",
+            Response::DnsFail => "DnsFail",
+            Response::UnlinkFail => "UnlinkFail",
+            Response::SoftApInfo(_) => "SoftApInfo",
+            Response::AccessPoint(_) => "AccessPoint",
+            Response::JoinedAccessPoint(_) => "JoinedAccessPoint",
+            Response::NoAccessPoint => "NoAccessPoint",
+            Response::MacAddress(_) => "MacAddress",
+            Response::Station(_) => "Station",
+            Response::ConnectionStatus(_) => "ConnectionStatus",
+            Response::LinkStatus(_) => "LinkStatus",
+            Response::ReceiveLengths(_) => "ReceiveLengths",
+            Response::SntpTime(_) => "SntpTime",
+            Response::Ping(_) => "Ping",
+            Response::PingTimeout => "PingTimeout",
+            Response::ModuleRestarted => "ModuleRestarted",
+            Response::UpdateProgress(_) => "UpdateProgress",
+            Response::WpsSuccess => "WpsSuccess",
+            Response::WpsFailure(_) => "WpsFailure",
+            Response::SmartConfig(_) => "SmartConfig",
+            Response::HttpData(_) => "HttpData",
+            Response::TransparentData(_) => "TransparentData",
+            Response::Busy => "Busy",
+            Response::Custom(_) => "Custom",
+            #[cfg(feature = "mqtt")]
+            Response::Mqtt(_) => "Mqtt",
+        }
+    }
 }
 
 impl Debug for Response {
@@ -163,18 +1215,26 @@ impl Debug for Response {
             Response::None => f.write_str("None"),
             Response::Ok => f.write_str("Ok"),
             Response::Error => f.write_str("Error"),
+            Response::LinkInvalid => f.write_str("LinkInvalid"),
+            Response::AlreadyConnected => f.write_str("AlreadyConnected"),
             Response::FirmwareInfo(v) => f.debug_tuple("FirmwareInfo").field(v).finish(),
             Response::ReadyForData => f.write_str("ReadyForData"),
             Response::ReceivedDataToSend(len) => f.debug_tuple("ReceivedDataToSend").field(len).finish(),
             Response::SendOk =>f.write_str("SendOk"),
             Response::SendFail => f.write_str("SendFail"),
-            Response::DataAvailable { link_id, len } => f
+            Response::DataAvailable { link_id, len, remote } => f
                 .debug_struct("DataAvailable")
                 .field("link_id", link_id)
                 .field("len", len)
+                .field("remote", remote)
+                .finish(),
+            Response::DataReceived(len) => f.debug_tuple("DataReceived").field(len).finish(),
+            Response::DatagramReceived { link_id, data, remote } => f
+                .debug_struct("DatagramReceived")
+                .field("link_id", link_id)
+                .field("len", &data.len())
+                .field("remote", remote)
                 .finish(),
-            //Response::DataReceived(d, l) => dump_data("DataReceived", d, *l, f),
-            Response::DataReceived(_, _) => f.write_str("DataReceived"),
             Response::WifiConnected => f.write_str("WifiConnected"),
             Response::WifiConnectionFailure(v) => {
                 f.debug_tuple("WifiConnectionFailure").field(v).finish()
@@ -183,25 +1243,151 @@ impl Debug for Response {
             Response::GotIp => f.write_str("GotIp"),
             Response::IpAddresses(v) => f.debug_tuple("IpAddresses").field(v).finish(),
             Response::Connect(v) => f.debug_tuple("Connect").field(v).finish(),
+            Response::IncomingConnection(v) => f.debug_tuple("IncomingConnection").field(v).finish(),
             Response::Closed(v) => f.debug_tuple("Closed").field(v).finish(),
             Response::IpAddress( v) => f.debug_tuple( "IpAddress").field(v).finish(),
             Response::Resolvers(v) => f.debug_tuple( "Resolvers").field(v).finish(),
             Response::DnsFail => f.write_str("DNS Fail"),
             Response::UnlinkFail => f.write_str("UnlinkFail"),
+            Response::SoftApInfo(v) => f.debug_tuple("SoftApInfo").field(v).finish(),
+            Response::AccessPoint(v) => f.debug_tuple("AccessPoint").field(v).finish(),
+            Response::JoinedAccessPoint(v) => f.debug_tuple("JoinedAccessPoint").field(v).finish(),
+            Response::NoAccessPoint => f.write_str("NoAccessPoint"),
+            Response::MacAddress(v) => f.debug_tuple("MacAddress").field(v).finish(),
+            Response::Station(v) => f.debug_tuple("Station").field(v).finish(),
+            Response::ConnectionStatus(v) => f.debug_tuple("ConnectionStatus").field(v).finish(),
+            Response::LinkStatus(v) => f.debug_tuple("LinkStatus").field(v).finish(),
+            Response::ReceiveLengths(v) => f.debug_tuple("ReceiveLengths").field(v).finish(),
+            Response::SntpTime(v) => f.debug_tuple("SntpTime").field(v).finish(),
+            Response::Ping(v) => f.debug_tuple("Ping").field(v).finish(),
+            Response::PingTimeout => f.write_str("PingTimeout"),
+            Response::ModuleRestarted => f.write_str("ModuleRestarted"),
+            Response::UpdateProgress(v) => f.debug_tuple("UpdateProgress").field(v).finish(),
+            Response::WpsSuccess => f.write_str("WpsSuccess"),
+            Response::WpsFailure(v) => f.debug_tuple("WpsFailure").field(v).finish(),
+            Response::SmartConfig(SmartConfigEvent::CredentialsReceived { ssid, .. }) => f
+                .debug_struct("SmartConfigCredentials")
+                .field("ssid", ssid)
+                .finish(),
+            Response::SmartConfig(v) => f.debug_tuple("SmartConfig").field(v).finish(),
+            Response::HttpData(data) => f.debug_tuple("HttpData").field(&data.len()).finish(),
+            Response::TransparentData(data) => f.debug_tuple("TransparentData").field(&data.len()).finish(),
+            Response::Busy => f.write_str("Busy"),
+            Response::Custom(v) => f.debug_tuple("Custom").field(v).finish(),
+            #[cfg(feature = "mqtt")]
+            Response::Mqtt(v) => f.debug_tuple("Mqtt").field(v).finish(),
         }
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for Response {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        use defmt::{write, Debug2Format};
+        match self {
+            Response::None => write!(f, "None"),
+            Response::Ok => write!(f, "Ok"),
+            Response::Error => write!(f, "Error"),
+            Response::LinkInvalid => write!(f, "LinkInvalid"),
+            Response::AlreadyConnected => write!(f, "AlreadyConnected"),
+            Response::FirmwareInfo(v) => write!(f, "FirmwareInfo({})", v),
+            Response::ReadyForData => write!(f, "ReadyForData"),
+            Response::ReceivedDataToSend(len) => write!(f, "ReceivedDataToSend({})", len),
+            Response::SendOk => write!(f, "SendOk"),
+            Response::SendFail => write!(f, "SendFail"),
+            Response::DataAvailable { link_id, len, remote } => write!(
+                f,
+                "DataAvailable {{ link_id: {}, len: {}, remote: {} }}",
+                link_id,
+                len,
+                Debug2Format(remote)
+            ),
+            Response::DataReceived(len) => write!(f, "DataReceived({})", len),
+            Response::DatagramReceived { link_id, data, remote } => write!(
+                f,
+                "DatagramReceived {{ link_id: {}, len: {}, remote: {} }}",
+                link_id,
+                data.len(),
+                Debug2Format(remote)
+            ),
+            Response::WifiConnected => write!(f, "WifiConnected"),
+            Response::WifiConnectionFailure(v) => write!(f, "WifiConnectionFailure({})", v),
+            Response::WifiDisconnect => write!(f, "WifiDisconnect"),
+            Response::GotIp => write!(f, "GotIp"),
+            Response::IpAddresses(v) => write!(f, "IpAddresses({})", v),
+            Response::Connect(v) => write!(f, "Connect({})", v),
+            Response::IncomingConnection(v) => write!(f, "IncomingConnection({})", v),
+            Response::Closed(v) => write!(f, "Closed({})", v),
+            Response::IpAddress(v) => write!(f, "IpAddress({})", Debug2Format(v)),
+            Response::Resolvers(v) => write!(f, "Resolvers({})", v),
+            Response::DnsFail => write!(f, "DNS Fail"),
+            Response::UnlinkFail => write!(f, "UnlinkFail"),
+            Response::SoftApInfo(v) => write!(f, "SoftApInfo({})", v),
+            Response::AccessPoint(v) => write!(f, "AccessPoint({})", v),
+            Response::JoinedAccessPoint(v) => write!(f, "JoinedAccessPoint({})", v),
+            Response::NoAccessPoint => write!(f, "NoAccessPoint"),
+            Response::MacAddress(v) => write!(f, "MacAddress({})", v),
+            Response::Station(v) => write!(f, "Station({})", v),
+            Response::ConnectionStatus(v) => write!(f, "ConnectionStatus({})", v),
+            Response::LinkStatus(v) => write!(f, "LinkStatus({})", v),
+            Response::ReceiveLengths(v) => write!(f, "ReceiveLengths({})", v.as_slice()),
+            Response::SntpTime(v) => write!(f, "SntpTime({})", v),
+            Response::Ping(v) => write!(f, "Ping({})", v),
+            Response::PingTimeout => write!(f, "PingTimeout"),
+            Response::ModuleRestarted => write!(f, "ModuleRestarted"),
+            Response::UpdateProgress(v) => write!(f, "UpdateProgress({})", v),
+            Response::WpsSuccess => write!(f, "WpsSuccess"),
+            Response::WpsFailure(v) => write!(f, "WpsFailure({})", v),
+            Response::SmartConfig(SmartConfigEvent::CredentialsReceived { ssid, .. }) => {
+                write!(f, "SmartConfigCredentials {{ ssid: {} }}", ssid)
+            }
+            Response::SmartConfig(v) => write!(f, "SmartConfig({})", v),
+            Response::HttpData(data) => write!(f, "HttpData({})", data.len()),
+            Response::TransparentData(data) => write!(f, "TransparentData({})", data.len()),
+            Response::Busy => write!(f, "Busy"),
+            Response::Custom(v) => write!(f, "Custom({})", v),
+            #[cfg(feature = "mqtt")]
+            Response::Mqtt(v) => write!(f, "Mqtt({})", v),
+        }
+    }
+}
+
+/// How the station interface obtains its addresses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Addressing {
+    /// Leased using DHCP
+    Dhcp,
+    /// Configured statically
+    Static,
+}
+
 /// IP addresses for the board, including its own address, netmask and gateway.
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct IpAddresses {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub ip: Ipv4Addr,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub gateway: Ipv4Addr,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     pub netmask: Ipv4Addr,
+    /// How the addresses were obtained, as far as tracked by the adapter.
+    pub addressing: Addressing,
+}
+
+impl IpAddresses {
+    /// The broadcast address of the board's subnet.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        let ip = u32::from(self.ip);
+        let netmask = u32::from(self.netmask);
+        Ipv4Addr::from(ip | !netmask)
+    }
 }
 
 /// Version information for the ESP board.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FirmwareInfo {
     pub major: u8,
     pub minor: u8,
@@ -210,12 +1396,16 @@ pub struct FirmwareInfo {
 }
 
 /// Reasons for Wifi access-point join failures.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WifiConnectionFailure {
     Timeout,
     WrongPassword,
     CannotFindTargetAp,
     ConnectionFailed,
+    /// The credentials could not be sent to the board, such as for being
+    /// too long.
+    InvalidParameter,
 }
 
 
@@ -285,18 +1475,62 @@ mod test {
 
     #[test]
     fn test_debug_data() {
-        let mut buf = ArrayString::<[u8; 256]>::new();
-        let data = b"FOO\0BAR";
+        let mut buf = ArrayString::<[u8; 20]>::new();
 
-        let mut array = [0u8; 128];
-        for (&x, p) in data.iter().zip(array.iter_mut()) {
-            *p = x;
-        }
+        write!(&mut buf, "{:?}", Response::DataReceived(7)).expect("Can't write");
+        assert_eq!(&buf, "DataReceived(7)");
+    }
 
-        write!(&mut buf, "{:?}", Response::DataReceived(array, data.len())).expect("Can't write");
+    #[test]
+    fn test_encode_ipv6() {
+        let remote = SocketAddr::new(IpAddr::V6(drogue_network::addr::Ipv6Addr::LOCALHOST), 80);
+        let command = Command::StartConnection {
+            link_id: Some(0),
+            connection_type: ConnectionType::TCP,
+            remote: RemoteEndpoint::Address(remote),
+            keepalive: None,
+            udp: None,
+        };
+        assert!(matches!(command.encode(Dialect::Nonos), Err(AdapterError::InvalidParameter)));
+    }
+
+    #[test]
+    fn test_encode_too_long() {
+        let host = core::str::from_utf8(&[b'a'; 300]).unwrap();
+        let command = Command::Ping { host };
+        assert!(matches!(command.encode(Dialect::Nonos), Err(AdapterError::InvalidParameter)));
+        let command = Command::Ping { host: &host[..16] };
+        assert!(command.encode(Dialect::Nonos).is_ok());
+    }
+
+    #[test]
+    fn test_encode_http_body() {
+        let command = Command::HttpRequest(HttpRequest {
+            method: HttpMethod::Post,
+            url: "http://example.com/",
+            content_type: HttpContentType::Json,
+            body: Some(r#"{"a":1,"b":2}"#),
+        });
+        let encoded = command.encode(Dialect::EspAt).unwrap();
         assert_eq!(
-            &buf,
-            "DataReceived(7; 'FOO\\0BAR'; [46, 4F, 4F, 0, 42, 41, 52])"
+            encoded.as_str(),
+            r#"AT+HTTPCLIENT=3,1,"http://example.com/",,,1,"{\"a\":1\,\"b\":2}""#
         );
     }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_encode_mqtt_escaped() {
+        let command = Command::Mqtt(MqttCommand::Connect { host: r#"a"b,c"#, port: 1883, reconnect: false });
+        let encoded = command.encode(Dialect::EspAt).unwrap();
+        assert_eq!(encoded.as_str(), r#"AT+MQTTCONN=0,"a\"b\,c",1883,0"#);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_encode_mqtt_too_long() {
+        let topic = core::str::from_utf8(&[b't'; 300]).unwrap();
+        let command = Command::Mqtt(MqttCommand::Subscribe { topic, qos: crate::mqtt::QoS::AtMostOnce });
+        assert!(matches!(command.encode(Dialect::EspAt), Err(AdapterError::InvalidParameter)));
+    }
 }
@@ -0,0 +1,62 @@
+//! Statically allocated queues, for applications such as RTIC ones which
+//! keep the adapter and ingress in `'static` resources.
+//!
+//! `Adapter` is `Send` as long as its serial transmitter is, and `Ingress` as
+//! long as its serial receiver is, so both can be moved from `init` into
+//! the resources of the tasks using them.
+
+use crate::adapter::{Adapter, NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use crate::ingress::Ingress;
+use crate::protocol::Response;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::serial::{Read, Write};
+use heapless::spsc::Queue;
+
+/// The queues passed to `initialize`, with `'static` lifetime.
+///
+/// ```ignore
+/// let queues = StaticQueues::take().unwrap();
+/// let (adapter, ingress) = initialize(
+///     tx, rx, &mut en, &mut rst,
+///     &mut queues.responses, &mut queues.notifications,
+///     &mut delay,
+/// )?;
+/// ```
+pub struct StaticQueues {
+    pub responses: Queue<Response, RESPONSE_QUEUE_LEN>,
+    pub notifications: Queue<Response, NOTIFICATION_QUEUE_LEN>,
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut QUEUES: MaybeUninit<StaticQueues> = MaybeUninit::uninit();
+
+impl StaticQueues {
+    /// The queues, which can be taken only once; returns `None` afterwards.
+    pub fn take() -> Option<&'static mut StaticQueues> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let queues = StaticQueues {
+            responses: Queue::new(),
+            notifications: Queue::new(),
+        };
+        // Safety: only reached once, so no other reference to the queues exists.
+        unsafe {
+            let slot = &mut *core::ptr::addr_of_mut!(QUEUES);
+            Some(slot.write(queues))
+        }
+    }
+}
+
+// Checks the bounds documented above.
+#[allow(dead_code)]
+fn assert_send<Tx, Rx>()
+    where
+        Tx: Write<u8> + Send,
+        Rx: Read<u8> + Send,
+{
+    fn send<T: Send>() {}
+    send::<Adapter<'static, Tx>>();
+    send::<Ingress<'static, Rx>>();
+}
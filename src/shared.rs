@@ -0,0 +1,78 @@
+//! Adapter shared between the application and the ingress interrupt.
+//!
+//! Notifications, such as data becoming available on a link, are otherwise
+//! only processed once the application uses the adapter again. With a
+//! `SharedAdapter`, the interrupt processes them right after
+//! `Ingress::digest`, so that the adapter's state is current whenever the
+//! application looks at it.
+
+use crate::adapter::{Adapter, MAX_LINKS, NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use core::cell::RefCell;
+use embedded_hal::serial::Write;
+
+/// Adapter usable from both the application and the ingress interrupt.
+///
+/// The adapter is only borrowed within critical sections, while it is used
+/// outside of them, as its commands need the ingress interrupt to complete.
+/// The interrupt therefore skips processing notifications while the
+/// application uses the adapter, leaving them to the application.
+pub struct SharedAdapter<'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    adapter: RefCell<Adapter<'a, Tx, RQ, NQ, L>>,
+}
+
+// Safety: the borrow state of the adapter is only changed within critical
+// sections, and the adapter itself is only used by whoever borrowed it.
+unsafe impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Sync for SharedAdapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8> + Send,
+{}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> SharedAdapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    pub fn new(adapter: Adapter<'a, Tx, RQ, NQ, L>) -> Self {
+        Self {
+            adapter: RefCell::new(adapter),
+        }
+    }
+
+    pub fn into_inner(self) -> Adapter<'a, Tx, RQ, NQ, L> {
+        self.adapter.into_inner()
+    }
+
+    /// Use the adapter from the application, waiting for the interrupt to
+    /// finish processing notifications first.
+    ///
+    /// Must not be called from the ingress interrupt, which can't wait for
+    /// the application.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut Adapter<'a, Tx, RQ, NQ, L>) -> R) -> R {
+        let mut adapter = loop {
+            let borrowed = critical_section::with(|_| self.adapter.try_borrow_mut().ok());
+            if let Some(adapter) = borrowed {
+                break adapter;
+            }
+        };
+        let result = f(&mut adapter);
+        critical_section::with(|_| drop(adapter));
+        result
+    }
+
+    /// Process the notifications enqueued by `Ingress::digest`, meant to be
+    /// called from the ingress interrupt right after it.
+    ///
+    /// Returns `false` if the adapter is in use by the application, which then
+    /// processes the notifications itself.
+    pub fn process_notifications(&self) -> bool {
+        critical_section::with(|_| match self.adapter.try_borrow_mut() {
+            Ok(mut adapter) => {
+                adapter.process_notifications();
+                true
+            }
+            Err(_) => false,
+        })
+    }
+}
@@ -0,0 +1,34 @@
+//! Observer of the traffic with the board, for dumping an AT transcript
+//! over RTT, semihosting or another UART while debugging.
+//!
+//! Register a `Tracer` using `Adapter::set_tracer` to observe the bytes sent,
+//! and using `Ingress::set_tracer` to observe those received, before they are
+//! parsed. Only available with the `trace` feature.
+
+/// Which way traced bytes went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Written to the board, by the adapter.
+    Sent,
+    /// Read from the board, by the ingress.
+    Received,
+}
+
+/// Receiver of the traffic with the board.
+///
+/// Called from the context of the adapter and of the ingress alike, so it
+/// is required to be `Sync`, and should return quickly.
+pub trait Tracer: Sync {
+    /// Observe `data` going `direction`, which is split arbitrarily, from
+    /// single bytes to whole commands.
+    fn trace(&self, direction: Direction, data: &[u8]);
+}
+
+impl<F> Tracer for F
+    where
+        F: Fn(Direction, &[u8]) + Sync,
+{
+    fn trace(&self, direction: Direction, data: &[u8]) {
+        self(direction, data)
+    }
+}
@@ -0,0 +1,135 @@
+//! Transparent transmission, where the UART becomes a raw pipe to a single
+//! connection.
+//!
+//! Data is passed on without the framing of `AT+CIPSEND` and `+IPD`, which
+//! allows for throughput close to the UART's line rate.
+
+use crate::adapter::{Adapter, AdapterError, MAX_LINKS, NOTIFICATION_QUEUE_LEN, RESPONSE_QUEUE_LEN};
+use crate::ingress::Passthrough;
+use crate::payload::Payload;
+use crate::protocol::{Command, ConnectionType, RemoteEndpoint, Response};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::serial::Write;
+
+/// A connection in transparent transmission mode.
+///
+/// No commands can be sent to the board while the connection is open. It
+/// must be closed using `close` to return the board to command mode.
+pub struct TransparentSocket<'s, 'a, Tx, const RQ: usize = RESPONSE_QUEUE_LEN, const NQ: usize = NOTIFICATION_QUEUE_LEN, const L: usize = MAX_LINKS>
+    where
+        Tx: Write<u8>,
+{
+    adapter: &'s mut Adapter<'a, Tx, RQ, NQ, L>,
+    // data received but not yet read, and how much of it was read
+    pending: Option<(Payload, usize)>,
+}
+
+impl<'s, 'a, Tx, const RQ: usize, const NQ: usize, const L: usize> TransparentSocket<'s, 'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// Write data to the connection. The board sends it once 2048 bytes are
+    /// buffered, or no data arrived for 20 ms.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), AdapterError> {
+        self.adapter.write_data(data)
+    }
+
+    /// Read data received from the connection, keeping whatever does not
+    /// fit into `buffer` for the next read.
+    pub fn read(&mut self, buffer: &mut [u8]) -> nb::Result<usize, AdapterError> {
+        let (data, read) = match self.pending.take() {
+            Some(pending) => pending,
+            None => match self.adapter.take_response() {
+                Some(Response::TransparentData(data)) => (data, 0),
+                Some(_) => return Err(nb::Error::Other(AdapterError::ReadError)),
+                None => return Err(nb::Error::WouldBlock),
+            },
+        };
+        let unread = &data[read..];
+        let len = core::cmp::min(unread.len(), buffer.len());
+        buffer[0..len].copy_from_slice(&unread[0..len]);
+        if len < unread.len() {
+            self.pending = Some((data, read + len));
+        }
+        Ok(len)
+    }
+
+    /// Close the connection, returning the board to command mode.
+    ///
+    /// Sending the escape sequence takes a little over a second, as it must
+    /// be separated from any data.
+    pub fn close<D>(self, delay: &mut D) -> Result<(), AdapterError>
+        where
+            D: DelayMs<u16>,
+    {
+        delay.delay_ms(20);
+        self.adapter.write_data(b"+++")?;
+        delay.delay_ms(1000);
+
+        self.adapter.shared().set_passthrough(Passthrough::Off);
+        // discard whatever was received before the escape took effect
+        while self.adapter.take_response().is_some() {}
+
+        self.adapter.leave_transparent()
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Adapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// Open a connection in transparent transmission mode.
+    ///
+    /// The board only supports a single connection in this mode, so all other
+    /// sockets must be closed, and no server may be running.
+    pub fn open_transparent<'s, 'r, R>(
+        &'s mut self,
+        connection_type: ConnectionType,
+        remote: R,
+    ) -> Result<TransparentSocket<'s, 'a, Tx, RQ, NQ, L>, AdapterError>
+        where
+            R: Into<RemoteEndpoint<'r>>,
+    {
+        self.process_notifications();
+        if self.has_open_sockets() {
+            return Err(AdapterError::UnableToOpen);
+        }
+
+        self.send_transparent(Command::DisableMux)?;
+        self.send_transparent(Command::SetTransparentMode(true))?;
+
+        let command = Command::StartConnection {
+            link_id: None,
+            connection_type,
+            remote: remote.into(),
+            keepalive: None,
+            udp: None,
+        };
+        if let Ok(Response::Connect(..)) = self.send(command) {
+            self.shared().set_passthrough(Passthrough::Armed);
+            if let Ok(Response::Ok) = self.send(Command::SendTransparent) {
+                if let Ok(Response::ReadyForData) = self.wait_for_response() {
+                    return Ok(TransparentSocket { adapter: self, pending: None });
+                }
+            }
+            self.shared().set_passthrough(Passthrough::Off);
+        }
+
+        self.leave_transparent()?;
+        Err(AdapterError::UnableToOpen)
+    }
+
+    fn send_transparent(&mut self, command: Command) -> Result<(), AdapterError> {
+        match self.send(command)? {
+            Response::Ok => Ok(()),
+            other => Err(self.unexpected(&other)),
+        }
+    }
+
+    fn leave_transparent(&mut self) -> Result<(), AdapterError> {
+        self.send_transparent(Command::SetTransparentMode(false))?;
+        // fails if the remote end closed the connection already
+        self.send(Command::CloseSingleConnection)?;
+        self.send_transparent(Command::EnableMux)
+    }
+}
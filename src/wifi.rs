@@ -0,0 +1,248 @@
+//! Rejoining the access point after the connection to it was lost.
+//!
+//! The credentials of the last successful `Adapter::join` are remembered, and
+//! once enabled, `Adapter::poll_reconnect` rejoins using them, backing off
+//! between failed attempts.
+
+use crate::adapter::Adapter;
+use crate::protocol::{MacAddress, WifiConnectionFailure};
+use embedded_hal::serial::Write;
+use heapless::{
+    spsc::Queue,
+    String,
+};
+
+/// How to rejoin the access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Time to wait before the first attempt, doubled after each failure.
+    pub initial_backoff_ms: u32,
+    /// Longest time to wait in between attempts.
+    pub max_backoff_ms: u32,
+    /// Number of attempts before giving up, or `None` to keep trying.
+    pub max_attempts: Option<u8>,
+}
+
+impl ReconnectPolicy {
+    /// Time to wait after the given number of failed attempts, saturating
+    /// rather than wrapping once doubled beyond `u32`.
+    fn backoff_ms(&self, attempt: u8) -> u32 {
+        let factor = 1u32.checked_shl(u32::from(attempt)).unwrap_or(u32::MAX);
+        self.initial_backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 60_000,
+            max_attempts: None,
+        }
+    }
+}
+
+/// Changes of the reconnect state, see `Adapter::reconnect_event`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconnectEvent {
+    /// The connection to the access point was lost.
+    Disconnected,
+    /// Rejoining the access point, counting attempts from 1.
+    Reconnecting { attempt: u8 },
+    Reconnected,
+    /// An attempt failed, and is retried after backing off.
+    Failed(WifiConnectionFailure),
+    /// The last attempt allowed by the policy failed.
+    GaveUp,
+}
+
+struct Credentials {
+    ssid: String<32>,
+    password: String<64>,
+    bssid: Option<MacAddress>,
+}
+
+/// State of reconnecting, as tracked from the board's notifications.
+pub(crate) struct Reconnect {
+    policy: Option<ReconnectPolicy>,
+    credentials: Option<Credentials>,
+    attempt: u8,
+    next_attempt: Option<u32>,
+    joining: bool,
+    events: Queue<ReconnectEvent, 5>,
+}
+
+impl Reconnect {
+    pub(crate) fn new() -> Self {
+        Self {
+            policy: None,
+            credentials: None,
+            attempt: 0,
+            next_attempt: None,
+            joining: false,
+            events: Queue::new(),
+        }
+    }
+
+    /// Remember the credentials of a successful join.
+    pub(crate) fn remember(&mut self, ssid: &str, password: &str, bssid: Option<MacAddress>) {
+        let mut credentials = Credentials {
+            ssid: String::new(),
+            password: String::new(),
+            bssid,
+        };
+        if credentials.ssid.push_str(ssid).is_ok() && credentials.password.push_str(password).is_ok() {
+            self.credentials.replace(credentials);
+        } else {
+            self.forget();
+        }
+    }
+
+    /// Forget the credentials, such as after leaving the access point on purpose.
+    pub(crate) fn forget(&mut self) {
+        self.credentials = None;
+        self.attempt = 0;
+        self.next_attempt = None;
+    }
+
+    /// Schedule rejoining, after the connection to the access point was lost.
+    pub(crate) fn disconnected(&mut self, now: Option<u32>) {
+        if self.joining || self.next_attempt.is_some() || self.credentials.is_none() {
+            return;
+        }
+        if let (Some(policy), Some(now)) = (self.policy, now) {
+            self.event(ReconnectEvent::Disconnected);
+            self.attempt = 0;
+            self.next_attempt = Some(now.wrapping_add(policy.initial_backoff_ms));
+        }
+    }
+
+    fn back_off(&mut self, now: u32) {
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return,
+        };
+        if let Some(max_attempts) = policy.max_attempts {
+            if self.attempt >= max_attempts {
+                self.event(ReconnectEvent::GaveUp);
+                self.attempt = 0;
+                self.next_attempt = None;
+                return;
+            }
+        }
+        let backoff = policy.backoff_ms(self.attempt);
+        self.next_attempt = Some(now.wrapping_add(backoff));
+    }
+
+    fn event(&mut self, event: ReconnectEvent) {
+        let result = self.events.enqueue(event);
+        if result.is_err() {
+            warn!("dropping reconnect event");
+        }
+    }
+}
+
+impl<'a, Tx, const RQ: usize, const NQ: usize, const L: usize> Adapter<'a, Tx, RQ, NQ, L>
+    where
+        Tx: Write<u8>,
+{
+    /// Rejoin the access point according to `policy` once the connection to it
+    /// is lost, from within `poll_reconnect`. Requires a clock, see `set_clock`.
+    pub fn enable_reconnect(&mut self, policy: ReconnectPolicy) {
+        self.reconnect.policy.replace(policy);
+    }
+
+    /// Stop rejoining the access point, cancelling any pending attempt.
+    pub fn disable_reconnect(&mut self) {
+        self.reconnect.policy = None;
+        self.reconnect.attempt = 0;
+        self.reconnect.next_attempt = None;
+    }
+
+    /// Meant to be called periodically, such as from the application's main
+    /// loop. Rejoins the access point once the backoff elapsed, blocking for
+    /// as long as joining takes.
+    pub fn poll_reconnect(&mut self) {
+        self.process_notifications();
+        let now = match self.now_ms() {
+            Some(now) => now,
+            None => return,
+        };
+        match self.reconnect.next_attempt {
+            // wrapping comparison, as the clock wraps on overflow
+            Some(at) if (now.wrapping_sub(at) as i32) >= 0 => {}
+            _ => return,
+        }
+        let (ssid, password, bssid) = match &self.reconnect.credentials {
+            Some(credentials) => (
+                credentials.ssid.clone(),
+                credentials.password.clone(),
+                credentials.bssid,
+            ),
+            None => return,
+        };
+
+        self.reconnect.attempt = self.reconnect.attempt.saturating_add(1);
+        let attempt = self.reconnect.attempt;
+        self.reconnect.event(ReconnectEvent::Reconnecting { attempt });
+
+        self.reconnect.joining = true;
+        let result = self.join_bssid(&ssid, &password, bssid);
+        // the board reports disconnects while joining, which must not
+        // schedule another attempt
+        self.process_notifications();
+        self.reconnect.joining = false;
+
+        match result {
+            Ok(()) => {
+                self.reconnect.event(ReconnectEvent::Reconnected);
+                self.reconnect.attempt = 0;
+                self.reconnect.next_attempt = None;
+            }
+            Err(reason) => {
+                self.reconnect.event(ReconnectEvent::Failed(reason));
+                let now = self.now_ms().unwrap_or(now);
+                self.reconnect.back_off(now);
+            }
+        }
+    }
+
+    /// The next change of the reconnect state, if any.
+    pub fn reconnect_event(&mut self) -> Option<ReconnectEvent> {
+        self.process_notifications();
+        self.reconnect.events.dequeue()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.backoff_ms(0), 1_000);
+        assert_eq!(policy.backoff_ms(1), 2_000);
+        assert_eq!(policy.backoff_ms(5), 32_000);
+        assert_eq!(policy.backoff_ms(6), 60_000);
+        for attempt in 6..=u8::MAX {
+            assert_eq!(policy.backoff_ms(attempt), 60_000);
+        }
+    }
+
+    #[test]
+    fn test_backoff_saturates() {
+        let policy = ReconnectPolicy {
+            max_backoff_ms: u32::MAX,
+            ..ReconnectPolicy::default()
+        };
+        assert_eq!(policy.backoff_ms(22), 1_000 << 22);
+        for attempt in 22..=u8::MAX {
+            assert!(policy.backoff_ms(attempt) >= policy.backoff_ms(attempt - 1));
+        }
+        assert_eq!(policy.backoff_ms(29), u32::MAX);
+        assert_eq!(policy.backoff_ms(32), u32::MAX);
+    }
+}